@@ -1,69 +1,212 @@
 use std::{convert::Infallible, time::Duration};
 
 use axum::{
-    extract::{WebSocketUpgrade, ws::Message as WsMessage},
+    Json,
+    extract::{State, WebSocketUpgrade, ws::Message as WsMessage},
     response::{
         Sse,
         sse::{Event as SseEvent, KeepAlive},
     },
-    routing::get,
+    routing::{get, post},
 };
 use futures::{SinkExt, Stream, StreamExt};
+use serde::Deserialize;
 
 use crate::{
-    error::AppResult,
-    extractors::{ClientTokenAuth, LastEventId, ReaderClient},
+    api::stream::EndStreamResponse,
+    auth::TokenScope,
+    error::{AppError, AppResult},
+    extractors::{ClientTokenAuth, LastEventId, Query, ReaderClient},
+    redis::{FinishDetails, MultiCursor, StreamStatus},
     state::AppState,
+    storage::{CancelRequestOutcome, EventFilter, Fanout, Subscription},
+    webhooks::WebhookEvent,
 };
 
 pub fn routes() -> axum::Router<AppState> {
     axum::Router::new()
         .route("/sse", get(client_sse))
         .route("/ws", get(client_ws))
+        .route("/cancel", post(client_cancel))
 }
 
+/// Message sent to live consumers and producers before disconnecting them due to a revoked token
+pub(super) const REVOKED_MESSAGE: &str = "token revoked";
+
+/// Query for filtering the events delivered to consumers
+#[derive(Deserialize)]
+struct EventFilterQuery {
+    /// Comma-separated event names to include, and/or names prefixed with `-` to exclude
+    events: Option<String>,
+}
+
+impl EventFilterQuery {
+    fn into_filter(self) -> EventFilter {
+        EventFilter::parse(self.events.as_deref().unwrap_or_default())
+    }
+}
+
+/// Subscribe to one stream, or several streams via repeated `key` parameters. When subscribed
+/// to several streams, the event data is the JSON event tagged with its stream key, and the
+/// event ID is a cursor tracking the last event ID of each stream. Events can be filtered
+/// with the `events` parameter.
+///
+/// Single streams are read via the shared reader of the stream, while subscriptions to
+/// several streams use their own exclusive connection.
 async fn client_sse(
-    ClientTokenAuth { key }: ClientTokenAuth,
+    auth: ClientTokenAuth,
     LastEventId(start_id): LastEventId,
-    ReaderClient(reader): ReaderClient,
+    Query(query): Query<EventFilterQuery>,
+    State(state): State<AppState>,
 ) -> AppResult<Sse<impl Stream<Item = Result<SseEvent, Infallible>>>> {
-    let (events, last_id, is_end) = reader.prev_sse_events(&key, start_id.as_deref()).await?;
-    let prev_events_stream = futures::stream::iter(events);
+    auth.require(TokenScope::Read)?;
+    let filter = query.into_filter();
+    let revoked = auth.revoked(&state.revocations);
+
+    let stream = match auth.keys.as_slice() {
+        [key] => {
+            let fanout = state.fanout();
+            let (events, last_id, is_end) = state
+                .history()
+                .prev_sse_events(key, start_id.as_deref(), &filter)
+                .await?;
+            let prev_events_stream = futures::stream::iter(events);
+            if is_end {
+                prev_events_stream.boxed()
+            } else {
+                let subscription = subscribe(&fanout, key, &last_id).await?;
+                prev_events_stream
+                    .chain(subscription.into_sse_events(filter))
+                    .boxed()
+            }
+        }
+        keys if state.config.redis_cluster => {
+            // Streams in different hash slots can't be read with one XREAD in a cluster,
+            // so merge the shared readers of each stream instead
+            let fanout = state.fanout();
+            let cursor = start_id.as_deref().map(MultiCursor::parse);
+            let (events, cursor, active_keys) = state
+                .history()
+                .prev_multi_sse_events(keys, cursor.unwrap_or_default(), &filter)
+                .await?;
+            let subscriptions = subscribe_all(&fanout, &active_keys, &cursor).await?;
+            futures::stream::iter(events)
+                .chain(Subscription::merge_sse_events(
+                    subscriptions,
+                    cursor,
+                    filter,
+                ))
+                .boxed()
+        }
+        keys => {
+            let ReaderClient(reader) = ReaderClient::from_state(&state).await?;
+            let cursor = start_id.as_deref().map(MultiCursor::parse);
+            let (events, cursor, active_keys) = state
+                .history()
+                .prev_multi_sse_events(keys, cursor.unwrap_or_default(), &filter)
+                .await?;
+            futures::stream::iter(events)
+                .chain(reader.stream_multi_sse_events(active_keys, cursor, filter))
+                .boxed()
+        }
+    };
 
     let keep_alive = KeepAlive::default().interval(Duration::from_secs(10));
-    if is_end {
-        Ok(Sse::new(prev_events_stream.map(Ok).boxed()).keep_alive(keep_alive))
-    } else {
-        let stream = prev_events_stream.chain(reader.stream_sse_events(&key, &last_id));
-        Ok(Sse::new(stream.map(Ok).boxed()).keep_alive(keep_alive))
-    }
+    let revoked_event = SseEvent::default().event("error").data(REVOKED_MESSAGE);
+    let stream = until_revoked(stream, revoked, revoked_event);
+    Ok(Sse::new(stream.map(Ok).boxed()).keep_alive(keep_alive))
 }
 
+/// Subscribe to one stream, or several streams via repeated `key` parameters. When subscribed
+/// to several streams, the events are tagged with their stream key. Events can be filtered
+/// with the `events` parameter. Consumers can send control messages to request cancellation.
 async fn client_ws(
-    ClientTokenAuth { key }: ClientTokenAuth,
+    auth: ClientTokenAuth,
     LastEventId(start_id): LastEventId,
-    ReaderClient(reader): ReaderClient,
+    Query(query): Query<EventFilterQuery>,
+    State(state): State<AppState>,
     ws: WebSocketUpgrade,
 ) -> AppResult<axum::response::Response> {
-    let (prev_events, last_id, is_end) = reader.prev_json_events(&key, start_id.as_deref()).await?;
+    auth.require(TokenScope::Read)?;
+    let filter = query.into_filter();
+    let revoked = auth.revoked(&state.revocations);
+    let control = ControlHandler {
+        state: state.clone(),
+        keys: auth.keys.clone(),
+        can_cancel: auth.require(TokenScope::Cancel).is_ok(),
+    };
 
-    if is_end {
-        Ok(ws.on_upgrade(async |mut socket| {
+    let (prev_events, stream) = match auth.keys.as_slice() {
+        [key] => {
+            let fanout = state.fanout();
+            let (prev_events, last_id, is_end) = state
+                .history()
+                .prev_json_events(key, start_id.as_deref(), &filter)
+                .await?;
+            let stream = match is_end {
+                true => None,
+                false => Some(
+                    subscribe(&fanout, key, &last_id)
+                        .await?
+                        .into_ws_events(filter)
+                        .boxed(),
+                ),
+            };
+            (prev_events, stream)
+        }
+        keys if state.config.redis_cluster => {
+            let fanout = state.fanout();
+            let cursor = start_id.as_deref().map(MultiCursor::parse);
+            let (prev_events, cursor, active_keys) = state
+                .history()
+                .prev_multi_json_events(keys, cursor.unwrap_or_default(), &filter)
+                .await?;
+            let subscriptions = subscribe_all(&fanout, &active_keys, &cursor).await?;
+            let stream = Subscription::merge_ws_events(subscriptions, filter).boxed();
+            (prev_events, Some(stream))
+        }
+        keys => {
+            let ReaderClient(reader) = ReaderClient::from_state(&state).await?;
+            let cursor = start_id.as_deref().map(MultiCursor::parse);
+            let (prev_events, cursor, active_keys) = state
+                .history()
+                .prev_multi_json_events(keys, cursor.unwrap_or_default(), &filter)
+                .await?;
+            let stream = reader
+                .stream_multi_ws_events(active_keys, cursor, filter)
+                .boxed();
+            (prev_events, Some(stream))
+        }
+    };
+
+    match stream {
+        None => Ok(ws.on_upgrade(async |mut socket| {
             let _ = socket.send(WsMessage::text(prev_events)).await;
             let _ = socket.send(WsMessage::Close(None)).await;
-        }))
-    } else {
-        let stream = reader.stream_ws_events(&key, &last_id);
-        Ok(ws.on_upgrade(async |mut socket| {
+        })),
+        Some(stream) => Ok(ws.on_upgrade(async move |mut socket| {
             let _ = socket.send(WsMessage::text(prev_events)).await;
 
             let (mut ws_sender, mut ws_reader) = socket.split();
             let mut stream = std::pin::pin!(stream);
+            let mut revoked = std::pin::pin!(revoked);
             loop {
                 tokio::select! {
+                    () = &mut revoked => {
+                        let error = serde_json::json!({ "event": "error", "data": REVOKED_MESSAGE });
+                        let _ = ws_sender.send(WsMessage::text(error.to_string())).await;
+                        let _ = ws_sender.send(WsMessage::Close(None)).await;
+                        break;
+                    }
                     ws_msg = ws_reader.next() => {
                         match ws_msg {
                             Some(Ok(WsMessage::Close(_))) | None => break,
+                            Some(Ok(WsMessage::Text(text))) => {
+                                if let Err(err) = control.handle(&text).await {
+                                    let error = serde_json::json!({ "event": "error", "data": err.to_string() });
+                                    let _ = ws_sender.send(WsMessage::text(error.to_string())).await;
+                                }
+                            }
                             Some(Ok(_)) => continue,
                             Some(Err(err)) => {
                                 tracing::warn!("WebSocket client read error: {err}");
@@ -87,6 +230,141 @@ async fn client_ws(
                     }
                 }
             }
-        }))
+        })),
+    }
+}
+
+/// Subscribe to the live events of the stream via its shared reader
+async fn subscribe(fanout: &Fanout<'_>, key: &str, last_event_id: &str) -> AppResult<Subscription> {
+    fanout
+        .subscribe(key, last_event_id)
+        .await?
+        .ok_or_else(AppError::too_many_requests)
+}
+
+/// Subscribe to the live events of several streams via their shared readers, starting
+/// after the last event ID of each stream in the cursor
+async fn subscribe_all(
+    fanout: &Fanout<'_>,
+    keys: &[String],
+    cursor: &MultiCursor,
+) -> AppResult<Vec<Subscription>> {
+    let mut subscriptions = Vec::with_capacity(keys.len());
+    for key in keys {
+        let last_event_id = cursor.get(key).unwrap_or("0-0");
+        subscriptions.push(subscribe(fanout, key, last_event_id).await?);
+    }
+
+    Ok(subscriptions)
+}
+
+/// Forward the events until they end or the client token is revoked, in which case
+/// the `revoked_event` is sent last
+fn until_revoked<T>(
+    events: impl Stream<Item = T>,
+    revoked: impl Future<Output = ()>,
+    revoked_event: T,
+) -> impl Stream<Item = T> {
+    async_stream::stream! {
+        let mut events = std::pin::pin!(events);
+        let mut revoked = std::pin::pin!(revoked);
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(event) => yield event,
+                    None => break,
+                },
+                () = &mut revoked => {
+                    yield revoked_event;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Request cancellation of the stream. The request is recorded with a `cancel_requested`
+/// event for the producer, and the stream is cancelled right away if its settings allow it.
+async fn client_cancel(
+    auth: ClientTokenAuth,
+    State(state): State<AppState>,
+) -> AppResult<Json<EndStreamResponse>> {
+    auth.require(TokenScope::Cancel)?;
+    let key = auth.single_key()?;
+    let status = request_cancel(&state, key).await?;
+
+    Ok(Json(EndStreamResponse {
+        status,
+        compacted: None,
+    }))
+}
+
+/// Reason of the `cancel` event when a consumer's request cancels the stream right away
+const CANCEL_REQUEST_REASON: &str = "cancel_requested";
+
+/// Request cancellation of the stream on behalf of a consumer, and send the webhooks.
+/// Returns the status of the stream afterwards.
+async fn request_cancel(state: &AppState, key: &str) -> AppResult<StreamStatus> {
+    let details = FinishDetails {
+        reason: Some(CANCEL_REQUEST_REASON.to_owned()),
+        data: None,
+    };
+    let Some(outcome) = state.storage.request_cancel(key, &details).await? else {
+        return Err(AppError::not_found("active stream not found"));
+    };
+
+    let status = match outcome {
+        CancelRequestOutcome::Recorded => {
+            state.webhooks.send(WebhookEvent::CancelRequested, key);
+            StreamStatus::Active
+        }
+        CancelRequestOutcome::AlreadyRequested => StreamStatus::Active,
+        CancelRequestOutcome::Cancelled => {
+            state.webhooks.send(WebhookEvent::CancelRequested, key);
+            state.webhooks.send(WebhookEvent::Cancelled, key);
+            StreamStatus::Cancelled
+        }
+    };
+
+    Ok(status)
+}
+
+/// Control message sent by a consumer over the WebSocket
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ControlMessage {
+    /// Request cancellation of the stream (the key is required when subscribed to several)
+    Cancel { key: Option<String> },
+}
+
+/// Handles the control messages of a WebSocket consumer
+struct ControlHandler {
+    state: AppState,
+    /// The subscribed stream keys
+    keys: Vec<String>,
+    /// Whether the token has the `cancel` scope
+    can_cancel: bool,
+}
+
+impl ControlHandler {
+    async fn handle(&self, text: &str) -> AppResult<()> {
+        let message: ControlMessage = serde_json::from_str(text)
+            .map_err(|_| AppError::bad_request("invalid control message"))?;
+        match message {
+            ControlMessage::Cancel { key } => {
+                if !self.can_cancel {
+                    return Err(AppError::forbidden("missing token scope"));
+                }
+                let key = match (key, self.keys.as_slice()) {
+                    (Some(key), keys) if keys.contains(&key) => key,
+                    (Some(_), _) => return Err(AppError::bad_request("stream not subscribed")),
+                    (None, [key]) => key.to_owned(),
+                    (None, _) => return Err(AppError::bad_request("expected a stream key")),
+                };
+                request_cancel(&self.state, &key).await?;
+            }
+        }
+
+        Ok(())
     }
 }
@@ -14,12 +14,13 @@ api_routes! {
 
 /// Get information about the server
 async fn get_info(State(state): State<AppState>) -> Json<InfoResponse> {
-    let streaming_available = state.exclusive_clients.num_available();
+    let streaming_available = state.storage.available_connections();
     let redis_stats = RedisStats {
         r#static: state.config.redis_pool,
         streaming_in_use: state.config.max_clients - streaming_available,
         streaming_available,
         streaming_max: state.config.max_clients,
+        shared_readers: state.stream_readers.len(),
     };
 
     Json(InfoResponse {
@@ -46,4 +47,6 @@ struct RedisStats {
     streaming_available: usize,
     /// Maximum number of streaming connections
     streaming_max: usize,
+    /// Number of streams being read by a shared reader (one streaming connection each)
+    shared_readers: usize,
 }
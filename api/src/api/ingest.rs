@@ -1,14 +1,25 @@
-use axum::{Json, extract::WebSocketUpgrade};
+use aide::OperationOutput;
+use axum::{
+    Json,
+    extract::{State, WebSocketUpgrade},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
 use axum_aide_macros::api_routes;
 use futures::{SinkExt, Stream, StreamExt, TryStreamExt, stream::TryReadyChunksError};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    api::client::REVOKED_MESSAGE,
     error::{AppError, AppResult},
-    extractors::{JsonBody, JsonStream, Query, StaticClient, WriterClient},
-    redis::{AddEvent, RedisWriter},
+    extractors::{IngestAuth, JsonBody, JsonStream, Query, Storage, WriterClient},
+    redis::{AddEvent, EventData},
     state::AppState,
+    storage::{
+        EventValidator, EventValidators, StorageError, StreamConnection, StreamSchema,
+        WriteOutcome, WriteResult,
+    },
 };
 
 api_routes! {
@@ -21,9 +32,12 @@ api_routes! {
 }
 
 #[derive(Debug, Deserialize, JsonSchema)]
-struct StreamKeyQuery {
+struct IngestStreamQuery {
     /// Key of the stream
     key: String,
+    /// Only write the first batch if this is still the ID of the last event in the stream.
+    /// Each following batch is then expected to follow the previously added events.
+    expected_last_id: Option<String>,
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -32,127 +46,472 @@ struct AddEventsRequest {
     key: String,
     /// Events to add to the stream
     events: Vec<AddEvent>,
+    /// Only write the events if this is still the ID of the last event in the stream
+    /// (e.g. the last ID returned by a previous write). Otherwise the request fails with
+    /// a `409` conflict that includes the current last ID.
+    expected_last_id: Option<String>,
 }
 
-#[derive(Debug, Serialize, JsonSchema)]
+#[derive(Debug, Default, Serialize, JsonSchema)]
 struct AddEventsResponse {
+    /// Number of events added to the stream
     num_events: usize,
+    /// Idempotency keys of the events that were skipped as duplicates
+    duplicates: Vec<String>,
+    /// ID of the last event added to the stream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_id: Option<String>,
+}
+
+impl AddEventsResponse {
+    /// Summarize the results of writing the events with the given idempotency keys
+    fn new(idempotency_keys: Vec<Option<String>>, results: Vec<WriteResult>) -> Self {
+        let mut response = Self::default();
+        for (idempotency_key, result) in idempotency_keys.into_iter().zip(results) {
+            match result {
+                WriteResult::Added(id) => {
+                    response.num_events += 1;
+                    response.last_id = Some(id.to_string());
+                }
+                WriteResult::Duplicate => response.duplicates.extend(idempotency_key),
+            }
+        }
+        response
+    }
+
+    /// Check if no events were written or skipped
+    fn is_empty(&self) -> bool {
+        self.num_events == 0 && self.duplicates.is_empty()
+    }
+
+    /// Add the results of another batch of events
+    fn extend(&mut self, other: Self) {
+        self.num_events += other.num_events;
+        self.duplicates.extend(other.duplicates);
+        if other.last_id.is_some() {
+            self.last_id = other.last_id;
+        }
+    }
+}
+
+/// Get the idempotency keys of the events, for reporting duplicates
+fn idempotency_keys(events: &[AddEvent]) -> Vec<Option<String>> {
+    events
+        .iter()
+        .map(|event| event.idempotency_key.clone())
+        .collect()
 }
 
 async fn add_events(
-    StaticClient(redis): StaticClient,
+    State(state): State<AppState>,
+    auth: IngestAuth,
+    Storage(storage): Storage,
     JsonBody(input): JsonBody<AddEventsRequest>,
-) -> AppResult<Json<AddEventsResponse>> {
-    let Some(ids) = redis.write_events(&input.key, input.events).await? else {
-        return Err(AppError::bad_request("stream not active"));
+) -> Result<Json<AddEventsResponse>, WriteError> {
+    auth.authorize(&*storage, &input.key).await?;
+    let mut schema = state.event_validators.get(&input.key);
+    let outcome = loop {
+        let validated = match schema.validator() {
+            Some(validator) => validator.validate_all(&input.events),
+            None => Ok(()),
+        };
+        // Invalid events aren't written, but an empty write still checks that the stream's
+        // event schema is the one they were validated against
+        let (events, expected_last_id) = match &validated {
+            Ok(()) => (input.events.as_slice(), input.expected_last_id.as_deref()),
+            Err(_) => (&[][..], None),
+        };
+        let outcome = storage
+            .write_events(&input.key, events, expected_last_id, schema.json())
+            .await?;
+        match (outcome, validated) {
+            (WriteOutcome::SchemaChanged(json), _) => {
+                schema = update_schema(&state.event_validators, &input.key, json)?;
+            }
+            (_, Err(err)) => {
+                return Err(AppError::bad_request(format!("invalid event(s): {err}")).into());
+            }
+            (outcome, Ok(())) => break outcome,
+        }
     };
-    let num_events = ids.len();
 
-    Ok(Json(AddEventsResponse { num_events }))
+    Ok(Json(write_response(
+        idempotency_keys(&input.events),
+        outcome,
+    )?))
 }
 
 /// Max number of streamed events to ingest at once
 const INGEST_BATCH_SIZE: usize = 50;
 
 async fn json_stream(
-    Query(query): Query<StreamKeyQuery>,
+    State(state): State<AppState>,
+    auth: IngestAuth,
+    Query(query): Query<IngestStreamQuery>,
+    Storage(storage): Storage,
     WriterClient(writer): WriterClient,
     JsonStream(stream): JsonStream,
-) -> AppResult<Json<AddEventsResponse>> {
+) -> Result<Json<AddEventsResponse>, WriteError> {
+    auth.authorize(&*storage, &query.key).await?;
+    let mut schema = state.event_validators.get(&query.key);
     let mut stream_chunks = stream.try_ready_chunks(INGEST_BATCH_SIZE);
-    let mut num_events = 0;
+    let mut response = AddEventsResponse::default();
+    let mut expected_last_id = query.expected_last_id;
 
     while let Some(read_result) = stream_chunks.next().await {
-        match read_result {
-            Ok(events) => {
-                num_events += write_event_batch(&writer, &query.key, events).await?;
-            }
-            Err(TryReadyChunksError(events, err)) => {
-                let _ = write_event_batch(&writer, &query.key, events).await?;
-                return Err(AppError::bad_request(format!("invalid event(s): {err}")));
-            }
+        let (events, read_error) = match read_result {
+            Ok(events) => (events, None),
+            Err(TryReadyChunksError(events, err)) => (events, Some(err)),
+        };
+        // Write the events before the first invalid one, and stop
+        let (invalid, written) = write_event_batch(
+            &*writer,
+            &state.event_validators,
+            &query.key,
+            &mut schema,
+            events,
+            expected_last_id.as_deref(),
+            truncate_invalid_events,
+        )
+        .await;
+        let written = written?;
+        if let Some(err) = read_error {
+            let message = invalid.unwrap_or_else(|| format!("invalid event(s): {err}"));
+            return Err(AppError::bad_request(message).into());
+        }
+        chain_expected_id(&mut expected_last_id, &written);
+        response.extend(written);
+        if let Some(err) = invalid {
+            return Err(AppError::bad_request(err).into());
         }
     }
 
-    Ok(Json(AddEventsResponse { num_events }))
+    Ok(Json(response))
 }
 
 async fn ws_stream(
-    Query(query): Query<StreamKeyQuery>,
+    State(state): State<AppState>,
+    auth: IngestAuth,
+    Query(query): Query<IngestStreamQuery>,
+    Storage(storage): Storage,
     WriterClient(writer): WriterClient,
     ws: WebSocketUpgrade,
-) -> axum::response::Response {
-    ws.on_upgrade(async move |ws| {
+) -> AppResult<axum::response::Response> {
+    auth.authorize(&*storage, &query.key).await?;
+    let mut schema = state.event_validators.get(&query.key);
+    let revoked = auth.revoked(&state.revocations, &query.key);
+
+    Ok(ws.on_upgrade(async move |ws| {
         let (mut ws_writer, ws_reader) = ws.split();
         let mut stream_chunks = transform_ws_stream(ws_reader).try_ready_chunks(INGEST_BATCH_SIZE);
+        let mut expected_last_id = query.expected_last_id;
+        let mut revoked = std::pin::pin!(revoked);
 
-        while let Some(result) = stream_chunks.next().await {
-            match result {
-                Ok(items) => {
-                    let should_close = items.iter().any(|item| matches!(item, WsStreamItem::Close));
-                    let events = items.into_iter().filter_map(WsStreamItem::into_event);
-
-                    match write_event_batch(&writer, &query.key, events).await {
-                        Ok(n) if n > 0 => {
-                            let _ = send_ws_response(&mut ws_writer, WsResponse::success(n)).await;
-                        }
-                        Ok(_) => {}
-                        Err(err) => {
-                            let response = WsResponse::error(err.to_string());
-                            let _ = send_ws_response(&mut ws_writer, response).await;
-                        }
-                    }
-
-                    if should_close {
-                        break;
-                    }
-                }
-                Err(TryReadyChunksError(events, err)) => {
-                    let should_close = events
-                        .iter()
-                        .any(|item| matches!(item, WsStreamItem::Close));
-                    let events = events.into_iter().filter_map(WsStreamItem::into_event);
-                    if let Ok(n) = write_event_batch(&writer, &query.key, events).await
-                        && n > 0
-                    {
-                        let _ = send_ws_response(&mut ws_writer, WsResponse::success(n)).await;
-                    }
-
-                    let _ = send_ws_response(&mut ws_writer, WsResponse::error(err)).await;
-                    if should_close {
-                        break;
-                    }
+        loop {
+            let result = tokio::select! {
+                result = stream_chunks.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                () = &mut revoked => {
+                    let response = WsResponse::error(REVOKED_MESSAGE);
+                    let _ = send_ws_response(&mut ws_writer, response).await;
+                    let _ = ws_writer.send(axum::extract::ws::Message::Close(None)).await;
+                    break;
                 }
+            };
+            let (items, read_error) = match result {
+                Ok(items) => (items, None),
+                Err(TryReadyChunksError(items, err)) => (items, Some(err)),
+            };
+            let should_close = items.iter().any(|item| matches!(item, WsStreamItem::Close));
+            let events = items
+                .into_iter()
+                .filter_map(WsStreamItem::into_event)
+                .collect();
+
+            let ingested = ingest_ws_batch(
+                &mut ws_writer,
+                &*writer,
+                &state.event_validators,
+                &query.key,
+                &mut schema,
+                &mut expected_last_id,
+                events,
+            )
+            .await;
+            if !ingested {
+                break;
+            }
+            if let Some(err) = read_error {
+                let _ = send_ws_response(&mut ws_writer, WsResponse::error(err)).await;
+            }
+            if should_close {
+                break;
             }
         }
-    })
+    }))
 }
 
-async fn write_event_batch(
-    writer: &RedisWriter,
+/// Validate and write a batch of events received over the WebSocket, and send the responses.
+/// Returns `false` if ingesting should stop, because the stream was written by someone else.
+async fn ingest_ws_batch<S>(
+    ws_writer: &mut S,
+    writer: &dyn StreamConnection,
+    validators: &EventValidators,
     key: &str,
-    events: impl IntoIterator<Item = AddEvent>,
-) -> AppResult<usize> {
-    let events: Vec<_> = events.into_iter().collect();
-    if events.is_empty() {
-        return Ok(0);
+    schema: &mut StreamSchema,
+    expected_last_id: &mut Option<String>,
+    events: Vec<AddEvent>,
+) -> bool
+where
+    S: futures::Sink<axum::extract::ws::Message> + Unpin,
+{
+    let (errors, written) = write_event_batch(
+        writer,
+        validators,
+        key,
+        schema,
+        events,
+        expected_last_id.as_deref(),
+        take_invalid_events,
+    )
+    .await;
+    for error in errors {
+        let _ = send_ws_response(ws_writer, WsResponse::error(error)).await;
+    }
+
+    match written {
+        Ok(written) if !written.is_empty() => {
+            chain_expected_id(expected_last_id, &written);
+            let _ = send_ws_response(ws_writer, WsResponse::success(written)).await;
+        }
+        Ok(_) => {}
+        Err(WriteError::Conflict { message, last_id }) => {
+            let response = WsResponse::conflict(message, last_id);
+            let _ = send_ws_response(ws_writer, response).await;
+            return false;
+        }
+        Err(WriteError::Other(err)) => {
+            let _ = send_ws_response(ws_writer, WsResponse::error(err.to_string())).await;
+        }
     }
 
-    match writer.write_events(key, events).await? {
-        Some(ids) => Ok(ids.len()),
-        None => Err(AppError::bad_request("stream not active")),
+    true
+}
+
+/// Update the cached event schema of the stream, after a write found that it changed
+fn update_schema(
+    validators: &EventValidators,
+    key: &str,
+    json: Option<String>,
+) -> AppResult<StreamSchema> {
+    let schema = validators
+        .update(key, json.as_deref())
+        .map_err(anyhow::Error::msg)?;
+    Ok(schema)
+}
+
+/// Remove the first event that doesn't match the stream's event schema and all events after it,
+/// returning its error
+fn truncate_invalid_events(
+    validator: Option<&EventValidator>,
+    events: &mut Vec<AddEvent>,
+) -> Option<String> {
+    let (idx, err) = events.iter().enumerate().find_map(|(idx, event)| {
+        let err = validator?.validate(event).err()?;
+        Some((idx, err))
+    })?;
+    events.truncate(idx);
+    Some(format!("invalid event: {err}"))
+}
+
+/// Remove the events that don't match the stream's event schema, returning their errors
+fn take_invalid_events(
+    validator: Option<&EventValidator>,
+    events: &mut Vec<AddEvent>,
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    if let Some(validator) = validator {
+        events.retain(|event| match validator.validate(event) {
+            Ok(()) => true,
+            Err(err) => {
+                errors.push(format!("invalid event: {err}"));
+                false
+            }
+        });
+    }
+    errors
+}
+
+/// Write a batch of events, after removing the ones that don't match the stream's (cached)
+/// event schema with `check`, which returns their errors. If the write finds that the stream's
+/// event schema changed, the cache is updated and the events are checked again.
+async fn write_event_batch<E>(
+    writer: &dyn StreamConnection,
+    validators: &EventValidators,
+    key: &str,
+    schema: &mut StreamSchema,
+    events: Vec<AddEvent>,
+    expected_last_id: Option<&str>,
+    check: impl Fn(Option<&EventValidator>, &mut Vec<AddEvent>) -> E,
+) -> (E, Result<AddEventsResponse, WriteError>) {
+    loop {
+        let mut valid = events.clone();
+        let errors = check(schema.validator(), &mut valid);
+        if events.is_empty() {
+            return (errors, Ok(AddEventsResponse::default()));
+        }
+
+        // Without valid events, the empty write still checks the stream's event schema
+        let expected_last_id = expected_last_id.filter(|_| !valid.is_empty());
+        let written = match writer
+            .write_events(key, &valid, expected_last_id, schema.json())
+            .await
+        {
+            Ok(WriteOutcome::SchemaChanged(json)) => match update_schema(validators, key, json) {
+                Ok(changed) => {
+                    *schema = changed;
+                    continue;
+                }
+                Err(err) => Err(err.into()),
+            },
+            Ok(outcome) => write_response(idempotency_keys(&valid), outcome),
+            Err(err) => Err(err.into()),
+        };
+        return (errors, written);
+    }
+}
+
+/// Get the response for the outcome of writing the events with the given idempotency keys
+fn write_response(
+    idempotency_keys: Vec<Option<String>>,
+    outcome: WriteOutcome,
+) -> Result<AddEventsResponse, WriteError> {
+    match outcome {
+        WriteOutcome::Written(results) => Ok(AddEventsResponse::new(idempotency_keys, results)),
+        WriteOutcome::NotActive => Err(AppError::bad_request("stream not active").into()),
+        WriteOutcome::Conflict(last_id) => Err(WriteError::Conflict {
+            message: "stream's last event ID doesn't match the expected ID",
+            last_id: last_id.to_string(),
+        }),
+        WriteOutcome::StaleId(last_id) => Err(WriteError::Conflict {
+            message: "event ID must be greater than the previous event ID",
+            last_id: last_id.to_string(),
+        }),
+        WriteOutcome::SchemaChanged(_) => {
+            Err(AppError::internal(anyhow::anyhow!("stream's event schema changed")).into())
+        }
+    }
+}
+
+/// Error when writing events, which includes the stream's last event ID for write conflicts
+#[derive(Debug)]
+enum WriteError {
+    /// The stream's last event ID doesn't match the expected ID, or is after the event's ID
+    Conflict {
+        message: &'static str,
+        last_id: String,
+    },
+    Other(AppError),
+}
+
+impl From<AppError> for WriteError {
+    fn from(error: AppError) -> Self {
+        Self::Other(error)
+    }
+}
+impl From<StorageError> for WriteError {
+    fn from(error: StorageError) -> Self {
+        Self::Other(error.into())
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct ConflictResponse {
+    error: ConflictBody,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct ConflictBody {
+    message: String,
+    status: u16,
+    /// Current last event ID of the stream
+    last_id: String,
+}
+
+impl IntoResponse for WriteError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Conflict { message, last_id } => {
+                let response = ConflictResponse {
+                    error: ConflictBody {
+                        message: message.to_owned(),
+                        status: StatusCode::CONFLICT.as_u16(),
+                        last_id,
+                    },
+                };
+                (StatusCode::CONFLICT, Json(response)).into_response()
+            }
+            Self::Other(error) => error.into_response(),
+        }
+    }
+}
+
+impl OperationOutput for WriteError {
+    type Inner = ConflictResponse;
+
+    fn inferred_responses(
+        ctx: &mut aide::generate::GenContext,
+        operation: &mut aide::openapi::Operation,
+    ) -> Vec<(Option<aide::openapi::StatusCode>, aide::openapi::Response)> {
+        let mut responses = AppError::inferred_responses(ctx, operation);
+        if let Some(response) = Json::<ConflictResponse>::operation_response(ctx, operation) {
+            responses.push((Some(aide::openapi::StatusCode::Code(409)), response));
+        }
+        responses
+    }
+}
+
+/// When writing with an expected last ID, expect the next batch to follow the added events
+fn chain_expected_id(expected_last_id: &mut Option<String>, written: &AddEventsResponse) {
+    if expected_last_id.is_some() && written.last_id.is_some() {
+        expected_last_id.clone_from(&written.last_id);
     }
 }
 
 #[derive(Debug, Serialize)]
 #[serde(tag = "status", rename_all = "lowercase")]
 enum WsResponse {
-    Success { num_events: usize },
-    Error { message: String },
+    Success {
+        num_events: usize,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        duplicates: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        last_id: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+    /// The stream's last event ID didn't match the expected ID
+    Conflict {
+        message: String,
+        last_id: String,
+    },
 }
 impl WsResponse {
-    fn success(num_events: usize) -> Self {
-        Self::Success { num_events }
+    fn success(written: AddEventsResponse) -> Self {
+        Self::Success {
+            num_events: written.num_events,
+            duplicates: written.duplicates,
+            last_id: written.last_id,
+        }
+    }
+    fn conflict(message: &str, last_id: String) -> Self {
+        Self::Conflict {
+            message: message.to_owned(),
+            last_id,
+        }
     }
     fn error(message: impl Into<String>) -> Self {
         Self::Error {
@@ -196,10 +555,7 @@ fn transform_ws_stream(
                 }
             }
             axum::extract::ws::Message::Binary(bytes) => {
-                match serde_json::from_slice::<AddEvent>(&bytes) {
-                    Ok(event) => Some(Ok(WsStreamItem::Event(event))),
-                    Err(err) => Some(Err(err.to_string())),
-                }
+                Some(parse_binary_event(bytes).map(WsStreamItem::Event))
             }
             axum::extract::ws::Message::Close(_) => Some(Ok(WsStreamItem::Close)),
             _ => None,
@@ -207,3 +563,26 @@ fn transform_ws_stream(
         Err(err) => Some(Err(err.to_string())),
     })
 }
+
+/// Parse a binary WebSocket frame into an event with binary data. The frame starts with a
+/// JSON header line with the event fields (e.g. `{"event":"audio"}`), followed by a newline
+/// and the raw data. Frames without such a header are parsed as a JSON event, like text
+/// messages (and like binary frames before binary data was supported).
+fn parse_binary_event(frame: axum::body::Bytes) -> Result<AddEvent, String> {
+    let header = frame
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .and_then(|header_len| {
+            let event: AddEvent = serde_json::from_slice(&frame[..header_len]).ok()?;
+            event.data.is_none().then_some((header_len, event))
+        });
+    match header {
+        Some((header_len, mut event)) => {
+            event.data = Some(EventData::Binary(frame.slice(header_len + 1..)));
+            Ok(event)
+        }
+        None => serde_json::from_slice(&frame).map_err(|err| {
+            format!("invalid binary frame, expected a JSON header line or JSON event: {err}")
+        }),
+    }
+}
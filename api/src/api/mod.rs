@@ -9,6 +9,7 @@ pub mod health;
 pub mod info;
 pub mod ingest;
 pub mod stream;
+pub mod webhook;
 
 /// Adds all API routes to the server under `/api`
 pub fn plugin() -> AdHocPlugin<AppState, AppConfig> {
@@ -19,12 +20,14 @@ pub fn plugin() -> AdHocPlugin<AppState, AppConfig> {
         let api_routes = aide::axum::ApiRouter::new()
             // backend / stream management routes
             .nest(&format!("{BASE_PATH}/info"), info::routes())
-            .nest(&format!("{BASE_PATH}/event"), ingest::routes())
             .nest(&format!("{BASE_PATH}/stream"), stream::routes())
+            .nest(&format!("{BASE_PATH}/webhook"), webhook::routes())
             // protect all previous routes with API key
             .layer(middleware::from_extractor_with_state::<ApiKey, AppState>(
                 app.state().clone(),
             ))
+            // ingest routes (API key or client token with write scope)
+            .nest(&format!("{BASE_PATH}/event"), ingest::routes())
             // client and health routes
             .nest(&format!("{BASE_PATH}/client"), client::routes().into())
             .nest(&format!("{BASE_PATH}/health"), health::routes())
@@ -1,13 +1,19 @@
-use axum::{Json, extract::State};
+use std::collections::{HashMap, HashSet};
+
+use axum::{Json, extract::State, response::NoContent};
 use axum_aide_macros::api_routes;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use time::UtcDateTime;
 
 use crate::{
+    auth::{MAX_KEY_LEN, TokenScope, TokenScopes, is_valid_stream_key, key_prefix, unix_millis},
     error::{AppError, AppResult},
-    extractors::{JsonBody, Query, ReaderClient, StaticClient},
-    redis::{StreamEvent, StreamStatus},
+    extractors::{JsonBody, Query, Storage},
+    redis::{FinishDetails, StreamEvent, StreamMeta, StreamSettings, StreamStatus, util},
     state::AppState,
+    storage::{EventSchema, PageQuery, StreamStorage},
+    webhooks::WebhookEvent,
 };
 
 api_routes! {
@@ -19,14 +25,24 @@ api_routes! {
     GET "/events" => get_stream_events, "Get stream events";
     POST "/" => create_stream, "Create stream";
     POST "/token" => create_token, "Create client token";
+    POST "/revoke" => revoke_tokens, "Revoke client tokens";
+    POST "/touch" => touch_stream, "Touch stream";
     POST "/cancel" => cancel_stream, "Cancel stream";
     POST "/end" => end_stream, "End stream";
 }
 
+/// Prefix for query parameters that filter streams by attribute (e.g. `attr.user=42`)
+const ATTR_QUERY_PREFIX: &str = "attr.";
+
 #[derive(Debug, Deserialize, JsonSchema)]
 struct StreamPatternQuery {
     /// Key prefix / pattern to search for
     pattern: Option<String>,
+    /// Status of the streams to list (default: active)
+    status: Option<StreamStatus>,
+    /// Attribute values to filter by, as `attr.<name>=<value>` parameters
+    #[serde(flatten)]
+    filters: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize, JsonSchema)]
@@ -37,58 +53,154 @@ struct StreamKeyQuery {
 
 async fn list_streams(
     Query(query): Query<StreamPatternQuery>,
-    StaticClient(redis): StaticClient,
+    Storage(storage): Storage,
 ) -> AppResult<Json<Vec<StreamInfo>>> {
-    let streams = redis.scan_streams(query.pattern.as_deref()).await?;
+    let attributes: Vec<_> = query
+        .filters
+        .iter()
+        .filter_map(|(param, value)| Some((param.strip_prefix(ATTR_QUERY_PREFIX)?, value.as_str())))
+        .collect();
+    let status = query.status.unwrap_or(StreamStatus::Active);
+    let streams = storage
+        .scan_streams(query.pattern.as_deref(), status, &attributes)
+        .await?;
     let response = streams
         .into_iter()
-        .map(|(key, length, ttl)| StreamInfo { key, length, ttl })
+        .filter_map(|(key, meta, length, ttl)| StreamInfo::new(key, meta, length, ttl))
         .collect();
 
     Ok(Json(response))
 }
 
+/// # Get stream info
+/// Get the status, length, TTL, and attributes of a stream, and why it finished. Finished
+/// streams are kept until their TTL passes, and expired streams for the expired retention
+/// period.
 async fn get_stream_info(
     Query(query): Query<StreamKeyQuery>,
-    StaticClient(redis): StaticClient,
+    Storage(storage): Storage,
 ) -> AppResult<Json<StreamInfo>> {
-    let (status, length, ttl) = redis.stream_info(&query.key).await?;
-    if status.is_none_or(|s| *s != StreamStatus::Active) {
-        return Err(AppError::not_found("active stream not found"));
-    }
+    let (meta, length, ttl) = storage.stream_info(&query.key).await?;
+    let Some(info) = StreamInfo::new(query.key, meta, length, ttl) else {
+        return Err(AppError::not_found("stream not found"));
+    };
 
-    Ok(Json(StreamInfo {
-        key: query.key.to_owned(),
-        length,
-        ttl,
-    }))
+    Ok(Json(info))
 }
 
+/// Default number of events per page when getting stream events
+const DEFAULT_EVENTS_LIMIT: u32 = 100;
+/// Maximum number of events per page when getting stream events
+const MAX_EVENTS_LIMIT: u32 = 1000;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct StreamEventsQuery {
+    /// Key of the stream
+    key: String,
+    /// ID of the first event to include (default: start of the stream). A millisecond
+    /// timestamp can also be given, to include all events from that time.
+    start: Option<String>,
+    /// ID of the last event to include (default: end of the stream). A millisecond
+    /// timestamp can also be given, to include all events up to that time.
+    end: Option<String>,
+    /// Maximum number of events to return (default: 100, max: 1000)
+    limit: Option<u32>,
+    /// Return the events from newest to oldest (default: false)
+    #[serde(default)]
+    reverse: bool,
+}
+
+/// # Get stream events
+/// Get a page of the stored events of a stream. If there are more events, the
+/// `next_cursor` of the response can be passed as the `start` of the next page
+/// (or as the `end` when paging in reverse).
 async fn get_stream_events(
-    Query(query): Query<StreamKeyQuery>,
-    ReaderClient(reader): ReaderClient,
-) -> AppResult<Json<Vec<StreamEvent>>> {
-    let events = reader.prev_formatted_events(&query.key).await?;
-    Ok(Json(events))
+    Query(query): Query<StreamEventsQuery>,
+    State(state): State<AppState>,
+) -> AppResult<Json<StreamEventsResponse>> {
+    let limit = check_limit("limit", query.limit, DEFAULT_EVENTS_LIMIT, MAX_EVENTS_LIMIT)?;
+    let start = match query.start.as_deref() {
+        Some(start) => util::parse_range_bound(start, false)
+            .ok_or_else(|| AppError::bad_request("invalid start ID"))?,
+        None => (0, 0),
+    };
+    let end = match query.end.as_deref() {
+        Some(end) => util::parse_range_bound(end, true)
+            .ok_or_else(|| AppError::bad_request("invalid end ID"))?,
+        None => (u64::MAX, u64::MAX),
+    };
+    let page_query = PageQuery {
+        start,
+        end,
+        count: limit as usize,
+        reverse: query.reverse,
+    };
+    let (events, next_cursor) = state
+        .history()
+        .formatted_events_page(&query.key, page_query)
+        .await?;
+
+    Ok(Json(StreamEventsResponse {
+        events,
+        next_cursor,
+    }))
 }
 
 /// # Create stream
 /// Create a new stream, and get a client URL and token to connect to the stream
 async fn create_stream(
-    StaticClient(redis): StaticClient,
+    Storage(storage): Storage,
     State(state): State<AppState>,
-    JsonBody(input): JsonBody<StreamRequest>,
+    JsonBody(input): JsonBody<CreateStreamRequest>,
 ) -> AppResult<Json<StreamAccessResponse>> {
-    let start_id = redis
-        .start_stream(&input.key, state.config.stream_ttl)
-        .await?;
+    check_stream_key(&input.key)?;
+    let config = &state.config;
+    let ttl = check_limit("ttl", input.ttl, config.stream_ttl, config.stream_ttl_limit)?;
+    let max_len = check_limit(
+        "max_len",
+        input.max_len,
+        config.max_stream_len,
+        config.max_stream_len_limit,
+    )?;
+    let inactivity_timeout = input
+        .inactivity_timeout
+        .map(|timeout| check_limit("inactivity_timeout", Some(timeout), ttl, ttl))
+        .transpose()?;
+
+    let event_schema = EventSchema {
+        allowed_events: input.allowed_events,
+        schemas: input.event_schemas,
+    };
+    let event_schema = match event_schema.is_empty() {
+        true => None,
+        false => Some(event_schema_json(event_schema)?),
+    };
+
+    let settings = StreamSettings {
+        ttl,
+        max_len,
+        attributes: input.attributes,
+        sequential_ids: input.sequential_ids,
+        sliding_ttl: input.sliding_ttl,
+        inactivity_timeout,
+        cancel_on_request: input.cancel_on_request,
+        event_schema,
+    };
+    let start_id = storage.start_stream(&input.key, &settings).await?;
     if start_id.is_none() {
         return Err(AppError::bad_request("stream at this key already exists"));
     }
+    state.webhooks.send(WebhookEvent::Created, &input.key);
 
+    // Writes keep extending a sliding TTL, so the token can outlive the initial TTL
+    let token_ttl = match input.sliding_ttl {
+        true => config.stream_ttl_limit,
+        false => ttl,
+    };
+    let scopes = TokenScopes::from_iter([TokenScope::Read]);
     let token = state
         .client_tokens()
-        .create(&input.key, state.config.stream_ttl)?;
+        .create(&input.key, token_ttl, scopes)?;
     let stream_service = state.streams();
 
     Ok(Json(StreamAccessResponse {
@@ -99,18 +211,28 @@ async fn create_stream(
 }
 
 /// # Create stream token
-/// Create a new client token for connecting to a stream
+/// Create a new client token for connecting to a stream, or to all streams
+/// under a non-empty key prefix ending in `*` (e.g. `user:42:*`)
 async fn create_token(
-    StaticClient(redis): StaticClient,
+    Storage(storage): Storage,
     State(state): State<AppState>,
-    JsonBody(input): JsonBody<StreamRequest>,
+    JsonBody(input): JsonBody<TokenRequest>,
 ) -> AppResult<Json<StreamAccessResponse>> {
-    if !redis.is_active(&input.key).await? {
-        return Err(AppError::not_found("active stream not found"));
-    }
-    let token = state
-        .client_tokens()
-        .create(&input.key, state.config.stream_ttl)?;
+    check_key_len(&input.key)?;
+    let config = &state.config;
+    let ttl = check_limit("ttl", input.ttl, config.stream_ttl, config.stream_ttl_limit)?;
+    let ttl = match key_prefix(&input.key) {
+        Some("") => return Err(AppError::bad_request("key prefix must not be empty")),
+        Some(_) => ttl,
+        None => match storage.active_stream_ttl(&input.key).await? {
+            // Writes keep extending a sliding TTL, so the token can outlive the remaining TTL
+            Some((stream_ttl, true)) => input.ttl.unwrap_or(stream_ttl),
+            Some((stream_ttl, false)) => input.ttl.map_or(stream_ttl, |ttl| ttl.min(stream_ttl)),
+            None => return Err(AppError::not_found("active stream not found")),
+        },
+    };
+    let scopes = input.scopes.into_iter().collect();
+    let token = state.client_tokens().create(&input.key, ttl, scopes)?;
     let stream_service = state.streams();
 
     Ok(Json(StreamAccessResponse {
@@ -120,48 +242,326 @@ async fn create_token(
     }))
 }
 
+/// # Revoke client tokens
+/// Revoke a single client token, or all tokens issued so far for a stream key or key prefix.
+/// Live consumers and WebSocket producers using a revoked token are disconnected.
+async fn revoke_tokens(
+    Storage(storage): Storage,
+    State(state): State<AppState>,
+    JsonBody(input): JsonBody<RevokeRequest>,
+) -> AppResult<NoContent> {
+    match (input.token, input.key) {
+        (Some(token), None) => {
+            let claims = state
+                .client_tokens()
+                .decode(&token)
+                .map_err(|err| AppError::bad_request(err.to_string()))?;
+            let ttl = (claims.expires_at - UtcDateTime::now()).whole_seconds() + 1;
+            storage.revoke_token(&claims.id, ttl).await?;
+        }
+        (None, Some(key)) => {
+            check_key_len(&key)?;
+            let revoked_at = unix_millis(UtcDateTime::now());
+            let ttl = state.config.stream_ttl_limit.into();
+            storage.revoke_key_tokens(&key, revoked_at, ttl).await?;
+        }
+        _ => {
+            return Err(AppError::bad_request(
+                "either token or key must be provided",
+            ));
+        }
+    }
+
+    Ok(NoContent)
+}
+
+/// # Touch stream
+/// Extend the TTL of an active stream, so that it expires `ttl` seconds from now. A new TTL
+/// also becomes the stream's TTL setting, used for sliding TTLs.
+async fn touch_stream(
+    Storage(storage): Storage,
+    State(state): State<AppState>,
+    JsonBody(input): JsonBody<TouchStreamRequest>,
+) -> AppResult<Json<TouchStreamResponse>> {
+    let limit = state.config.stream_ttl_limit;
+    let ttl = input
+        .ttl
+        .map(|ttl| check_limit("ttl", Some(ttl), limit, limit))
+        .transpose()?;
+    let Some(ttl) = storage.touch_stream(&input.key, ttl).await? else {
+        return Err(AppError::not_found("active stream not found"));
+    };
+
+    Ok(Json(TouchStreamResponse { ttl }))
+}
+
 /// # Cancel stream
+/// Cancel a stream, optionally with a reason and final data for the `cancel` event
 async fn cancel_stream(
-    StaticClient(redis): StaticClient,
-    JsonBody(input): JsonBody<StreamRequest>,
+    Storage(storage): Storage,
+    State(state): State<AppState>,
+    JsonBody(input): JsonBody<CancelStreamRequest>,
 ) -> AppResult<Json<EndStreamResponse>> {
-    if redis.cancel_stream(&input.key).await?.is_none() {
+    let details = FinishDetails {
+        reason: input.reason,
+        data: input.data,
+    };
+    if storage
+        .finish_stream(&input.key, StreamStatus::Cancelled, Some(&details))
+        .await?
+        .is_none()
+    {
         return Err(AppError::not_found("active stream not found"));
     }
+    state.webhooks.send(WebhookEvent::Cancelled, &input.key);
 
     Ok(Json(EndStreamResponse {
         status: StreamStatus::Cancelled,
+        compacted: None,
     }))
 }
 
 /// # End stream
+/// End a stream, optionally with a reason and final data for the `end` event, and optionally
+/// compacting it so that late joiners replay fewer events
 async fn end_stream(
-    StaticClient(redis): StaticClient,
-    JsonBody(input): JsonBody<StreamRequest>,
+    Storage(storage): Storage,
+    State(state): State<AppState>,
+    JsonBody(input): JsonBody<EndStreamRequest>,
 ) -> AppResult<Json<EndStreamResponse>> {
-    if redis.end_stream(&input.key).await?.is_none() {
+    let details = FinishDetails {
+        reason: input.reason,
+        data: input.data,
+    };
+    if storage
+        .finish_stream(&input.key, StreamStatus::Ended, Some(&details))
+        .await?
+        .is_none()
+    {
         return Err(AppError::not_found("active stream not found"));
     }
+    state.webhooks.send(WebhookEvent::Ended, &input.key);
+    let compacted = match input.compact.is_empty() {
+        true => None,
+        false => Some(compact_ended_stream(storage.as_ref(), &input.key, &input.compact).await),
+    };
 
     Ok(Json(EndStreamResponse {
         status: StreamStatus::Ended,
+        compacted,
     }))
 }
 
+/// Compact the ended stream, returning whether it was compacted. The stream has ended
+/// either way, and can still be replayed without compaction.
+async fn compact_ended_stream(storage: &dyn StreamStorage, key: &str, events: &[String]) -> bool {
+    match storage.compact_stream(key, events).await {
+        Ok(Some(_)) => true,
+        Ok(None) => {
+            tracing::warn!("Failed to compact stream {key}: ended stream not found");
+            false
+        }
+        Err(error) => {
+            tracing::warn!("Failed to compact stream {key}: {error}");
+            false
+        }
+    }
+}
+
+/// Check that the JSON Schemas of the events are valid, and serialize the event schema
+fn event_schema_json(event_schema: EventSchema) -> AppResult<String> {
+    let json = serde_json::to_string(&event_schema).map_err(anyhow::Error::from)?;
+    event_schema.compile().map_err(AppError::bad_request)?;
+
+    Ok(json)
+}
+
+/// Get the requested value or the default, checking that it's within the allowed limit
+fn check_limit(name: &str, value: Option<u32>, default: u32, limit: u32) -> AppResult<u32> {
+    match value {
+        None => Ok(default),
+        Some(0) => Err(AppError::bad_request(format!(
+            "{name} must be greater than 0"
+        ))),
+        Some(value) if value > limit => Err(AppError::bad_request(format!(
+            "{name} must not exceed {limit}"
+        ))),
+        Some(value) => Ok(value),
+    }
+}
+
+/// Check that a new stream's key is non-empty (an empty key would become an empty hash tag in
+/// cluster mode, which spreads the stream's keys over different hash slots), doesn't contain
+/// the wildcard, and isn't too long
+fn check_stream_key(key: &str) -> AppResult<()> {
+    if key.is_empty() {
+        return Err(AppError::bad_request("stream key must not be empty"));
+    }
+    if !is_valid_stream_key(key) {
+        return Err(AppError::bad_request("stream key can't contain `*`"));
+    }
+    check_key_len(key)
+}
+
+/// Check that the stream key or token key isn't longer than the maximum length
+fn check_key_len(key: &str) -> AppResult<()> {
+    match key.len() > MAX_KEY_LEN {
+        true => Err(AppError::bad_request(format!(
+            "key must not exceed {MAX_KEY_LEN} bytes"
+        ))),
+        false => Ok(()),
+    }
+}
+
 /// Information about the stream
 #[derive(JsonSchema, Serialize)]
 pub struct StreamInfo {
     /// Key of the stream in Redis
     key: String,
+    /// Status of the stream
+    status: StreamStatus,
     /// Number of events in the stream
     length: u64,
     /// Expiration of the stream
     ttl: i64,
+    /// Custom attributes of the stream
+    attributes: HashMap<String, String>,
+    /// Why the stream was ended or cancelled, if a reason was given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// Final data given when the stream was ended or cancelled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+    /// Whether a consumer has requested cancellation of the stream
+    cancel_requested: bool,
+}
+
+impl StreamInfo {
+    fn new(key: String, meta: StreamMeta, length: u64, ttl: i64) -> Option<Self> {
+        let status = meta.stream_status()?;
+        let finish = meta.finish.unwrap_or_default();
+
+        Some(Self {
+            key,
+            status,
+            length,
+            ttl,
+            attributes: meta.attributes,
+            reason: finish.reason,
+            data: finish.data,
+            cancel_requested: meta.cancel_requested,
+        })
+    }
+}
+
+/// A page of stream events
+#[derive(JsonSchema, Serialize)]
+struct StreamEventsResponse {
+    /// Events of the page, in order
+    events: Vec<StreamEvent>,
+    /// ID of the first event of the next page, if there are more events
+    next_cursor: Option<String>,
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct CancelStreamRequest {
+    /// Key of the stream
+    key: String,
+    /// Why the stream was cancelled (e.g. `user_stopped` or `provider_error`)
+    reason: Option<String>,
+    /// Final data of the stream (e.g. an error message)
+    data: Option<serde_json::Value>,
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct EndStreamRequest {
+    /// Key of the stream
+    key: String,
+    /// Why the stream was ended (e.g. `completed` or `max_tokens`)
+    reason: Option<String>,
+    /// Final data of the stream (e.g. usage totals)
+    data: Option<serde_json::Value>,
+    /// Names of events to compact after ending the stream (e.g. `delta`). Each run of
+    /// consecutive events with one of these names is merged into a single event with the
+    /// concatenated data and the ID of the run's last event. Events with JSON data aren't
+    /// merged.
+    #[serde(default)]
+    compact: Vec<String>,
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct TokenRequest {
+    /// Key of the stream, or a key prefix ending in `*` to access all matching streams
+    key: String,
+    /// TTL of the token in seconds (default: the stream's remaining TTL, or the server default
+    /// for prefix tokens). Tokens for a single stream can't outlive the stream's remaining TTL,
+    /// which can be extended by touching the stream, unless the stream has a sliding TTL.
+    ttl: Option<u32>,
+    /// Permissions granted by the token (default: read only)
+    #[serde(default = "default_token_scopes")]
+    scopes: Vec<TokenScope>,
+}
+
+fn default_token_scopes() -> Vec<TokenScope> {
+    vec![TokenScope::Read]
 }
 
 #[derive(JsonSchema, Deserialize)]
-struct StreamRequest {
+struct RevokeRequest {
+    /// Client token to revoke
+    token: Option<String>,
+    /// Stream key or key prefix (ending in `*`) to revoke all previously issued tokens for
+    key: Option<String>,
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct TouchStreamRequest {
+    /// Key of the stream
     key: String,
+    /// New TTL of the stream in seconds, counted from now (default: the stream's TTL setting)
+    ttl: Option<u32>,
+}
+
+#[derive(JsonSchema, Serialize)]
+struct TouchStreamResponse {
+    /// TTL of the stream in seconds, counted from now
+    ttl: u32,
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct CreateStreamRequest {
+    /// Key of the stream (can't contain `*`, which is reserved for token key prefixes)
+    key: String,
+    /// TTL of the stream and client token in seconds (uses the server default if not set)
+    ttl: Option<u32>,
+    /// Approximate maximum number of events in the stream (uses the server default if not set)
+    max_len: Option<u32>,
+    /// Custom attributes to store with the stream (e.g. user or conversation ID)
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+    /// Assign sequential event IDs (`1-0`, `2-0`, ...) instead of timestamp IDs, so the
+    /// first part of each event ID is its sequence number in the stream. The start event
+    /// has ID `0-1`, and the sequence number can be used as the `Last-Event-ID`.
+    #[serde(default)]
+    sequential_ids: bool,
+    /// Refresh the TTL of the stream on every write, so that it only expires after being
+    /// idle for its TTL
+    #[serde(default)]
+    sliding_ttl: bool,
+    /// Finish the stream with the `timed_out` status after this many seconds without writes
+    /// (e.g. when the producer crashed). Can't exceed the stream's TTL.
+    inactivity_timeout: Option<u32>,
+    /// Cancel the stream right away when a consumer requests cancellation (with a client
+    /// token with the `cancel` scope). Otherwise, the request is only recorded with a
+    /// `cancel_requested` event for the producer to act on.
+    #[serde(default)]
+    cancel_on_request: bool,
+    /// Names of the events that can be added to the stream (default: any event)
+    allowed_events: Option<HashSet<String>>,
+    /// JSON Schema for the data of each event name (events without data are checked as
+    /// `null`). Events that don't match are rejected when they're added.
+    #[serde(default)]
+    event_schemas: HashMap<String, serde_json::Value>,
 }
 
 #[derive(JsonSchema, Serialize, Deserialize)]
@@ -176,7 +576,53 @@ struct StreamAccessResponse {
 }
 
 #[derive(JsonSchema, Serialize)]
-struct EndStreamResponse {
+pub struct EndStreamResponse {
     /// Status of the stream
-    status: StreamStatus,
+    pub status: StreamStatus,
+    /// Whether the stream was compacted, if compaction was requested. The stream has ended
+    /// even if it couldn't be compacted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compacted: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn stream_key_checks() {
+        assert!(check_stream_key("user:42:chat").is_ok());
+        assert!(check_stream_key("").is_err());
+        assert!(check_stream_key("user:42:*").is_err());
+        assert!(check_stream_key(&"x".repeat(MAX_KEY_LEN)).is_ok());
+        assert!(check_stream_key(&"x".repeat(MAX_KEY_LEN + 1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn ended_stream_compaction_failure() {
+        let (revocations, _) = tokio::sync::broadcast::channel(16);
+        let storage = MemoryStorage::new(1, 0, 60, 60, 100, revocations);
+        let compact = ["delta".to_owned()];
+        assert!(!compact_ended_stream(&storage, "a", &compact).await);
+
+        let settings = StreamSettings {
+            ttl: 60,
+            max_len: 100,
+            attributes: HashMap::new(),
+            sequential_ids: false,
+            sliding_ttl: false,
+            inactivity_timeout: None,
+            cancel_on_request: false,
+            event_schema: None,
+        };
+        storage.start_stream("a", &settings).await.unwrap();
+        assert!(!compact_ended_stream(&storage, "a", &compact).await);
+
+        storage
+            .finish_stream("a", StreamStatus::Ended, None)
+            .await
+            .unwrap();
+        assert!(compact_ended_stream(&storage, "a", &compact).await);
+    }
 }
@@ -0,0 +1,41 @@
+use axum::{Json, extract::State};
+use axum_aide_macros::api_routes;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{
+    error::AppResult,
+    extractors::Query,
+    state::AppState,
+    webhooks::{DeliveryStatus, WebhookDelivery},
+};
+
+api_routes! {
+    state: AppState,
+    tag: "webhook",
+    security: "ApiKey",
+    GET "/deliveries" => get_deliveries, "Get webhook deliveries";
+}
+
+/// Default number of deliveries returned
+const DEFAULT_DELIVERIES_LIMIT: usize = 50;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct DeliveriesQuery {
+    /// Maximum number of deliveries to return (default: 50)
+    limit: Option<usize>,
+    /// Only return deliveries with this status
+    status: Option<DeliveryStatus>,
+}
+
+/// # Get webhook deliveries
+/// Get the recent webhook deliveries of this server instance, newest first
+async fn get_deliveries(
+    Query(query): Query<DeliveriesQuery>,
+    State(state): State<AppState>,
+) -> AppResult<Json<Vec<WebhookDelivery>>> {
+    let limit = query.limit.unwrap_or(DEFAULT_DELIVERIES_LIMIT);
+    let deliveries = state.webhooks.log().recent(limit, query.status);
+
+    Ok(Json(deliveries))
+}
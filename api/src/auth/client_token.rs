@@ -1,3 +1,6 @@
+use chacha20poly1305::aead::Generate;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use time::{Duration, UtcDateTime};
 
 use crate::auth::{AuthError, TokenEncryption};
@@ -7,52 +10,362 @@ pub struct ClientToken<'r> {
 }
 
 struct TokenPayload<'t> {
+    id: &'t str,
     key: &'t str,
+    scopes: TokenScopes,
+    issued_at: i64,
     expires_at: UtcDateTime,
 }
 
+/// Suffix of a token key that grants access to all stream keys starting with the prefix
+const KEY_WILDCARD: char = '*';
+
+/// Maximum length in bytes of a stream key or token key, which bounds the number of key
+/// prefixes checked for revocations
+pub const MAX_KEY_LEN: usize = 256;
+
+/// Get the key prefix if the given token key is a prefix pattern (e.g. `user:42:*`)
+pub fn key_prefix(key: &str) -> Option<&str> {
+    key.strip_suffix(KEY_WILDCARD)
+}
+
+/// Check if revoking the tokens for `revoked_key` (a stream key or key prefix) covers the
+/// token key or stream `key`, i.e. the keys are equal or `key` falls under the revoked prefix
+pub fn revocation_covers(revoked_key: &str, key: &str) -> bool {
+    match key_prefix(revoked_key) {
+        Some(prefix) => key_prefix(key).unwrap_or(key).starts_with(prefix),
+        None => revoked_key == key,
+    }
+}
+
+/// Get all revocation keys that cover the token key or stream `key`: the key itself, and
+/// every key prefix it falls under (e.g. `user:42:chat`, `user:42:chat*`, ..., `u*`, `*`)
+pub fn revocation_keys(key: &str) -> impl Iterator<Item = String> + '_ {
+    let base = key_prefix(key).unwrap_or(key);
+    let prefix_ends = base.char_indices().map(|(end, _)| end).chain([base.len()]);
+    let prefixes = prefix_ends.map(move |end| [&base[..end], "*"].concat());
+
+    key_prefix(key)
+        .is_none()
+        .then(|| key.to_owned())
+        .into_iter()
+        .chain(prefixes)
+}
+
+/// Check that a stream key doesn't contain the wildcard, which is reserved for token key
+/// prefixes (a token for `foo*` would otherwise cover every stream starting with `foo`)
+pub fn is_valid_stream_key(key: &str) -> bool {
+    !key.contains(KEY_WILDCARD)
+}
+
+/// Permission that can be granted by a client token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Read events from the stream
+    Read,
+    /// Write events to the stream
+    Write,
+    /// Request cancellation of the stream
+    Cancel,
+}
+
+impl TokenScope {
+    const ALL: [TokenScope; 3] = [TokenScope::Read, TokenScope::Write, TokenScope::Cancel];
+
+    const fn flag(self) -> u8 {
+        1 << self as u8
+    }
+
+    const fn as_char(self) -> char {
+        match self {
+            TokenScope::Read => 'r',
+            TokenScope::Write => 'w',
+            TokenScope::Cancel => 'c',
+        }
+    }
+}
+
+/// Set of permissions granted by a client token
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenScopes(u8);
+
+impl TokenScopes {
+    /// Check if the given permission is granted
+    pub fn contains(self, scope: TokenScope) -> bool {
+        self.0 & scope.flag() != 0
+    }
+
+    fn to_token_str(self) -> String {
+        TokenScope::ALL
+            .into_iter()
+            .filter(|scope| self.contains(*scope))
+            .map(TokenScope::as_char)
+            .collect()
+    }
+
+    fn from_token_str(scopes_str: &str) -> Result<Self, AuthError> {
+        scopes_str
+            .chars()
+            .map(|c| {
+                TokenScope::ALL
+                    .into_iter()
+                    .find(|scope| scope.as_char() == c)
+            })
+            .collect::<Option<Self>>()
+            .ok_or(AuthError::InvalidToken)
+    }
+}
+
+impl FromIterator<TokenScope> for TokenScopes {
+    fn from_iter<I: IntoIterator<Item = TokenScope>>(iter: I) -> Self {
+        Self(
+            iter.into_iter()
+                .fold(0, |flags, scope| flags | scope.flag()),
+        )
+    }
+}
+
+/// The decoded claims of a valid client token
+pub struct TokenClaims {
+    /// Unique identifier of the token (jti)
+    pub id: String,
+    /// The stream key, or key prefix ending in `*`, that can be accessed
+    pub key: String,
+    /// The granted permissions
+    pub scopes: TokenScopes,
+    /// Issue time of the token (unix milliseconds)
+    pub issued_at: i64,
+    /// Expiration time of the token
+    pub expires_at: UtcDateTime,
+}
+
+impl TokenClaims {
+    /// Check if these claims give access to the stream key
+    pub fn allows_key(&self, key: &str) -> bool {
+        match key_prefix(&self.key) {
+            Some(prefix) => key.starts_with(prefix),
+            None => self.key == key,
+        }
+    }
+
+    /// Verify that these claims give the given permission for the stream key
+    pub fn authorize(&self, key: &str, scope: TokenScope) -> Result<(), AuthError> {
+        if !self.allows_key(key) {
+            return Err(AuthError::PermissionDenied);
+        }
+        if !self.scopes.contains(scope) {
+            return Err(AuthError::MissingScope);
+        }
+
+        Ok(())
+    }
+}
+
 impl<'r> ClientToken<'r> {
     pub fn new(encryptor: &'r TokenEncryption) -> Self {
         Self { encryptor }
     }
 
-    /// Create an encrypted client token that gives access to the given stream key
-    /// and is valid for the given length of time
-    pub fn create(&self, key: &str, ttl: u32) -> Result<String, AuthError> {
+    /// Create an encrypted client token that gives the given permissions for the stream key
+    /// (or all keys under a prefix ending in `*`, e.g. `user:42:*`) and is valid for the
+    /// given length of time
+    pub fn create(&self, key: &str, ttl: u32, scopes: TokenScopes) -> Result<String, AuthError> {
+        let id = hex::encode(<[u8; 12]>::generate());
+        let now = UtcDateTime::now();
         let payload = TokenPayload {
+            id: &id,
             key,
-            expires_at: UtcDateTime::now() + Duration::seconds(ttl.into()),
+            scopes,
+            issued_at: unix_millis(now),
+            expires_at: now + Duration::seconds(ttl.into()),
         };
         let token_str = payload.to_token_str();
 
         self.encryptor.encrypt_base64(&token_str)
     }
 
-    /// Verify that the encrypted client token is valid and matches the given stream key
-    pub fn validate(&self, token: &str, key: &str) -> Result<(), AuthError> {
+    /// Decrypt and verify the client token, returning its claims
+    pub fn decode(&self, token: &str) -> Result<TokenClaims, AuthError> {
         let token_str = self.encryptor.decrypt_base64(token)?;
         let payload = TokenPayload::from_token_str(&token_str)?;
-        if payload.key != key {
+
+        Ok(TokenClaims {
+            id: payload.id.to_owned(),
+            key: payload.key.to_owned(),
+            scopes: payload.scopes,
+            issued_at: payload.issued_at,
+            expires_at: payload.expires_at,
+        })
+    }
+
+    /// Verify that the encrypted client token is valid and matches all of the given stream keys,
+    /// either exactly or by prefix. Returns the claims of the token.
+    pub fn validate<'k>(
+        &self,
+        token: &str,
+        keys: impl IntoIterator<Item = &'k str>,
+    ) -> Result<TokenClaims, AuthError> {
+        let claims = self.decode(token)?;
+        if !keys.into_iter().all(|key| claims.allows_key(key)) {
             return Err(AuthError::PermissionDenied);
         }
 
-        Ok(())
+        Ok(claims)
     }
 }
 
 impl<'t> TokenPayload<'t> {
     fn to_token_str(&self) -> String {
-        format!("{}:{}", self.expires_at.unix_timestamp(), self.key)
+        format!(
+            "{}:{}:{}:{}:{}",
+            self.expires_at.unix_timestamp(),
+            self.issued_at,
+            self.id,
+            self.scopes.to_token_str(),
+            self.key
+        )
     }
 
     fn from_token_str(token_str: &'t str) -> Result<Self, AuthError> {
-        let (unix_expires, key) = token_str.split_once(':').ok_or(AuthError::InvalidToken)?;
+        let mut parts = token_str.splitn(5, ':');
+        let (Some(unix_expires), Some(issued_at), Some(id), Some(scopes), Some(key)) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return Err(AuthError::InvalidToken);
+        };
         let expires_at = UtcDateTime::from_unix_timestamp(unix_expires.parse().unwrap_or_default())
             .map_err(|_| AuthError::InvalidToken)?;
         if expires_at < UtcDateTime::now() {
             return Err(AuthError::ExpiredToken);
         }
 
-        Ok(Self { key, expires_at })
+        Ok(Self {
+            id,
+            key,
+            scopes: TokenScopes::from_token_str(scopes)?,
+            issued_at: issued_at.parse().map_err(|_| AuthError::InvalidToken)?,
+            expires_at,
+        })
+    }
+}
+
+/// Get the unix timestamp in milliseconds
+pub fn unix_millis(date_time: UtcDateTime) -> i64 {
+    (date_time.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_encryptor() -> TokenEncryption {
+        let test_key = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        TokenEncryption::new(test_key).unwrap()
+    }
+
+    #[test]
+    fn scopes_round_trip() {
+        let encryptor = get_test_encryptor();
+        let tokens = ClientToken::new(&encryptor);
+        let scopes = TokenScopes::from_iter([TokenScope::Read, TokenScope::Cancel]);
+
+        let token = tokens.create("user:42", 60, scopes).unwrap();
+        let granted = tokens.validate(&token, ["user:42"]).unwrap().scopes;
+
+        assert_eq!(granted, scopes);
+        assert!(granted.contains(TokenScope::Read));
+        assert!(!granted.contains(TokenScope::Write));
+        assert!(granted.contains(TokenScope::Cancel));
+    }
+
+    #[test]
+    fn wrong_key_or_scope() {
+        let encryptor = get_test_encryptor();
+        let tokens = ClientToken::new(&encryptor);
+        let token = tokens
+            .create("stream", 60, TokenScopes::from_iter([TokenScope::Read]))
+            .unwrap();
+
+        assert!(tokens.validate(&token, ["other-stream"]).is_err());
+        let claims = tokens.decode(&token).unwrap();
+        assert!(claims.authorize("stream", TokenScope::Read).is_ok());
+        assert!(claims.authorize("stream", TokenScope::Write).is_err());
+    }
+
+    #[test]
+    fn unique_token_ids() {
+        let encryptor = get_test_encryptor();
+        let tokens = ClientToken::new(&encryptor);
+        let scopes = TokenScopes::from_iter([TokenScope::Read]);
+
+        let first = tokens
+            .decode(&tokens.create("key", 60, scopes).unwrap())
+            .unwrap();
+        let second = tokens
+            .decode(&tokens.create("key", 60, scopes).unwrap())
+            .unwrap();
+
+        assert_ne!(first.id, second.id);
+        assert!(first.issued_at <= second.issued_at);
+        assert!(first.expires_at > UtcDateTime::now());
+    }
+
+    #[test]
+    fn prefix_revocations() {
+        assert!(revocation_covers("user:42:chat", "user:42:chat"));
+        assert!(revocation_covers("user:42:*", "user:42:chat"));
+        assert!(revocation_covers("user:*", "user:42:*"));
+        assert!(!revocation_covers("user:42:*", "user:*"));
+        assert!(!revocation_covers("user:42:chat", "user:42:*"));
+
+        let keys: Vec<_> = revocation_keys("ab").collect();
+        assert_eq!(keys, ["ab", "*", "a*", "ab*"]);
+        let keys: Vec<_> = revocation_keys("ab*").collect();
+        assert_eq!(keys, ["*", "a*", "ab*"]);
+        assert!(revocation_keys("user:42:chat").all(|key| revocation_covers(&key, "user:42:chat")));
+    }
+
+    #[test]
+    fn prefix_token() {
+        let encryptor = get_test_encryptor();
+        let tokens = ClientToken::new(&encryptor);
+        let token = tokens
+            .create("user:42:*", 60, TokenScopes::from_iter([TokenScope::Read]))
+            .unwrap();
+
+        assert!(tokens.validate(&token, ["user:42:chat-1"]).is_ok());
+        assert!(tokens.validate(&token, ["user:42:chat-2"]).is_ok());
+        assert!(tokens.validate(&token, ["user:420:chat-1"]).is_err());
+        assert!(tokens.validate(&token, ["user:4"]).is_err());
+        assert!(
+            tokens
+                .validate(&token, ["user:42:chat-1", "user:42:chat-2"])
+                .is_ok()
+        );
+        assert!(
+            tokens
+                .validate(&token, ["user:42:chat-1", "user:43:chat-1"])
+                .is_err()
+        );
+        let claims = tokens.decode(&token).unwrap();
+        assert!(claims.authorize("user:42:chat-1", TokenScope::Read).is_ok());
+        assert!(
+            claims
+                .authorize("user:43:chat-1", TokenScope::Read)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn invalid_payload() {
+        assert!(TokenPayload::from_token_str("not-a-token").is_err());
+        assert!(TokenPayload::from_token_str("0:0:id:r:key").is_err());
+        assert!(TokenPayload::from_token_str("99999999999:0:id:x:key").is_err());
+        assert!(TokenPayload::from_token_str("99999999999:now:id:r:key").is_err());
     }
 }
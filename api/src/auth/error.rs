@@ -11,6 +11,8 @@ pub enum AuthError {
     ExpiredToken,
     #[error("Permission denied")]
     PermissionDenied,
+    #[error("Missing token scope")]
+    MissingScope,
     #[error("Failed to encrypt token")]
     Encrypt,
     #[error("Failed to decrypt token")]
@@ -25,6 +27,7 @@ impl From<AuthError> for AppError {
             AuthError::PermissionDenied | AuthError::ExpiredToken | AuthError::InvalidToken => {
                 Self::unauthorized("invalid token")
             }
+            AuthError::MissingScope => Self::forbidden("missing token scope"),
             err => Self::internal(err.into()),
         }
     }
@@ -2,6 +2,9 @@ mod client_token;
 mod crypto;
 mod error;
 
-pub use client_token::ClientToken;
+pub use client_token::{
+    ClientToken, MAX_KEY_LEN, TokenClaims, TokenScope, TokenScopes, is_valid_stream_key,
+    key_prefix, revocation_covers, revocation_keys, unix_millis,
+};
 pub use crypto::TokenEncryption;
 pub use error::AuthError;
@@ -2,6 +2,8 @@ use std::net::{IpAddr, Ipv4Addr};
 
 use serde::{Deserialize, Serialize};
 
+use crate::storage::StorageBackend;
+
 /// Parsed app configuration
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppConfig {
@@ -18,20 +20,51 @@ pub struct AppConfig {
     /// 32-byte hex string (64 characters) used for encrypting client tokens
     pub secret_key: String,
 
+    // Storage
+    /// Storage backend for streams: `redis` or `memory` (default: redis)
+    pub storage: StorageBackend,
+
     // Redis
     pub redis_url: String,
+    /// Connect to a Redis Cluster, storing the keys of each stream in the same hash slot
+    /// (default: false)
+    pub redis_cluster: bool,
     /// Redis static pool size (default: 4)
     pub redis_pool: usize,
     /// Timeout in seconds for Redis connections and commands (default: 4 seconds)
     pub redis_timeout: u32,
     /// Default TTL in seconds for Redis streams (default: 30 minutes)
     pub stream_ttl: u32,
+    /// Upper bound in seconds for the TTL requested when creating a stream (default: 24 hours)
+    pub stream_ttl_limit: u32,
     /// Prefix for all streams in Redis (default: "tinistream:")
     pub key_prefix: String,
     /// Maximum number of events in a Redis stream (default: 5000)
     pub max_stream_len: u32,
+    /// Upper bound for the max length requested when creating a stream (default: 50000)
+    pub max_stream_len_limit: u32,
+    /// Window in seconds for skipping events with an already written idempotency key
+    /// (default: 5 minutes)
+    pub dedup_window: u32,
+    /// Seconds an expired stream is kept with its `expired` event, after its TTL has passed
+    /// (default: 5 minutes)
+    pub expired_retention: u32,
     /// Maximum number of concurrent reading clients (default: 50)
     pub max_clients: usize,
+    /// Maximum number of events read from Redis at once for live clients, at least 1
+    /// (default: 100)
+    pub read_batch_size: u32,
+
+    // Webhooks
+    /// URLs that are sent the lifecycle events of streams, comma-separated list (no webhooks
+    /// by default)
+    pub webhook_urls: Option<String>,
+    /// Secret key for signing the webhook requests with HMAC-SHA256 (required with webhook URLs)
+    pub webhook_secret: Option<String>,
+    /// Maximum number of attempts to deliver each webhook (default: 5)
+    pub webhook_attempts: u32,
+    /// Timeout in seconds for each webhook request (default: 10 seconds)
+    pub webhook_timeout: u32,
 
     // Security
     /// Allowed origins for CORS, comma-separated list of domains (all domains allowed by default)
@@ -50,13 +83,24 @@ impl Default for AppConfig {
             api_key: String::new(),
             api_key_header: "x-api-key".into(),
             secret_key: String::new(),
+            storage: StorageBackend::default(),
             redis_url: "redis://localhost".into(),
+            redis_cluster: false,
             redis_pool: 4,
             redis_timeout: 4,
             stream_ttl: 30 * 60,
+            stream_ttl_limit: 24 * 60 * 60,
             key_prefix: "tinistream:".into(),
             max_stream_len: 5000,
+            max_stream_len_limit: 50_000,
+            dedup_window: 5 * 60,
+            expired_retention: 5 * 60,
             max_clients: 50,
+            read_batch_size: 100,
+            webhook_urls: None,
+            webhook_secret: None,
+            webhook_attempts: 5,
+            webhook_timeout: 10,
             allowed_origins: None,
             body_limit: 10 * 1024 * 1024, // 10 MB
         }
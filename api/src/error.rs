@@ -61,6 +61,14 @@ impl AppError {
         }
     }
 
+    pub fn forbidden(error: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            message: "forbidden".into(),
+            source: Some(anyhow::anyhow!(error.into())),
+        }
+    }
+
     pub fn not_found(message: impl Into<String>) -> Self {
         Self::new(StatusCode::NOT_FOUND, message)
     }
@@ -120,7 +128,7 @@ impl OperationOutput for AppError {
         operation: &mut aide::openapi::Operation,
     ) -> Vec<(Option<aide::openapi::StatusCode>, aide::openapi::Response)> {
         if let Some(response) = Json::<ErrorResponse>::operation_response(ctx, operation) {
-            [400, 401, 404, 429, 500]
+            [400, 401, 403, 404, 429, 500]
                 .into_iter()
                 .map(|code| {
                     let status_code = Some(aide::openapi::StatusCode::Code(code));
@@ -2,14 +2,54 @@ use axum::{
     extract::{FromRequestParts, Query},
     http::header,
 };
+use itertools::Itertools;
 use serde::Deserialize;
 
-use crate::{error::AppError, state::AppState};
+use crate::{
+    auth::{MAX_KEY_LEN, TokenClaims, TokenScope, revocation_keys},
+    error::AppError,
+    redis::RevocationListener,
+    state::AppState,
+    storage::StreamStorage,
+};
+
+/// Maximum number of streams that can be requested at once
+const MAX_KEYS: usize = 50;
 
-/// Validate the client token
+/// Validate the client token for one or more stream keys (via repeated `key` query
+/// parameters), and check that it hasn't been revoked
 pub struct ClientTokenAuth {
-    /// The stream key that can be accessed
-    pub key: String,
+    /// The stream keys that can be accessed
+    pub keys: Vec<String>,
+    /// The claims of the token
+    pub claims: TokenClaims,
+}
+
+impl ClientTokenAuth {
+    /// Ensure the token grants the given permission
+    pub fn require(&self, scope: TokenScope) -> Result<(), AppError> {
+        match self.claims.scopes.contains(scope) {
+            true => Ok(()),
+            false => Err(AppError::forbidden("missing token scope")),
+        }
+    }
+
+    /// Get the stream key, for routes that only accept a single stream
+    pub fn single_key(&self) -> Result<&str, AppError> {
+        match self.keys.as_slice() {
+            [key] => Ok(key),
+            _ => Err(AppError::bad_request("expected a single stream key")),
+        }
+    }
+
+    /// Returns a future that resolves once the token is revoked, for disconnecting live consumers
+    pub fn revoked(&self, revocations: &RevocationListener) -> impl Future<Output = ()> + use<> {
+        revocations.revoked(
+            self.claims.id.clone(),
+            self.claims.key.clone(),
+            self.keys.clone(),
+        )
+    }
 }
 
 impl FromRequestParts<AppState> for ClientTokenAuth {
@@ -22,27 +62,82 @@ impl FromRequestParts<AppState> for ClientTokenAuth {
         // Extract the query
         let Query(query) = Query::<ClientTokenQuery>::try_from_uri(&parts.uri)
             .map_err(|_| AppError::bad_request("invalid query"))?;
+        let keys = query_keys(parts.uri.query().unwrap_or_default());
+        if keys.is_empty() {
+            return Err(AppError::bad_request("missing stream key"));
+        }
+        if keys.len() > MAX_KEYS {
+            return Err(AppError::bad_request(format!(
+                "too many stream keys (maximum {MAX_KEYS})"
+            )));
+        }
 
         // Try to get token from the Authorization header, or from the 'token' query
-        let bearer_token = parts
-            .headers
-            .get(header::AUTHORIZATION)
-            .and_then(|val| val.to_str().ok())
-            .and_then(|val| val.strip_prefix("Bearer "));
-        let token = bearer_token
+        let token = bearer_token(parts)
             .or(query.token.as_deref())
             .ok_or_else(|| AppError::unauthorized("missing token"))?;
 
-        // Validate the token
-        match state.client_tokens().validate(token, &query.key) {
-            Ok(_) => Ok(Self { key: query.key }),
-            Err(err) => Err(AppError::unauthorized(err.to_string())),
-        }
+        // Validate the token for all of the keys
+        let claims = state
+            .client_tokens()
+            .validate(token, keys.iter().map(String::as_str))
+            .map_err(|err| AppError::unauthorized(err.to_string()))?;
+        ensure_not_revoked(&*state.storage, &claims, &keys).await?;
+
+        Ok(Self { keys, claims })
     }
 }
 
+/// Get the token from the `Authorization: Bearer` header
+pub(super) fn bearer_token(parts: &axum::http::request::Parts) -> Option<&str> {
+    parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.strip_prefix("Bearer "))
+}
+
+/// Check the denylist to ensure the token hasn't been revoked, either by its ID,
+/// or for the token's key, any of the requested stream keys, or a prefix covering them
+pub(super) async fn ensure_not_revoked(
+    storage: &dyn StreamStorage,
+    claims: &TokenClaims,
+    keys: &[String],
+) -> Result<(), AppError> {
+    // Streams can't have longer keys, and the number of checked prefixes grows with the length
+    if keys.iter().any(|key| key.len() > MAX_KEY_LEN) {
+        return Err(AppError::bad_request(format!(
+            "stream key must not exceed {MAX_KEY_LEN} bytes"
+        )));
+    }
+    let keys: Vec<String> = std::iter::once(claims.key.as_str())
+        .chain(keys.iter().map(String::as_str))
+        .flat_map(revocation_keys)
+        .unique()
+        .collect();
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+    match storage
+        .is_token_revoked(&claims.id, claims.issued_at, &keys)
+        .await?
+    {
+        true => Err(AppError::unauthorized("revoked token")),
+        false => Ok(()),
+    }
+}
+
+/// Get the unique stream keys from the (possibly repeated) `key` query parameters
+fn query_keys(query: &str) -> Vec<String> {
+    query
+        .split('&')
+        .filter_map(|param| {
+            let key = param.strip_prefix("key=")?.replace('+', " ");
+            urlencoding::decode(&key).ok().map(|key| key.into_owned())
+        })
+        .unique()
+        .collect()
+}
+
 #[derive(Deserialize)]
 struct ClientTokenQuery {
-    key: String,
     token: Option<String>,
 }
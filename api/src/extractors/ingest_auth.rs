@@ -0,0 +1,96 @@
+use aide::OperationIo;
+use axum::extract::{FromRequestParts, Query};
+use futures::future::Either;
+use serde::Deserialize;
+
+use crate::{
+    auth::{AuthError, TokenClaims, TokenScope},
+    error::AppError,
+    extractors::{
+        ApiKey,
+        client_token::{bearer_token, ensure_not_revoked},
+    },
+    redis::RevocationListener,
+    state::AppState,
+    storage::StreamStorage,
+};
+
+/// Extractor that authenticates event ingestion via either the API key, or a
+/// client token. Call [`IngestAuth::authorize`] to check access to a stream (including
+/// whether the token was revoked for it).
+#[derive(OperationIo)]
+pub enum IngestAuth {
+    ApiKey,
+    ClientToken(TokenClaims),
+}
+
+impl IngestAuth {
+    /// Ensure events can be written to the given stream key, and that the token
+    /// hasn't been revoked for it
+    pub async fn authorize(&self, storage: &dyn StreamStorage, key: &str) -> Result<(), AppError> {
+        match self {
+            Self::ApiKey => Ok(()),
+            Self::ClientToken(claims) => {
+                claims
+                    .authorize(key, TokenScope::Write)
+                    .map_err(|err| match err {
+                        AuthError::MissingScope => AppError::forbidden(err.to_string()),
+                        err => AppError::unauthorized(err.to_string()),
+                    })?;
+                ensure_not_revoked(storage, claims, &[key.to_owned()]).await
+            }
+        }
+    }
+
+    /// Returns a future that resolves once the client token is revoked for the stream key,
+    /// for disconnecting live producers. Never resolves when using the API key.
+    pub fn revoked(
+        &self,
+        revocations: &RevocationListener,
+        key: &str,
+    ) -> impl Future<Output = ()> + use<> {
+        match self {
+            Self::ApiKey => Either::Left(futures::future::pending()),
+            Self::ClientToken(claims) => Either::Right(revocations.revoked(
+                claims.id.clone(),
+                claims.key.clone(),
+                vec![key.to_owned()],
+            )),
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for IngestAuth {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        // Use the API key if provided
+        if parts.headers.contains_key(&state.config.api_key_header) {
+            ApiKey::from_request_parts(parts, state).await?;
+            return Ok(Self::ApiKey);
+        }
+
+        // Otherwise try to get token from the Authorization header, or from the 'token' query
+        let query_token = Query::<TokenQuery>::try_from_uri(&parts.uri)
+            .ok()
+            .and_then(|Query(query)| query.token);
+        let token = bearer_token(parts)
+            .or(query_token.as_deref())
+            .ok_or_else(|| AppError::unauthorized("missing API key or token"))?;
+
+        let claims = state
+            .client_tokens()
+            .decode(token)
+            .map_err(|err| AppError::unauthorized(err.to_string()))?;
+
+        Ok(Self::ClientToken(claims))
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
@@ -1,15 +1,17 @@
 mod api_key;
 mod client_token;
+mod ingest_auth;
 mod json;
 mod json_stream;
 mod last_event_id;
 mod query;
-mod redis_client;
+mod storage;
 
 pub use api_key::ApiKey;
 pub use client_token::ClientTokenAuth;
+pub use ingest_auth::IngestAuth;
 pub use json::JsonBody;
 pub use json_stream::JsonStream;
 pub use last_event_id::LastEventId;
 pub use query::Query;
-pub use redis_client::{ReaderClient, StaticClient, WriterClient};
+pub use storage::{ReaderClient, Storage, WriterClient};
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use aide::OperationIo;
+use axum::extract::FromRequestParts;
+
+use crate::{
+    error::AppError,
+    state::AppState,
+    storage::{StreamConnection, StreamReader, StreamStorage},
+};
+
+/// Extractor: storage backend for quick operations
+#[derive(OperationIo)]
+pub struct Storage(pub Arc<dyn StreamStorage>);
+/// Extractor: exclusive storage connection for long-running read operations
+#[derive(OperationIo)]
+pub struct ReaderClient(pub StreamReader);
+/// Extractor: exclusive storage connection for long-running write operations
+#[derive(OperationIo)]
+pub struct WriterClient(pub Box<dyn StreamConnection>);
+
+impl Storage {
+    /// Get the storage backend from the app state
+    pub fn from_state(state: &AppState) -> Self {
+        Self(Arc::clone(&state.storage))
+    }
+}
+
+impl FromRequestParts<AppState> for Storage {
+    type Rejection = ();
+    async fn from_request_parts(
+        _parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_state(state))
+    }
+}
+
+impl ReaderClient {
+    /// Check out an exclusive connection from the app state
+    pub async fn from_state(state: &AppState) -> Result<Self, AppError> {
+        match state.storage.connect().await? {
+            Some(connection) => {
+                let reader = StreamReader::new(Arc::clone(&state.storage), connection);
+                Ok(Self(reader))
+            }
+            None => Err(AppError::too_many_requests()),
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for ReaderClient {
+    type Rejection = AppError;
+    async fn from_request_parts(
+        _parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Self::from_state(state).await
+    }
+}
+
+impl FromRequestParts<AppState> for WriterClient {
+    type Rejection = AppError;
+    async fn from_request_parts(
+        _parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        match state.storage.connect().await? {
+            Some(connection) => Ok(Self(connection)),
+            None => Err(AppError::too_many_requests()),
+        }
+    }
+}
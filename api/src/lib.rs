@@ -10,11 +10,15 @@ mod extractors;
 mod plugins;
 mod redis;
 mod state;
+mod storage;
+mod webhooks;
 
 pub async fn create_app() -> anyhow::Result<InitializedApp<AppState, AppConfig>> {
     let app = App::from_env_and_file("STREAMER_", "config.toml")?
         .register(plugins::crypto::plugin()) // Add token encryption
-        .register(plugins::redis::plugin()) // Connect and setup Redis pools
+        .register(plugins::storage::plugin()) // Connect to Redis or set up in-memory storage
+        .register(plugins::webhooks::plugin()) // Lifecycle webhooks
+        .register(plugins::expiry::plugin()) // Expire streams whose TTL has passed
         .register(api::plugin()) // Add API routes
         .register(plugins::logging::plugin()) // Request logging
         .register(plugins::security::plugin()) // Body limit, security headers, etc.
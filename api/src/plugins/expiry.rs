@@ -0,0 +1,24 @@
+use std::sync::Arc;
+
+use crate::{plugins::Plugin, storage::ExpiryWatcher};
+
+/// Plugin that runs the watcher for expired streams
+pub fn plugin() -> Plugin {
+    Plugin::named("Expiry watcher")
+        .on_init(async |mut app| {
+            app.insert(ExpiryWatcher::default())?;
+            Ok(app)
+        })
+        .on_setup(|app, router| {
+            let state = app.state();
+            state
+                .expiry_watcher
+                .start(Arc::clone(&state.storage), state.webhooks.clone());
+
+            Ok(router)
+        })
+        .on_shutdown(async |app| {
+            app.state().expiry_watcher.stop();
+            Ok(())
+        })
+}
@@ -6,6 +6,8 @@ use crate::{config::AppConfig, state::AppState};
 type Plugin = AdHocPlugin<AppState, AppConfig>;
 
 pub mod crypto;
+pub mod expiry;
 pub mod logging;
-pub mod redis;
 pub mod security;
+pub mod storage;
+pub mod webhooks;
@@ -0,0 +1,96 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use fred::{prelude::*, socket2::TcpKeepalive};
+
+use crate::{
+    config::AppConfig,
+    plugins::Plugin,
+    redis::{ExclusiveClientManager, RedisStorage, RevocationListener},
+    storage::{EventValidators, MemoryStorage, StorageBackend, StreamReaders, StreamStorage},
+};
+
+/// Plugin that sets up the storage backend, the shared stream readers, and the
+/// listener for token revocations
+pub fn plugin() -> Plugin {
+    Plugin::named("Storage")
+        .on_init(async |mut app| {
+            let config = app.config();
+            if config.read_batch_size == 0 {
+                anyhow::bail!("read batch size must be at least 1");
+            }
+            let (storage, revocations): (Arc<dyn StreamStorage>, _) = match config.storage {
+                StorageBackend::Redis => {
+                    let (storage, revocations) = connect_redis(config).await?;
+                    (Arc::new(storage), revocations)
+                }
+                StorageBackend::Memory => {
+                    let revocations = RevocationListener::local();
+                    let storage = MemoryStorage::new(
+                        config.max_clients,
+                        config.redis_timeout,
+                        config.dedup_window,
+                        config.expired_retention,
+                        config.read_batch_size,
+                        revocations.sender(),
+                    );
+                    (Arc::new(storage), revocations)
+                }
+            };
+
+            app.insert(storage)?;
+            app.insert(revocations)?;
+            app.insert(StreamReaders::default())?;
+            app.insert(EventValidators::default())?;
+            Ok(app)
+        })
+        .on_shutdown(async |app| {
+            tracing::info!("Shutting down storage connections...");
+            app.state().storage.shutdown().await;
+            app.state().revocations.shutdown().await;
+
+            Ok(())
+        })
+}
+
+/// Connect the Redis static pool and the subscriber for token revocations
+async fn connect_redis(config: &AppConfig) -> anyhow::Result<(RedisStorage, RevocationListener)> {
+    let redis_config = match config.redis_cluster {
+        true => Config::from_url_clustered(&config.redis_url),
+        false => Config::from_url(&config.redis_url),
+    }
+    .context("parse Redis URL")?;
+    if redis_config.server.is_clustered() && !config.redis_cluster {
+        anyhow::bail!("cluster mode must be enabled to connect to a Redis Cluster");
+    }
+    let timeout = Duration::from_secs(config.redis_timeout.into());
+    let mut builder = Builder::from_config(redis_config);
+    builder
+        .with_connection_config(|config| {
+            config.connection_timeout = timeout;
+            config.internal_command_timeout = timeout;
+            config.max_command_attempts = 2;
+            config.tcp.nodelay = Some(true);
+            config.tcp.keepalive = Some(TcpKeepalive::new().with_time(Duration::from_secs(10)));
+        })
+        .with_performance_config(|config| {
+            config.default_command_timeout = timeout;
+        })
+        .set_policy(ReconnectPolicy::new_linear(5, 2_000, 500));
+    let static_pool = builder.build_pool(config.redis_pool)?;
+    static_pool.init().await.context("connect to Redis")?;
+
+    let revocations =
+        RevocationListener::start(builder.build_subscriber_client()?, &config.key_prefix)
+            .await
+            .context("subscribe to token revocations")?;
+
+    let exclusive_clients = ExclusiveClientManager::new(
+        static_pool.next().clone_new(),
+        config.max_clients,
+        config.redis_timeout,
+    );
+    let storage = RedisStorage::new(static_pool, exclusive_clients, config);
+
+    Ok((storage, revocations))
+}
@@ -0,0 +1,11 @@
+use crate::{plugins::Plugin, webhooks::Webhooks};
+
+/// Plugin that sets up the webhooks for the lifecycle events of streams
+pub fn plugin() -> Plugin {
+    Plugin::named("Webhooks").on_init(async |mut app| {
+        let webhooks = Webhooks::new(app.config())?;
+        app.insert(webhooks)?;
+
+        Ok(app)
+    })
+}
@@ -1,8 +1,18 @@
-use fred::prelude::*;
+use fred::{prelude::*, types::sorted_sets::Ordering};
 use futures::StreamExt;
 use itertools::Itertools;
+use time::UtcDateTime;
 
-use crate::redis::{AddEvent, StreamService, constants, scripts::RedisScripts, types::RedisStr};
+use crate::{
+    auth::unix_millis,
+    redis::{
+        AddEvent, FinishDetails, Revocation, StreamMeta, StreamService, StreamSettings, constants,
+        scripts::{ExpireOutcome, RedisScripts},
+        types::{RedisStr, StreamEntry},
+        util,
+    },
+    storage::{CancelRequestOutcome, PageQuery, WriteOutcome},
+};
 
 /// Redis client from static pool. Used for quick operations like retrieving stream status and
 /// initializing a stream, not long-running / blocking commands.
@@ -10,134 +20,532 @@ pub struct RedisClient {
     client: Client,
     stream: StreamService,
     max_len: u32,
+    dedup_window: u32,
+    expired_retention: u32,
 }
 
+/// Maximum number of due streams checked in each expiry sweep
+const EXPIRY_BATCH_SIZE: i64 = 100;
+/// Maximum number of stream entries rewritten by each compaction script call
+const COMPACT_BATCH_SIZE: u32 = 1000;
+
 impl RedisClient {
-    pub fn new(client: Client, max_len: u32, stream_service: StreamService) -> Self {
+    pub fn new(
+        client: Client,
+        max_len: u32,
+        dedup_window: u32,
+        expired_retention: u32,
+        stream_service: StreamService,
+    ) -> Self {
         Self {
             client,
             max_len,
+            dedup_window,
+            expired_retention,
             stream: stream_service,
         }
     }
 
-    /// Get status, length, and TTL of a stream
-    pub async fn stream_info(&self, key: &str) -> FredResult<(Option<RedisStr>, u64, i64)> {
+    /// Get metadata, length, and TTL of a stream
+    pub async fn stream_info(&self, key: &str) -> FredResult<(StreamMeta, u64, i64)> {
         let stream_key = self.stream.stream_key(key);
         let meta_key = self.stream.meta_key(key);
 
         let pipeline = self.client.pipeline();
-        let _: () = pipeline
-            .hget(meta_key, constants::META_STATUS_FIELD)
-            .await?;
+        let _: () = pipeline.hgetall(meta_key).await?;
         let _: () = pipeline.xlen(&stream_key).await?;
         let _: () = pipeline.ttl(&stream_key).await?;
+        let (meta, len, ttl): (StreamMeta, u64, i64) = pipeline.all().await?;
+        let ttl = self.stream_ttl(ttl);
 
-        pipeline.all().await
+        Ok((meta, len, ttl))
     }
 
-    /// Check if there's an active stream with the given key
-    pub async fn is_active(&self, key: &str) -> FredResult<bool> {
-        let status: Option<RedisStr> = self
-            .client
-            .hget(self.stream.meta_key(key), constants::META_STATUS_FIELD)
+    /// Get the TTL of the stream from the TTL of its keys, which also include the retention
+    /// period of expired streams (so expired streams have a TTL of 0)
+    fn stream_ttl(&self, key_ttl: i64) -> i64 {
+        match key_ttl > 0 {
+            true => (key_ttl - i64::from(self.expired_retention)).max(0),
+            false => key_ttl,
+        }
+    }
+
+    /// Get the remaining TTL (in seconds) of the active stream with the given key, and whether
+    /// the stream has a sliding TTL. Returns `None` if the stream is not active, or its TTL
+    /// has passed.
+    pub async fn active_stream_ttl(&self, key: &str) -> FredResult<Option<(u32, bool)>> {
+        let pipeline = self.client.pipeline();
+        let _: () = pipeline
+            .hmget(
+                self.stream.meta_key(key),
+                vec![constants::META_STATUS_FIELD, constants::META_SLIDING_FIELD],
+            )
             .await?;
+        let _: () = pipeline.ttl(self.stream.stream_key(key)).await?;
+        let ((status, sliding), key_ttl): ((Option<RedisStr>, Option<RedisStr>), i64) =
+            pipeline.all().await?;
+        if status.is_none_or(|s| *s != constants::StreamStatus::Active) {
+            return Ok(None);
+        }
 
-        Ok(status.is_some_and(|s| *s == constants::StreamStatus::Active))
+        let ttl = key_ttl - i64::from(self.expired_retention);
+        let sliding = sliding.is_some_and(|sliding| *sliding == "1");
+        Ok(u32::try_from(ttl)
+            .ok()
+            .filter(|ttl| *ttl > 0)
+            .map(|ttl| (ttl, sliding)))
     }
 
     /// Start a new stream by writing a `start` entry and setting the expiration.
     /// Deletes any old inactive stream at the same key.
     /// Returns `None` if the stream is already active.
-    pub async fn start_stream(&self, key: &str, ttl: u32) -> FredResult<Option<RedisStr>> {
+    pub async fn start_stream(
+        &self,
+        key: &str,
+        settings: &StreamSettings,
+    ) -> FredResult<Option<RedisStr>> {
         let stream_key = self.stream.stream_key(key);
         let meta_key = self.stream.meta_key(key);
+        let dedup_key = self.stream.dedup_key(key);
+
+        let start_id = RedisScripts::start_stream(
+            &self.client,
+            &stream_key,
+            &meta_key,
+            &dedup_key,
+            settings,
+            self.expired_retention,
+        )
+        .await?;
+        if start_id.is_some() {
+            let check_after = match settings.inactivity_timeout {
+                Some(timeout) => settings.ttl.min(timeout),
+                None => settings.ttl,
+            };
+            self.schedule_expiry(key, check_after).await?;
+        }
 
-        RedisScripts::start_stream(&self.client, &stream_key, &meta_key, ttl).await
+        Ok(start_id)
     }
 
-    /// Write multiple events to the stream, with an atomic check if the stream is active.
-    /// Returns the IDs of the written events, or `None` if the stream is not active.
-    pub async fn write_events(
-        &self,
-        key: &str,
-        events: Vec<AddEvent>,
-    ) -> FredResult<Option<Vec<RedisStr>>> {
+    /// Extend the TTL of an active stream, so that it expires `ttl` seconds from now (or its
+    /// current TTL setting if not given). Returns the applied TTL, or `None` if the stream is
+    /// not active.
+    pub async fn touch_stream(&self, key: &str, ttl: Option<u32>) -> FredResult<Option<u32>> {
         let stream_key = self.stream.stream_key(key);
         let meta_key = self.stream.meta_key(key);
 
-        RedisScripts::write_events(&self.client, &stream_key, &meta_key, self.max_len, events).await
+        let ttl = RedisScripts::touch_stream(
+            &self.client,
+            &stream_key,
+            &meta_key,
+            ttl,
+            self.expired_retention,
+        )
+        .await?;
+        if let Some(ttl) = ttl {
+            self.schedule_expiry(key, ttl).await?;
+        }
+
+        Ok(ttl)
     }
 
-    /// Mark the stream as ended. Returns `None` if the stream is not active.
-    pub async fn end_stream(&self, key: &str) -> FredResult<Option<RedisStr>> {
-        self.finish_stream(key, constants::StreamStatus::Ended, constants::END)
+    /// Schedule the expiry check of the stream in `ttl` seconds, unless an earlier check is
+    /// already scheduled (a separate command, as the sorted set of all streams can be in a
+    /// different hash slot). Checks that are too early reschedule themselves.
+    async fn schedule_expiry(&self, key: &str, ttl: u32) -> FredResult<()> {
+        let expires_at = unix_millis(UtcDateTime::now()) + i64::from(ttl) * 1000;
+        self.client
+            .zadd(
+                self.stream.expiries_key(),
+                None,
+                Some(Ordering::LessThan),
+                false,
+                false,
+                (expires_at as f64, key),
+            )
             .await
     }
 
-    /// Mark the stream as cancelled. Returns `None` if the stream is not active.
-    pub async fn cancel_stream(&self, key: &str) -> FredResult<Option<RedisStr>> {
-        self.finish_stream(key, constants::StreamStatus::Cancelled, constants::CANCEL)
-            .await
+    /// Write multiple events to the stream, with an atomic check if the stream is active, its
+    /// last event ID matches `expected_last_id` (if given), and its event schema is
+    /// `event_schema`, skipping duplicate events.
+    pub async fn write_events(
+        &self,
+        key: &str,
+        events: &[AddEvent],
+        expected_last_id: Option<&str>,
+        event_schema: Option<&str>,
+    ) -> FredResult<WriteOutcome> {
+        let stream_key = self.stream.stream_key(key);
+        let meta_key = self.stream.meta_key(key);
+        let dedup_key = self.stream.dedup_key(key);
+
+        RedisScripts::write_events(
+            &self.client,
+            (&stream_key, &meta_key, &dedup_key),
+            self.max_len,
+            self.dedup_window,
+            self.expired_retention,
+            events,
+            expected_last_id,
+            event_schema,
+        )
+        .await
     }
 
-    async fn finish_stream(
+    /// Write the terminal event for the given final status (with the finish details as its
+    /// data) and mark the stream inactive. Returns `None` if the stream is not active.
+    pub async fn finish_stream(
         &self,
         key: &str,
         status: constants::StreamStatus,
-        event: &str,
+        details: Option<&FinishDetails>,
     ) -> FredResult<Option<RedisStr>> {
         let stream_key = self.stream.stream_key(key);
         let meta_key = self.stream.meta_key(key);
 
-        RedisScripts::finish_stream(&self.client, &stream_key, &meta_key, status, event).await
+        RedisScripts::finish_stream(&self.client, &stream_key, &meta_key, status, details).await
     }
 
-    /// Get the ID, length, and TTL of all active streams matching the given pattern.
-    pub async fn scan_streams(&self, pattern: Option<&str>) -> FredResult<Vec<(String, u64, i64)>> {
+    /// Record a consumer's request to cancel the active stream, cancelling it right away if
+    /// its settings allow it. Returns `None` if the stream is not active.
+    pub async fn request_cancel(
+        &self,
+        key: &str,
+        details: &FinishDetails,
+    ) -> FredResult<Option<CancelRequestOutcome>> {
+        let stream_key = self.stream.stream_key(key);
+        let meta_key = self.stream.meta_key(key);
+
+        RedisScripts::request_cancel(&self.client, &stream_key, &meta_key, details).await
+    }
+
+    /// Expire the active streams whose TTL has passed, or time out the ones without writes for
+    /// their inactivity timeout, writing their terminal event. Streams with an extended TTL or
+    /// recent writes are rescheduled. Returns the keys and final status of the finished streams.
+    pub async fn expire_streams(&self) -> FredResult<Vec<(String, constants::StreamStatus)>> {
+        let expiries_key = self.stream.expiries_key();
+        let now = unix_millis(UtcDateTime::now());
+        let due_keys: Vec<String> = self
+            .client
+            .zrangebyscore(
+                &expiries_key,
+                "-inf",
+                now as f64,
+                false,
+                Some((0, EXPIRY_BATCH_SIZE)),
+            )
+            .await?;
+        if due_keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut finished = Vec::new();
+        let mut rescheduled = Vec::new();
+        for key in &due_keys {
+            let outcome = RedisScripts::expire_stream(
+                &self.client,
+                &self.stream.stream_key(key),
+                &self.stream.meta_key(key),
+                self.expired_retention,
+            )
+            .await?;
+            match outcome {
+                ExpireOutcome::Finished(status) => finished.push((key.to_owned(), status)),
+                ExpireOutcome::Pending(remaining) => {
+                    rescheduled.push(((now + remaining) as f64, key.as_str()))
+                }
+                ExpireOutcome::NotActive => {}
+            }
+        }
+
+        let due_keys: Vec<&str> = due_keys.iter().map(String::as_str).collect();
+        RedisScripts::remove_expiries(&self.client, &expiries_key, now, &due_keys).await?;
+        if !rescheduled.is_empty() {
+            let _: () = self
+                .client
+                .zadd(
+                    &expiries_key,
+                    None,
+                    Some(Ordering::LessThan),
+                    false,
+                    false,
+                    rescheduled,
+                )
+                .await?;
+        }
+
+        Ok(finished)
+    }
+
+    /// Merge the runs of consecutive events with the given names in a finished stream. The
+    /// stream is compacted in chunks into a temporary stream, which then replaces the stream.
+    /// Returns the number of removed entries, or `None` if the stream is not finished.
+    pub async fn compact_stream(&self, key: &str, events: &[String]) -> FredResult<Option<u64>> {
+        let stream_key = self.stream.stream_key(key);
+        let meta_key = self.stream.meta_key(key);
+        let compact_key = self.stream.compact_key(key);
+
+        let mut removed = 0;
+        let mut last_id = String::new();
+        loop {
+            let chunk = RedisScripts::compact_stream_chunk(
+                &self.client,
+                &stream_key,
+                &meta_key,
+                &compact_key,
+                &last_id,
+                COMPACT_BATCH_SIZE,
+                events,
+            )
+            .await?;
+            let Some((chunk_removed, read, chunk_last_id)) = chunk else {
+                let _: () = self.client.del(&compact_key).await?;
+                return Ok(None);
+            };
+            removed += chunk_removed;
+            if read > 0 {
+                last_id = chunk_last_id;
+            }
+            if read < u64::from(COMPACT_BATCH_SIZE) {
+                break;
+            }
+        }
+
+        if removed == 0 {
+            let _: () = self.client.del(&compact_key).await?;
+            return Ok(Some(0));
+        }
+        let replaced = RedisScripts::replace_compacted_stream(
+            &self.client,
+            &stream_key,
+            &meta_key,
+            &compact_key,
+            &last_id,
+        )
+        .await?;
+
+        Ok(replaced.then_some(removed))
+    }
+
+    /// Get the entries of each stream after the given event ID, along with its status
+    pub async fn range(
+        &self,
+        streams: &[(&str, &str)],
+    ) -> FredResult<Vec<(Vec<StreamEntry>, Option<RedisStr>)>> {
+        let pipeline = self.client.pipeline();
+        for (key, start_event_id) in streams {
+            let _: () = pipeline
+                .xrange(
+                    self.stream.stream_key(key),
+                    ["(", start_event_id].concat(),
+                    "+",
+                    None,
+                )
+                .await?;
+            let _: () = pipeline
+                .hget(self.stream.meta_key(key), constants::META_STATUS_FIELD)
+                .await?;
+        }
+        let results: Vec<Value> = pipeline.all().await?;
+
+        results
+            .into_iter()
+            .tuples()
+            .map(|(entries, status)| Ok((entries.convert()?, status.convert()?)))
+            .collect()
+    }
+
+    /// Get a page of the stream's entries between the IDs of the query (inclusive),
+    /// along with the stream status
+    pub async fn range_page(
+        &self,
+        key: &str,
+        query: &PageQuery,
+    ) -> FredResult<(Vec<StreamEntry>, Option<RedisStr>)> {
+        let stream_key = self.stream.stream_key(key);
+        let (start, end) = (
+            util::format_entry_id(query.start),
+            util::format_entry_id(query.end),
+        );
+        let count = Some(query.count as u64);
+
+        let pipeline = self.client.pipeline();
+        let _: () = match query.reverse {
+            true => pipeline.xrevrange(stream_key, end, start, count).await?,
+            false => pipeline.xrange(stream_key, start, end, count).await?,
+        };
+        let _: () = pipeline
+            .hget(self.stream.meta_key(key), constants::META_STATUS_FIELD)
+            .await?;
+
+        pipeline.all().await
+    }
+
+    /// Get the keys of the given streams that are no longer active
+    pub async fn inactive_streams(&self, keys: &[&str]) -> FredResult<Vec<String>> {
+        let pipeline = self.client.pipeline();
+        for key in keys {
+            let _: () = pipeline
+                .hget(self.stream.meta_key(key), constants::META_STATUS_FIELD)
+                .await?;
+        }
+        let statuses: Vec<Option<RedisStr>> = pipeline.all().await?;
+
+        let inactive_keys = keys
+            .iter()
+            .zip(statuses)
+            .filter(|(_, status)| {
+                status
+                    .as_ref()
+                    .is_none_or(|s| **s != constants::StreamStatus::Active)
+            })
+            .map(|(key, _)| (*key).to_owned())
+            .collect();
+
+        Ok(inactive_keys)
+    }
+
+    /// Add the token ID to the denylist until the token expires, and notify live consumers
+    pub async fn revoke_token(&self, token_id: &str, ttl: i64) -> FredResult<()> {
+        let revocation = Revocation::Token(token_id.to_owned());
+
+        let pipeline = self.client.pipeline();
+        let _: () = pipeline
+            .set(
+                self.stream.revoked_token_key(token_id),
+                1,
+                Some(Expiration::EX(ttl)),
+                None,
+                false,
+            )
+            .await?;
+        let _: () = pipeline
+            .publish(self.stream.revocation_channel(), revocation.to_message())
+            .await?;
+
+        pipeline.all().await
+    }
+
+    /// Revoke all tokens for the stream key/prefix that were issued up to the given time
+    /// (unix milliseconds), and notify live consumers. The revocation is kept for the given
+    /// TTL, which should outlast any of the revoked tokens.
+    ///
+    /// All key revocations are kept in one sorted set, so they can be checked with a single
+    /// command. As every revocation is kept for the same TTL, the ones older than the TTL are
+    /// removed on each revocation, and the set expires with its latest revocation.
+    pub async fn revoke_key_tokens(&self, key: &str, revoked_at: i64, ttl: i64) -> FredResult<()> {
+        let revocation = Revocation::Key(key.to_owned());
+        let revoked_keys_key = self.stream.revoked_keys_key();
+        let expired_before = revoked_at - ttl * 1000;
+
+        let pipeline = self.client.pipeline();
+        let _: () = pipeline
+            .zadd(
+                &revoked_keys_key,
+                None,
+                Some(Ordering::GreaterThan),
+                false,
+                false,
+                (revoked_at as f64, key),
+            )
+            .await?;
+        let _: () = pipeline
+            .zremrangebyscore(&revoked_keys_key, "-inf", expired_before as f64)
+            .await?;
+        let _: () = pipeline.expire(&revoked_keys_key, ttl, None).await?;
+        let _: () = pipeline
+            .publish(self.stream.revocation_channel(), revocation.to_message())
+            .await?;
+
+        pipeline.all().await
+    }
+
+    /// Check if the token with the given ID and issue time (unix milliseconds) has been revoked,
+    /// either directly or for any of the given stream keys/prefixes
+    pub async fn is_token_revoked(
+        &self,
+        token_id: &str,
+        issued_at: i64,
+        keys: &[&str],
+    ) -> FredResult<bool> {
+        // The keys are in different hash slots, so get them via pipeline
+        let pipeline = self.client.pipeline();
+        let _: () = pipeline
+            .get(self.stream.revoked_token_key(token_id))
+            .await?;
+        let _: () = pipeline
+            .zmscore(self.stream.revoked_keys_key(), keys.to_vec())
+            .await?;
+        let (token_revoked, revoked_at): (Option<i64>, Vec<Option<f64>>) = pipeline.all().await?;
+
+        let key_revoked = revoked_at
+            .into_iter()
+            .flatten()
+            .any(|revoked_at| issued_at as f64 <= revoked_at);
+
+        Ok(token_revoked.is_some() || key_revoked)
+    }
+
+    /// Get the ID, metadata, length, and TTL of all streams with the given status matching
+    /// the given pattern and attribute values.
+    pub async fn scan_streams(
+        &self,
+        pattern: Option<&str>,
+        status: constants::StreamStatus,
+        attributes: &[(&str, &str)],
+    ) -> FredResult<Vec<(String, StreamMeta, u64, i64)>> {
         use fred::types::scan::{ScanType, Scanner};
         const PAGE_COUNT: u32 = 50;
 
         // Scan for metadata keys matching the pattern
         let meta_pattern = self.stream.meta_key(pattern.unwrap_or("*"));
         let mut stream_keys: Vec<(String, String)> = Vec::with_capacity(PAGE_COUNT as usize);
-        let mut scan_stream =
-            self.client
-                .scan(meta_pattern, Some(PAGE_COUNT), Some(ScanType::Hash));
+        let mut scan_stream = match self.client.is_clustered() {
+            true => self
+                .client
+                .scan_cluster(meta_pattern, Some(PAGE_COUNT), Some(ScanType::Hash))
+                .boxed(),
+            false => self
+                .client
+                .scan(meta_pattern, Some(PAGE_COUNT), Some(ScanType::Hash))
+                .boxed(),
+        };
         while let Some(page) = scan_stream.next().await {
             let meta_keys = page?.take_results().unwrap_or_default();
             stream_keys.extend(meta_keys.into_iter().filter_map(|meta_key| {
                 let meta_key_str = meta_key.into_string()?;
-                let key = &meta_key_str[self.stream.meta_key_prefix_len()..];
-                Some((key.to_owned(), meta_key_str))
+                let key = self.stream.key_from_meta_key(&meta_key_str)?.to_owned();
+                Some((key, meta_key_str))
             }));
         }
 
-        // Get status, length, and TTL of each stream
+        // Get metadata, length, and TTL of each stream
         let pipeline = self.client.pipeline();
         for (key, meta_key) in &stream_keys {
             let stream_key = self.stream.stream_key(key);
-            let _: () = pipeline
-                .hget(meta_key, constants::META_STATUS_FIELD)
-                .await?;
+            let _: () = pipeline.hgetall(meta_key).await?;
             let _: () = pipeline.xlen(&stream_key).await?;
             let _: () = pipeline.ttl(&stream_key).await?;
         }
         let stream_info: Vec<Value> = pipeline.all().await?;
 
-        // Filter active streams
-        let active_streams = stream_keys
+        // Filter streams with the status and matching attributes
+        let streams = stream_keys
             .into_iter()
             .zip(stream_info.into_iter().tuples())
-            .filter_map(|((key, _), (status, len, ttl))| {
-                if *status.as_str()? == constants::StreamStatus::Active {
-                    Some((key.to_owned(), len.as_u64()?, ttl.as_i64()?))
+            .filter_map(|((key, _), (meta, len, ttl))| {
+                let meta: StreamMeta = meta.convert().ok()?;
+                if meta.has_status(status) && meta.matches_attributes(attributes.iter().copied()) {
+                    let ttl = self.stream_ttl(ttl.as_i64()?);
+                    Some((key, meta, len.as_u64()?, ttl))
                 } else {
                     None
                 }
             })
             .collect();
 
-        Ok(active_streams)
+        Ok(streams)
     }
 }
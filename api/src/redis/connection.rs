@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use fred::{interfaces::ClientLike, prelude::StreamsInterface};
+use futures::{FutureExt, future::BoxFuture};
+
+use crate::{
+    redis::{
+        AddEvent, ExclusiveClient, StreamService,
+        scripts::RedisScripts,
+        types::{RedisStr, StreamEntry},
+    },
+    storage::{StorageResult, StreamConnection, WriteOutcome},
+};
+
+/// A Redis client with an exclusive connection, for long-running read and write
+/// operations (e.g. blocking reads for streaming events, or ingesting events)
+pub struct RedisConnection {
+    client: ExclusiveClient,
+    stream: StreamService,
+    max_len: u32,
+    dedup_window: u32,
+    expired_retention: u32,
+    batch_size: u32,
+}
+
+impl RedisConnection {
+    pub fn new(
+        client: ExclusiveClient,
+        stream: StreamService,
+        max_len: u32,
+        dedup_window: u32,
+        expired_retention: u32,
+        batch_size: u32,
+    ) -> Self {
+        Self {
+            client,
+            stream,
+            max_len,
+            dedup_window,
+            expired_retention,
+            batch_size,
+        }
+    }
+
+    /// Blocking `XREAD` command reading a batch of entries from each of the given streams.
+    /// Returns the entries in order, along with the index of their stream.
+    async fn xread(
+        &self,
+        streams: &[(&str, &str)],
+        block: u64,
+    ) -> StorageResult<Vec<(usize, StreamEntry)>> {
+        let command_timeout = block + 5_000; // 5 second grace period for command timeout
+        let stream_keys: Vec<String> = streams
+            .iter()
+            .map(|(key, _)| self.stream.stream_key(key))
+            .collect();
+        let start_event_ids: Vec<&str> = streams.iter().map(|(_, id)| *id).collect();
+        let entries = self
+            .client
+            .with_options(&fred::prelude::Options {
+                timeout: Some(Duration::from_millis(command_timeout)),
+                ..Default::default()
+            })
+            .xread::<Option<Vec<(RedisStr, Vec<StreamEntry>)>>, _, _>(
+                Some(self.batch_size.into()),
+                Some(block),
+                stream_keys.clone(),
+                start_event_ids,
+            )
+            .await?
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(stream_key, entries)| {
+                let idx = stream_keys.iter().position(|k| **k == *stream_key)?;
+                Some(entries.into_iter().map(move |entry| (idx, entry)))
+            })
+            .flatten()
+            .collect();
+
+        Ok(entries)
+    }
+}
+
+impl StreamConnection for RedisConnection {
+    fn read<'a>(
+        &'a self,
+        streams: &'a [(&'a str, &'a str)],
+        block_ms: u64,
+    ) -> BoxFuture<'a, StorageResult<Vec<(usize, StreamEntry)>>> {
+        self.xread(streams, block_ms).boxed()
+    }
+
+    fn write_events<'a>(
+        &'a self,
+        key: &'a str,
+        events: &'a [AddEvent],
+        expected_last_id: Option<&'a str>,
+        event_schema: Option<&'a str>,
+    ) -> BoxFuture<'a, StorageResult<WriteOutcome>> {
+        async move {
+            let stream_key = self.stream.stream_key(key);
+            let meta_key = self.stream.meta_key(key);
+            let dedup_key = self.stream.dedup_key(key);
+            let outcome = RedisScripts::write_events(
+                &self.client,
+                (&stream_key, &meta_key, &dedup_key),
+                self.max_len,
+                self.dedup_window,
+                self.expired_retention,
+                events,
+                expected_last_id,
+                event_schema,
+            )
+            .await?;
+
+            Ok(outcome)
+        }
+        .boxed()
+    }
+}
@@ -1,32 +1,76 @@
 //! Shared constants for Redis streams
 
 use schemars::JsonSchema;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 /// Key of the event field in the Redis stream entry
 pub const EVENT_KEY: &str = "event";
 /// Key of the data field in the Redis stream entry
 pub const DATA_KEY: &str = "data";
+/// Key of the content type field in the Redis stream entry (omitted for plain text data)
+pub const CONTENT_TYPE_KEY: &str = "content_type";
+/// Content type of JSON data
+pub const JSON_CONTENT_TYPE: &str = "json";
+/// Content type of binary data
+pub const BINARY_CONTENT_TYPE: &str = "binary";
 
 pub const START: &str = "start";
 pub const CANCEL: &str = "cancel";
 pub const END: &str = "end";
+pub const EXPIRED: &str = "expired";
+pub const TIMED_OUT: &str = "timed_out";
+/// Event written when a consumer requests cancellation of the stream
+pub const CANCEL_REQUESTED: &str = "cancel_requested";
 pub const ERROR: &str = "error";
 
-pub const CANCEL_ENTRY: (&str, &str) = (EVENT_KEY, CANCEL);
-pub const END_ENTRY: (&str, &str) = (EVENT_KEY, END);
-pub const ERROR_ENTRY: (&str, &str) = (EVENT_KEY, ERROR);
-
 pub const STREAM_PREFIX: &str = "stream:";
 pub const META_PREFIX: &str = "meta:";
+/// Prefix for the sorted sets of recent idempotency keys written to each stream
+pub const DEDUP_PREFIX: &str = "dedup:";
+/// Prefix for the temporary streams written while compacting each stream
+pub const COMPACT_PREFIX: &str = "compact:";
+/// Sorted set of the active stream keys, scored by when they expire (unix ms)
+pub const EXPIRIES_KEY: &str = "expiries";
 pub const META_STATUS_FIELD: &str = "status";
+pub const META_TTL_FIELD: &str = "ttl";
+pub const META_MAX_LEN_FIELD: &str = "max_len";
+/// Metadata field set to `1` for streams with sequential event IDs
+pub const META_SEQUENTIAL_FIELD: &str = "sequential";
+/// Metadata field set to `1` for streams whose TTL is refreshed on every write
+pub const META_SLIDING_FIELD: &str = "sliding";
+/// Metadata field with the event schema of the stream (as JSON)
+pub const META_SCHEMA_FIELD: &str = "schema";
+/// Metadata field with the reason and final data of a finished stream (as JSON)
+pub const META_FINISH_FIELD: &str = "finish";
+/// Metadata field set to `1` once a consumer has requested cancellation of the stream
+pub const META_CANCEL_REQUESTED_FIELD: &str = "cancel_requested";
+/// Metadata field set to `1` for streams that are cancelled right away when a consumer
+/// requests cancellation
+pub const META_CANCEL_ON_REQUEST_FIELD: &str = "cancel_on_request";
+/// Metadata field with the inactivity timeout of the stream in seconds
+pub const META_INACTIVITY_TIMEOUT_FIELD: &str = "inactivity_timeout";
+/// Metadata field with the time of the last write to the stream (unix ms)
+pub const META_LAST_WRITE_FIELD: &str = "last_write";
+/// Prefix for the custom attribute fields in the metadata hash
+pub const META_ATTR_PREFIX: &str = "attr:";
+/// Prefix for the denylist keys of individually revoked tokens
+pub const REVOKED_TOKEN_PREFIX: &str = "revoked:token:";
+/// Sorted set of the stream keys/prefixes whose tokens were revoked, scored by when they were
+/// revoked (unix ms)
+pub const REVOKED_KEYS_KEY: &str = "revoked:keys";
+/// Pub/sub channel for notifying all server instances of token revocations
+pub const REVOCATION_CHANNEL: &str = "revocations";
 
-#[derive(Debug, PartialEq, Serialize, JsonSchema)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
 pub enum StreamStatus {
     Active,
     Cancelled,
     Ended,
+    /// The stream's TTL passed before it was ended or cancelled
+    Expired,
+    /// The stream had no writes for its inactivity timeout (e.g. the producer crashed)
+    TimedOut,
 }
 impl StreamStatus {
     pub const fn as_str(&self) -> &'static str {
@@ -34,6 +78,31 @@ impl StreamStatus {
             StreamStatus::Active => "active",
             StreamStatus::Cancelled => "cancelled",
             StreamStatus::Ended => "ended",
+            StreamStatus::Expired => "expired",
+            StreamStatus::TimedOut => "timed_out",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        [
+            Self::Active,
+            Self::Cancelled,
+            Self::Ended,
+            Self::Expired,
+            Self::TimedOut,
+        ]
+        .into_iter()
+        .find(|s| s.as_str() == status)
+    }
+
+    /// Name of the stream event written when the stream enters this status
+    pub const fn status_event(&self) -> &'static str {
+        match self {
+            StreamStatus::Active => START,
+            StreamStatus::Cancelled => CANCEL,
+            StreamStatus::Ended => END,
+            StreamStatus::Expired => EXPIRED,
+            StreamStatus::TimedOut => TIMED_OUT,
         }
     }
 }
@@ -1,18 +1,22 @@
 mod client;
-mod constants;
-mod error;
+mod connection;
+pub mod constants;
 mod exclusive_client;
-mod reader;
+mod revocation;
 mod scripts;
+mod storage;
 mod stream;
 mod types;
-mod util;
-mod writer;
+pub mod util;
 
 pub use client::RedisClient;
+pub use connection::RedisConnection;
 pub use constants::StreamStatus;
 pub use exclusive_client::{ExclusiveClient, ExclusiveClientManager};
-pub use reader::RedisReader;
+pub use revocation::{Revocation, RevocationListener};
+pub use storage::RedisStorage;
 pub use stream::StreamService;
-pub use types::{AddEvent, StreamEvent};
-pub use writer::RedisWriter;
+pub use types::{
+    AddEvent, EventData, FinishDetails, MultiCursor, RedisStr, SseEvent, StreamEntry, StreamEvent,
+    StreamMeta, StreamSettings, WsMessage,
+};
@@ -0,0 +1,185 @@
+//! Live notifications of client token revocations across server instances
+
+use fred::{clients::SubscriberClient, prelude::*};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{auth::revocation_covers, redis::constants};
+
+/// Capacity of the in-process channel for forwarding revocations to live consumers
+const CHANNEL_CAPACITY: usize = 256;
+
+const TOKEN_PREFIX: &str = "token:";
+const KEY_PREFIX: &str = "key:";
+
+/// A client token revocation, published to all server instances via Redis pub/sub
+#[derive(Debug, Clone, PartialEq)]
+pub enum Revocation {
+    /// A single token was revoked (by token ID)
+    Token(String),
+    /// All tokens for a stream key or key prefix were revoked
+    Key(String),
+}
+
+impl Revocation {
+    /// Get the pub/sub channel for revocations with the given key prefix
+    pub fn channel(key_prefix: &str) -> String {
+        [key_prefix, constants::REVOCATION_CHANNEL].concat()
+    }
+
+    /// Serialize the revocation into a pub/sub message
+    pub fn to_message(&self) -> String {
+        match self {
+            Revocation::Token(token_id) => [TOKEN_PREFIX, token_id].concat(),
+            Revocation::Key(key) => [KEY_PREFIX, key].concat(),
+        }
+    }
+
+    /// Parse the revocation from a pub/sub message
+    pub fn from_message(message: &str) -> Option<Self> {
+        if let Some(token_id) = message.strip_prefix(TOKEN_PREFIX) {
+            Some(Revocation::Token(token_id.to_owned()))
+        } else {
+            let key = message.strip_prefix(KEY_PREFIX)?;
+            Some(Revocation::Key(key.to_owned()))
+        }
+    }
+
+    /// Check if this revocation applies to a consumer of the stream `keys`, using a token
+    /// with the given ID that was issued for `token_key`
+    pub fn applies_to(&self, token_id: &str, token_key: &str, keys: &[String]) -> bool {
+        match self {
+            Revocation::Token(id) => id == token_id,
+            Revocation::Key(revoked_key) => std::iter::once(token_key)
+                .chain(keys.iter().map(String::as_str))
+                .any(|key| revocation_covers(revoked_key, key)),
+        }
+    }
+}
+
+/// Subscribes to token revocations in Redis, and forwards them to the live consumers
+/// on this server instance
+pub struct RevocationListener {
+    /// Redis subscriber client (not used with in-memory storage)
+    subscriber: Option<SubscriberClient>,
+    sender: broadcast::Sender<Revocation>,
+}
+
+impl RevocationListener {
+    /// Create a listener for revocations sent only within this server instance
+    /// (i.e. with in-memory storage)
+    pub fn local() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            subscriber: None,
+            sender,
+        }
+    }
+
+    /// Get the sender for forwarding revocations to the live consumers
+    pub fn sender(&self) -> broadcast::Sender<Revocation> {
+        self.sender.clone()
+    }
+
+    /// Connect the subscriber client and start listening for revocations
+    pub async fn start(subscriber: SubscriberClient, key_prefix: &str) -> FredResult<Self> {
+        subscriber.init().await?;
+        subscriber.manage_subscriptions();
+        subscriber
+            .subscribe(Revocation::channel(key_prefix))
+            .await?;
+
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let mut message_rx = subscriber.message_rx();
+        let revocation_tx = sender.clone();
+        tokio::spawn(async move {
+            loop {
+                match message_rx.recv().await {
+                    Ok(message) => {
+                        let revocation = message
+                            .value
+                            .as_str()
+                            .and_then(|message| Revocation::from_message(&message));
+                        if let Some(revocation) = revocation {
+                            let _ = revocation_tx.send(revocation);
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        tracing::warn!("Missed {count} token revocation messages");
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Ok(Self {
+            subscriber: Some(subscriber),
+            sender,
+        })
+    }
+
+    /// Returns a future that resolves once the token with the given ID, issued for
+    /// `token_key`, is revoked for any of the stream `keys`
+    pub fn revoked(
+        &self,
+        token_id: String,
+        token_key: String,
+        keys: Vec<String>,
+    ) -> impl Future<Output = ()> + use<> {
+        let mut revocation_rx = self.sender.subscribe();
+
+        async move {
+            loop {
+                match revocation_rx.recv().await {
+                    Ok(revocation) if revocation.applies_to(&token_id, &token_key, &keys) => break,
+                    Ok(_) => continue,
+                    // Revocations may have been missed, so disconnect and let the client
+                    // reconnect with a re-validated token
+                    Err(RecvError::Lagged(_)) => break,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+
+    /// Unsubscribe and disconnect the subscriber client
+    pub async fn shutdown(&self) {
+        if let Some(subscriber) = &self.subscriber {
+            let _ = subscriber.quit().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_round_trip() {
+        for revocation in [
+            Revocation::Token("abc123".into()),
+            Revocation::Key("user:42:*".into()),
+        ] {
+            let message = revocation.to_message();
+            assert_eq!(Revocation::from_message(&message), Some(revocation));
+        }
+        assert_eq!(Revocation::from_message("other"), None);
+    }
+
+    #[test]
+    fn applies_to_token_or_key() {
+        let keys = ["user:42:chat".to_owned(), "user:42:other".to_owned()];
+        let by_token = Revocation::Token("abc123".into());
+        assert!(by_token.applies_to("abc123", "user:42:*", &keys));
+        assert!(!by_token.applies_to("def456", "user:42:*", &keys));
+
+        let by_key = Revocation::Key("user:42:chat".into());
+        assert!(by_key.applies_to("abc123", "user:42:*", &keys));
+        assert!(by_key.applies_to("abc123", "user:42:chat", &keys[..1]));
+        assert!(!by_key.applies_to("abc123", "user:42:*", &keys[1..]));
+
+        let by_prefix = Revocation::Key("user:42:*".into());
+        assert!(by_prefix.applies_to("abc123", "user:42:chat", &keys[..1]));
+        assert!(by_prefix.applies_to("abc123", "user:*", &keys));
+        assert!(!by_prefix.applies_to("abc123", "user:43:chat", &[]));
+    }
+}
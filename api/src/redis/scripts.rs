@@ -1,70 +1,233 @@
 use std::sync::LazyLock;
 
-use fred::{clients::Client, prelude::FredResult, types::scripts::Script};
+use fred::{
+    clients::Client,
+    prelude::{FredResult, Value},
+    types::scripts::Script,
+};
 
-use crate::redis::{AddEvent, StreamStatus, constants, types::RedisStr};
+use crate::{
+    redis::{
+        AddEvent, StreamStatus, constants,
+        types::{FinishDetails, RedisStr, StreamSettings},
+        util,
+    },
+    storage::{CancelRequestOutcome, WriteOutcome, WriteResult},
+};
+
+/// Outcome of expiring a stream
+pub(super) enum ExpireOutcome {
+    /// The stream was expired or timed out by writing its terminal event
+    Finished(StreamStatus),
+    /// The stream's TTL was extended or it was written to, and it expires or times out in
+    /// the given number of milliseconds
+    Pending(i64),
+    /// The stream is not active
+    NotActive,
+}
 
 /// Lua scripts for atomic Redis stream mutations. The scripts return
 /// `nil` (i.e. `None`) when the stream state does not allow the mutation.
 pub(super) struct RedisScripts;
 
 impl RedisScripts {
-    /// Start and activate a stream.
+    /// Start and activate a stream, storing the settings, attributes, and event schema in
+    /// the metadata. The keys expire after the stream's TTL plus `expired_retention`
+    /// seconds, so that an expired stream can be kept with its `expired` event.
     ///
-    /// Returns the Redis stream ID for the start event. Returns `None` if the
-    /// stream is already active. If an inactive stream exists at the same key,
-    /// the script deletes the old stream and metadata before creating the new
-    /// stream.
+    /// Returns the Redis stream ID for the start event (`0-1` with sequential IDs).
+    /// Returns `None` if the stream is already active. If an inactive stream exists at the same key,
+    /// the script deletes the old stream, metadata, and idempotency keys before
+    /// creating the new stream.
     pub(super) async fn start_stream(
         client: &Client,
         stream_key: &str,
         meta_key: &str,
-        ttl: u32,
+        dedup_key: &str,
+        settings: &StreamSettings,
+        expired_retention: u32,
     ) -> FredResult<Option<RedisStr>> {
-        let mut ttl_buffer = itoa::Buffer::new();
-        let args = [
+        let (mut ttl_buffer, mut max_len_buffer) = (itoa::Buffer::new(), itoa::Buffer::new());
+        let mut key_ttl_buffer = itoa::Buffer::new();
+        let key_ttl = u64::from(settings.ttl) + u64::from(expired_retention);
+        let mut fields: Vec<_> = settings
+            .attributes
+            .iter()
+            .map(|(name, value)| ([constants::META_ATTR_PREFIX, name].concat(), value.as_str()))
+            .collect();
+        if let Some(event_schema) = &settings.event_schema {
+            fields.push((constants::META_SCHEMA_FIELD.to_owned(), event_schema));
+        }
+        if settings.sliding_ttl {
+            fields.push((constants::META_SLIDING_FIELD.to_owned(), "1"));
+        }
+        if settings.cancel_on_request {
+            fields.push((constants::META_CANCEL_ON_REQUEST_FIELD.to_owned(), "1"));
+        }
+        let mut timeout_buffer = itoa::Buffer::new();
+        if let Some(timeout) = settings.inactivity_timeout {
+            let timeout = timeout_buffer.format(timeout);
+            fields.push((constants::META_INACTIVITY_TIMEOUT_FIELD.to_owned(), timeout));
+        }
+        let mut args = vec![
             constants::META_STATUS_FIELD,
             constants::StreamStatus::Active.as_str(),
-            ttl_buffer.format(ttl),
+            ttl_buffer.format(settings.ttl),
             constants::EVENT_KEY,
             constants::START,
+            constants::META_TTL_FIELD,
+            constants::META_MAX_LEN_FIELD,
+            max_len_buffer.format(settings.max_len),
+            constants::META_SEQUENTIAL_FIELD,
+            if settings.sequential_ids { "1" } else { "0" },
+            key_ttl_buffer.format(key_ttl),
+            constants::META_LAST_WRITE_FIELD,
         ];
+        args.extend(
+            fields
+                .iter()
+                .flat_map(|(field, value)| [field.as_str(), *value]),
+        );
 
         START_STREAM_SCRIPT
-            .evalsha_with_reload(client, (stream_key, meta_key), args)
+            .evalsha_with_reload(client, (stream_key, meta_key, dedup_key), args)
             .await
     }
 
-    /// Write a batch of events to an active stream.
-    //
-    /// Returns the Redis stream IDs for all written events. Returns `None` if
-    /// the stream is not active, without writing any events.
+    /// Write a batch of events to an active stream. The stream is trimmed to the
+    /// max length stored in its metadata, or to `default_max_len` if none is stored.
+    /// Events with an idempotency key that was written within the last `dedup_window`
+    /// seconds (or earlier in the batch) are skipped.
+    ///
+    /// Events are added with their explicit ID if given, or else the next sequence number
+    /// for streams with sequential IDs, or the current time. No events are written if the
+    /// stream is not active, if its event schema isn't `event_schema`, if its last event ID
+    /// doesn't match `expected_last_id` (if given), or if an explicit ID isn't greater than
+    /// the previous event ID. An empty batch only checks the stream's status and event schema.
+    ///
+    /// For streams with a sliding TTL, the keys' expiry is refreshed to the stream's TTL
+    /// (plus `expired_retention` seconds) after writing.
     pub(super) async fn write_events(
         client: &Client,
-        stream_key: &str,
-        meta_key: &str,
-        max_len: u32,
-        events: Vec<AddEvent>,
-    ) -> FredResult<Option<Vec<RedisStr>>> {
-        let mut max_len_buffer = itoa::Buffer::new();
-        let mut args = vec![
+        keys: (&str, &str, &str),
+        default_max_len: u32,
+        dedup_window: u32,
+        expired_retention: u32,
+        events: &[AddEvent],
+        expected_last_id: Option<&str>,
+        event_schema: Option<&str>,
+    ) -> FredResult<WriteOutcome> {
+        let (mut max_len_buffer, mut window_buffer) = (itoa::Buffer::new(), itoa::Buffer::new());
+        let mut retention_buffer = itoa::Buffer::new();
+        let mut args: Vec<Value> = [
             constants::META_STATUS_FIELD,
             constants::StreamStatus::Active.as_str(),
-            max_len_buffer.format(max_len),
+            max_len_buffer.format(default_max_len),
             constants::EVENT_KEY,
             constants::DATA_KEY,
+            constants::META_MAX_LEN_FIELD,
+            constants::CONTENT_TYPE_KEY,
+            window_buffer.format(u64::from(dedup_window) * 1000),
+            expected_last_id.unwrap_or_default(),
+            constants::META_SEQUENTIAL_FIELD,
+            constants::META_SLIDING_FIELD,
+            constants::META_TTL_FIELD,
+            retention_buffer.format(expired_retention),
+            constants::META_INACTIVITY_TIMEOUT_FIELD,
+            constants::META_LAST_WRITE_FIELD,
+            constants::META_SCHEMA_FIELD,
+            event_schema.unwrap_or_default(),
+        ]
+        .into_iter()
+        .map(Value::from)
+        .collect();
+        for event in events {
+            args.push(event.event.as_str().into());
+            match &event.data {
+                Some(data) => args.extend([
+                    "1".into(),
+                    data.to_bytes().into(),
+                    data.content_type().unwrap_or("").into(),
+                ]),
+                None => args.extend(["0".into(), "".into(), "".into()]),
+            }
+            args.push(event.idempotency_key.as_deref().unwrap_or_default().into());
+            args.push(
+                event
+                    .id
+                    .map(util::format_entry_id)
+                    .unwrap_or_default()
+                    .into(),
+            );
+        }
+
+        let reply: Option<Vec<RedisStr>> = WRITE_EVENTS_SCRIPT
+            .evalsha_with_reload(client, keys, args)
+            .await?;
+        let Some(reply) = reply else {
+            return Ok(WriteOutcome::NotActive);
+        };
+        let mut reply = reply.into_iter();
+        let outcome = match reply.next().as_deref() {
+            Some("ok") => WriteOutcome::Written(
+                reply
+                    .map(|id| match id.is_empty() {
+                        true => WriteResult::Duplicate,
+                        false => WriteResult::Added(id),
+                    })
+                    .collect(),
+            ),
+            Some("schema") => WriteOutcome::SchemaChanged(
+                reply
+                    .next()
+                    .filter(|schema| !schema.is_empty())
+                    .map(|schema| schema.to_string()),
+            ),
+            Some("conflict") => WriteOutcome::Conflict(reply.next().unwrap_or_default()),
+            Some("stale") => WriteOutcome::StaleId(reply.next().unwrap_or_default()),
+            _ => {
+                return Err(fred::error::Error::new(
+                    fred::error::ErrorKind::Parse,
+                    "unexpected write events reply",
+                ));
+            }
+        };
+
+        Ok(outcome)
+    }
+
+    /// Extend the TTL of an active stream, so that it expires `ttl` seconds from now (plus
+    /// `expired_retention` seconds for its keys). The given TTL is stored as the stream's
+    /// new TTL setting. If no TTL is given, the stream's current TTL setting is used.
+    ///
+    /// Returns the applied TTL in seconds. Returns `None` if the stream is not active.
+    pub(super) async fn touch_stream(
+        client: &Client,
+        stream_key: &str,
+        meta_key: &str,
+        ttl: Option<u32>,
+        expired_retention: u32,
+    ) -> FredResult<Option<u32>> {
+        let (mut ttl_buffer, mut retention_buffer) = (itoa::Buffer::new(), itoa::Buffer::new());
+        let args = [
+            constants::META_STATUS_FIELD,
+            constants::StreamStatus::Active.as_str(),
+            constants::META_TTL_FIELD,
+            match ttl {
+                Some(ttl) => ttl_buffer.format(ttl),
+                None => "",
+            },
+            retention_buffer.format(expired_retention),
         ];
-        args.extend(events.iter().flat_map(|ev| match ev.data.as_ref() {
-            Some(data) => [&ev.event, "1", data],
-            None => [&ev.event, "0", ""],
-        }));
 
-        WRITE_EVENTS_SCRIPT
+        TOUCH_STREAM_SCRIPT
             .evalsha_with_reload(client, (stream_key, meta_key), args)
             .await
     }
 
-    /// Write a terminal event and mark the stream inactive.
+    /// Write the terminal event for the final status and mark the stream inactive. With
+    /// sequential IDs, the terminal event gets the next sequence number. The finish details
+    /// (if any) are written as the JSON data of the terminal event, and stored in the metadata.
     ///
     /// Returns the Redis stream ID for the terminal event. Returns `None` if
     /// the stream is not active, without appending a terminal event.
@@ -73,20 +236,190 @@ impl RedisScripts {
         stream_key: &str,
         meta_key: &str,
         status: StreamStatus,
-        event: &str,
+        details: Option<&FinishDetails>,
     ) -> FredResult<Option<RedisStr>> {
-        let args = [
+        let details = details.and_then(FinishDetails::to_json);
+        let args = finish_args(status, details.as_deref().unwrap_or_default());
+
+        FINISH_STREAM_SCRIPT
+            .evalsha_with_reload(client, (stream_key, meta_key), args)
+            .await
+    }
+
+    /// Record a consumer's request to cancel an active stream: write the `cancel_requested`
+    /// event and set the flag in the metadata, unless cancellation was already requested. For
+    /// streams that are cancelled on request, also write the `cancel` terminal event with the
+    /// given finish details, and mark the stream cancelled.
+    ///
+    /// Returns `None` if the stream is not active.
+    pub(super) async fn request_cancel(
+        client: &Client,
+        stream_key: &str,
+        meta_key: &str,
+        details: &FinishDetails,
+    ) -> FredResult<Option<CancelRequestOutcome>> {
+        let details = details.to_json();
+        let mut args = finish_args(
+            StreamStatus::Cancelled,
+            details.as_deref().unwrap_or_default(),
+        )
+        .to_vec();
+        args.extend([
+            constants::META_CANCEL_REQUESTED_FIELD,
+            constants::META_CANCEL_ON_REQUEST_FIELD,
+            constants::CANCEL_REQUESTED,
+        ]);
+
+        let reply: Option<RedisStr> = REQUEST_CANCEL_SCRIPT
+            .evalsha_with_reload(client, (stream_key, meta_key), args)
+            .await?;
+        let outcome = match reply.as_deref() {
+            None => None,
+            Some("recorded") => Some(CancelRequestOutcome::Recorded),
+            Some("already") => Some(CancelRequestOutcome::AlreadyRequested),
+            Some("cancelled") => Some(CancelRequestOutcome::Cancelled),
+            Some(_) => {
+                return Err(fred::error::Error::new(
+                    fred::error::ErrorKind::Parse,
+                    "unexpected cancel request reply",
+                ));
+            }
+        };
+
+        Ok(outcome)
+    }
+
+    /// Expire an active stream whose TTL has passed (i.e. only the expired retention is left
+    /// of its keys' TTL): write the `expired` terminal event and mark the stream expired.
+    /// A stream that had no writes for its inactivity timeout is timed out instead.
+    pub(super) async fn expire_stream(
+        client: &Client,
+        stream_key: &str,
+        meta_key: &str,
+        expired_retention: u32,
+    ) -> FredResult<ExpireOutcome> {
+        let mut retention_buffer = itoa::Buffer::new();
+        let mut args = finish_args(StreamStatus::Expired, "").to_vec();
+        args.extend([
+            retention_buffer.format(u64::from(expired_retention) * 1000),
+            constants::META_INACTIVITY_TIMEOUT_FIELD,
+            constants::META_LAST_WRITE_FIELD,
+            constants::StreamStatus::TimedOut.as_str(),
+            constants::StreamStatus::TimedOut.status_event(),
+        ]);
+
+        let reply: Option<(RedisStr, RedisStr)> = EXPIRE_STREAM_SCRIPT
+            .evalsha_with_reload(client, (stream_key, meta_key), args)
+            .await?;
+        let outcome = match reply {
+            None => ExpireOutcome::NotActive,
+            Some((kind, value)) => match (&*kind, StreamStatus::parse(&kind)) {
+                ("pending", _) => ExpireOutcome::Pending(value.parse().unwrap_or_default()),
+                (_, Some(status)) => ExpireOutcome::Finished(status),
+                _ => {
+                    return Err(fred::error::Error::new(
+                        fred::error::ErrorKind::Parse,
+                        "unexpected expire stream reply",
+                    ));
+                }
+            },
+        };
+
+        Ok(outcome)
+    }
+
+    /// Remove the given stream keys from the sorted set of expirations, unless they were
+    /// rescheduled after `now` (unix ms) in the meantime (e.g. a new stream at the same key)
+    pub(super) async fn remove_expiries(
+        client: &Client,
+        expiries_key: &str,
+        now: i64,
+        keys: &[&str],
+    ) -> FredResult<()> {
+        let mut args = vec![now.to_string()];
+        args.extend(keys.iter().map(|key| (*key).to_owned()));
+
+        REMOVE_EXPIRIES_SCRIPT
+            .evalsha_with_reload(client, vec![expiries_key], args)
+            .await
+    }
+
+    /// Compact the next chunk of up to `count` entries of a finished stream, after the entry
+    /// with the `cursor` ID (or from the start if empty). Each run of consecutive events with
+    /// one of the given names is merged into a single event with the concatenated data and the
+    /// ID of the run's last event, and written to the temporary compacted stream. Events with
+    /// JSON data, or with a different content type, are not merged.
+    ///
+    /// Returns the number of removed entries, the number of read entries, and the ID of the
+    /// last read entry. Returns `None` if the stream doesn't exist or is still active.
+    pub(super) async fn compact_stream_chunk(
+        client: &Client,
+        stream_key: &str,
+        meta_key: &str,
+        compact_key: &str,
+        cursor: &str,
+        count: u32,
+        events: &[String],
+    ) -> FredResult<Option<(u64, u64, String)>> {
+        let mut count_buffer = itoa::Buffer::new();
+        let mut args = vec![
             constants::META_STATUS_FIELD,
             constants::StreamStatus::Active.as_str(),
-            status.as_str(),
             constants::EVENT_KEY,
-            event,
+            constants::DATA_KEY,
+            constants::CONTENT_TYPE_KEY,
+            constants::JSON_CONTENT_TYPE,
+            cursor,
+            count_buffer.format(count),
         ];
+        args.extend(events.iter().map(String::as_str));
 
-        FINISH_STREAM_SCRIPT
-            .evalsha_with_reload(client, (stream_key, meta_key), args)
+        COMPACT_STREAM_CHUNK_SCRIPT
+            .evalsha_with_reload(client, (stream_key, meta_key, compact_key), args)
             .await
     }
+
+    /// Replace a finished stream with its compacted stream, if the stream still ends with
+    /// the entry with `last_id` (i.e. it wasn't restarted while compacting). Otherwise the
+    /// compacted stream is deleted.
+    ///
+    /// Returns whether the stream was replaced.
+    pub(super) async fn replace_compacted_stream(
+        client: &Client,
+        stream_key: &str,
+        meta_key: &str,
+        compact_key: &str,
+        last_id: &str,
+    ) -> FredResult<bool> {
+        let args = [
+            constants::META_STATUS_FIELD,
+            constants::StreamStatus::Active.as_str(),
+            last_id,
+        ];
+        let replaced: Option<i64> = REPLACE_COMPACTED_STREAM_SCRIPT
+            .evalsha_with_reload(client, (stream_key, meta_key, compact_key), args)
+            .await?;
+
+        Ok(replaced.is_some())
+    }
+}
+
+/// Arguments for finishing a stream with the given status and finish details (as JSON, or
+/// empty for no data), following the argument contract of [`FINISH_STREAM_SCRIPT`]
+fn finish_args(status: StreamStatus, details: &str) -> [&str; 11] {
+    [
+        constants::META_STATUS_FIELD,
+        constants::StreamStatus::Active.as_str(),
+        status.as_str(),
+        constants::EVENT_KEY,
+        status.status_event(),
+        constants::META_SEQUENTIAL_FIELD,
+        constants::DATA_KEY,
+        constants::CONTENT_TYPE_KEY,
+        constants::JSON_CONTENT_TYPE,
+        constants::META_FINISH_FIELD,
+        details,
+    ]
 }
 
 /// Atomically create a stream unless it is already active.
@@ -94,13 +427,25 @@ impl RedisScripts {
 /// Key contract:
 /// - `KEYS[1]`: Redis stream key
 /// - `KEYS[2]`: stream metadata hash key
+/// - `KEYS[3]`: sorted set of recent idempotency keys
 ///
 /// Argument contract:
 /// - `ARGV[1]`: metadata status field name
 /// - `ARGV[2]`: active status value
-/// - `ARGV[3]`: stream/meta TTL in seconds
+/// - `ARGV[3]`: stream TTL setting in seconds
 /// - `ARGV[4]`: stream entry event field name
 /// - `ARGV[5]`: start event value
+/// - `ARGV[6]`: metadata TTL field name
+/// - `ARGV[7]`: metadata max length field name
+/// - `ARGV[8]`: stream max length
+/// - `ARGV[9]`: metadata sequential IDs field name
+/// - `ARGV[10]`: `"1"` to use sequential IDs (starting with `0-1` for the start event), else `"0"`
+/// - `ARGV[11]`: stream/meta key TTL in seconds (the TTL setting plus the expired retention)
+/// - `ARGV[12]`: metadata last write time field name, set to the current time
+///
+/// Repeated metadata field argument contract, starting at `ARGV[13]`:
+/// - metadata field name (an attribute field, the event schema field, or a setting field)
+/// - field value
 ///
 /// Return contract:
 /// - stream ID for the start event when created
@@ -111,59 +456,172 @@ if redis.call('HGET', KEYS[2], ARGV[1]) == ARGV[2] then
   return nil
 end
 
-redis.call('DEL', KEYS[1], KEYS[2])
-local id = redis.call('XADD', KEYS[1], '*', ARGV[4], ARGV[5])
-redis.call('EXPIRE', KEYS[1], ARGV[3])
-redis.call('HSET', KEYS[2], ARGV[1], ARGV[2])
-redis.call('EXPIRE', KEYS[2], ARGV[3])
+redis.call('DEL', KEYS[1], KEYS[2], KEYS[3])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local start_id = ARGV[10] == '1' and '0-1' or '*'
+local id = redis.call('XADD', KEYS[1], start_id, ARGV[4], ARGV[5])
+redis.call('EXPIRE', KEYS[1], ARGV[11])
+redis.call('HSET', KEYS[2], ARGV[1], ARGV[2], ARGV[6], ARGV[3], ARGV[7], ARGV[8], ARGV[9], ARGV[10], ARGV[12], now, unpack(ARGV, 13))
+redis.call('EXPIRE', KEYS[2], ARGV[11])
 
 return id
 "#;
     Script::from_lua(lua)
 });
 
-/// Atomically write a batch of events if the stream is active.
+/// Atomically write a batch of events if the stream is active, skipping events with
+/// a recently written idempotency key. The IDs of all events are assigned before writing,
+/// so the batch is rejected as a whole if an explicit ID isn't increasing. Streams with a
+/// sliding TTL get their expiry refreshed after writing, and streams with an inactivity
+/// timeout get their last write time updated.
 ///
 /// Key contract:
 /// - `KEYS[1]`: Redis stream key
 /// - `KEYS[2]`: stream metadata hash key
+/// - `KEYS[3]`: sorted set of recent idempotency keys, scored by write time (unix ms)
 ///
 /// Fixed argument contract:
 /// - `ARGV[1]`: metadata status field name
 /// - `ARGV[2]`: active status value
-/// - `ARGV[3]`: approximate stream max length
+/// - `ARGV[3]`: default approximate stream max length, if not set in the metadata
 /// - `ARGV[4]`: stream entry event field name
 /// - `ARGV[5]`: stream entry data field name
+/// - `ARGV[6]`: metadata max length field name
+/// - `ARGV[7]`: stream entry content type field name
+/// - `ARGV[8]`: dedup window in milliseconds
+/// - `ARGV[9]`: expected last stream ID, or empty to skip the check
+/// - `ARGV[10]`: metadata sequential IDs field name
+/// - `ARGV[11]`: metadata sliding TTL field name
+/// - `ARGV[12]`: metadata TTL field name
+/// - `ARGV[13]`: retention period of expired streams in seconds, added to the refreshed TTL
+/// - `ARGV[14]`: metadata inactivity timeout field name
+/// - `ARGV[15]`: metadata last write time field name
+/// - `ARGV[16]`: metadata event schema field name
+/// - `ARGV[17]`: event schema the events were validated against, or empty for none
 ///
-/// Repeated event argument contract, starting at `ARGV[6]`:
+/// Repeated event argument contract, starting at `ARGV[18]`:
 /// - event name
 /// - data flag: `"1"` means include the data field, `"0"` means omit it
-/// - data value, or an empty placeholder when the flag is `"0"`
+/// - data value (raw bytes), or an empty placeholder when the flag is `"0"`
+/// - content type of the data, or empty for plain text (omits the content type field)
+/// - idempotency key, or empty to always write the event
+/// - explicit stream ID, or empty to assign the next ID
 ///
 /// Return contract:
-/// - array of stream IDs for the written events
+/// - `ok` followed by the stream ID of each written event, or an empty string for
+///   skipped duplicates
+/// - `schema` and the current event schema (or empty for none) when it doesn't match the
+///   given schema
+/// - `conflict` and the current last stream ID when it doesn't match the expected ID
+/// - `stale` and the current last stream ID when an explicit ID isn't greater than the
+///   previous event ID
 /// - `nil` when the stream is not active
 static WRITE_EVENTS_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
     let lua = r#"
-if redis.call('HGET', KEYS[2], ARGV[1]) ~= ARGV[2] then
+local meta = redis.call('HMGET', KEYS[2], ARGV[1], ARGV[6], ARGV[10], ARGV[11], ARGV[12], ARGV[14], ARGV[16])
+if meta[1] ~= ARGV[2] then
   return nil
 end
+if (meta[7] or '') ~= ARGV[17] then
+  return {'schema', meta[7] or ''}
+end
+if #ARGV < 18 then
+  return {'ok'}
+end
+
+local last_entries = redis.call('XREVRANGE', KEYS[1], '+', '-', 'COUNT', 1)
+local last_id = last_entries[1] and last_entries[1][1] or ''
+if ARGV[9] ~= '' and last_id ~= ARGV[9] then
+  return {'conflict', last_id}
+end
+
+local function parse_id(id)
+  local ms, seq = string.match(id, '^(%d+)-(%d+)$')
+  return tonumber(ms) or 0, tonumber(seq) or 0
+end
+
+local max_len = meta[2] or ARGV[3]
+local sequential = meta[3] == '1'
+local window = tonumber(ARGV[8])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+redis.call('ZREMRANGEBYSCORE', KEYS[3], '-inf', now - window)
 
-local ids = {}
-local arg_index = 6
+local events = {}
+local seen_keys = {}
+local last_ms, last_seq = parse_id(last_id)
+local arg_index = 18
 while arg_index <= #ARGV do
-  local event = ARGV[arg_index]
-  local has_data = ARGV[arg_index + 1]
-  local data = ARGV[arg_index + 2]
-  arg_index = arg_index + 3
-
-  local command = {'XADD', KEYS[1], 'MAXLEN', '~', ARGV[3], '*', ARGV[4], event}
-  if has_data == '1' then
-    table.insert(command, ARGV[5])
-    table.insert(command, data)
+  local event = {
+    name = ARGV[arg_index],
+    has_data = ARGV[arg_index + 1],
+    data = ARGV[arg_index + 2],
+    content_type = ARGV[arg_index + 3],
+    idempotency_key = ARGV[arg_index + 4],
+  }
+  local explicit_id = ARGV[arg_index + 5]
+  arg_index = arg_index + 6
+
+  local idempotency_key = event.idempotency_key
+  if idempotency_key ~= '' and (seen_keys[idempotency_key] or redis.call('ZSCORE', KEYS[3], idempotency_key)) then
+    event.duplicate = true
+  else
+    if idempotency_key ~= '' then
+      seen_keys[idempotency_key] = true
+    end
+    if explicit_id ~= '' then
+      local ms, seq = parse_id(explicit_id)
+      if ms < last_ms or (ms == last_ms and seq <= last_seq) then
+        return {'stale', last_id}
+      end
+      last_ms, last_seq = ms, seq
+    elseif sequential then
+      last_ms, last_seq = last_ms + 1, 0
+    elseif now > last_ms then
+      last_ms, last_seq = now, 0
+    else
+      last_seq = last_seq + 1
+    end
+    event.id = string.format('%d-%d', last_ms, last_seq)
   end
+  table.insert(events, event)
+end
 
-  table.insert(ids, redis.call(unpack(command)))
+local ids = {'ok'}
+local has_idempotency_keys = false
+for _, event in ipairs(events) do
+  if event.duplicate then
+    table.insert(ids, '')
+  else
+    local command = {'XADD', KEYS[1], 'MAXLEN', '~', max_len, event.id, ARGV[4], event.name}
+    if event.has_data == '1' then
+      table.insert(command, ARGV[5])
+      table.insert(command, event.data)
+      if event.content_type ~= '' then
+        table.insert(command, ARGV[7])
+        table.insert(command, event.content_type)
+      end
+    end
+
+    table.insert(ids, redis.call(unpack(command)))
+    if event.idempotency_key ~= '' then
+      redis.call('ZADD', KEYS[3], now, event.idempotency_key)
+      has_idempotency_keys = true
+    end
+  end
+end
+
+if has_idempotency_keys then
+  redis.call('PEXPIRE', KEYS[3], window)
+end
+if meta[4] == '1' and meta[5] then
+  local key_ttl = tonumber(meta[5]) + tonumber(ARGV[13])
+  redis.call('EXPIRE', KEYS[1], key_ttl)
+  redis.call('EXPIRE', KEYS[2], key_ttl)
+end
+if meta[6] then
+  redis.call('HSET', KEYS[2], ARGV[15], now)
 end
 
 return ids
@@ -171,6 +629,44 @@ return ids
     Script::from_lua(lua)
 });
 
+/// Atomically extend the TTL of an active stream's keys, optionally storing a new TTL setting.
+///
+/// Key contract:
+/// - `KEYS[1]`: Redis stream key
+/// - `KEYS[2]`: stream metadata hash key
+///
+/// Argument contract:
+/// - `ARGV[1]`: metadata status field name
+/// - `ARGV[2]`: active status value
+/// - `ARGV[3]`: metadata TTL field name
+/// - `ARGV[4]`: new TTL setting in seconds, or empty to use the stored TTL setting
+/// - `ARGV[5]`: retention period of expired streams in seconds, added to the keys' TTL
+///
+/// Return contract:
+/// - the applied TTL in seconds
+/// - `nil` when the stream is not active
+static TOUCH_STREAM_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    let lua = r#"
+local meta = redis.call('HMGET', KEYS[2], ARGV[1], ARGV[3])
+if meta[1] ~= ARGV[2] then
+  return nil
+end
+
+local ttl = ARGV[4]
+if ttl == '' then
+  ttl = meta[2]
+else
+  redis.call('HSET', KEYS[2], ARGV[3], ttl)
+end
+local key_ttl = tonumber(ttl) + tonumber(ARGV[5])
+redis.call('EXPIRE', KEYS[1], key_ttl)
+redis.call('EXPIRE', KEYS[2], key_ttl)
+
+return tonumber(ttl)
+"#;
+    Script::from_lua(lua)
+});
+
 /// Atomically append a terminal event and mark a stream inactive.
 ///
 /// Key contract:
@@ -183,20 +679,336 @@ return ids
 /// - `ARGV[3]`: final status value
 /// - `ARGV[4]`: stream entry event field name
 /// - `ARGV[5]`: terminal event value
+/// - `ARGV[6]`: metadata sequential IDs field name
+/// - `ARGV[7]`: stream entry data field name
+/// - `ARGV[8]`: stream entry content type field name
+/// - `ARGV[9]`: JSON content type
+/// - `ARGV[10]`: metadata finish details field name
+/// - `ARGV[11]`: finish details as JSON (the terminal event's data), or empty for no data
 ///
 /// Return contract:
 /// - stream ID for the terminal event
 /// - `nil` when the stream is not active
 static FINISH_STREAM_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
     let lua = r#"
-if redis.call('HGET', KEYS[2], ARGV[1]) ~= ARGV[2] then
+local meta = redis.call('HMGET', KEYS[2], ARGV[1], ARGV[6])
+if meta[1] ~= ARGV[2] then
   return nil
 end
 
-local id = redis.call('XADD', KEYS[1], '*', ARGV[4], ARGV[5])
-redis.call('HSET', KEYS[2], ARGV[1], ARGV[3])
+return finish_stream(meta[2], ARGV[3], ARGV[5], ARGV[11])
+"#;
+    Script::from_lua([FINISH_STREAM_LUA, lua].concat())
+});
 
-return id
+/// Lua functions for finishing a stream, shared by the scripts that follow the argument
+/// contract of [`FINISH_STREAM_SCRIPT`]:
+/// - `next_entry_id(sequential)`: ID for the next entry (the next sequence number if
+///   `sequential` is the stream's `"1"` sequential IDs setting, else `*`)
+/// - `finish_stream(sequential, status, event, details)`: append the terminal event, with the
+///   finish details as its JSON data unless they're empty, and store the final status and
+///   details in the metadata. Returns the stream ID of the terminal event.
+const FINISH_STREAM_LUA: &str = r#"
+local function next_entry_id(sequential)
+  if sequential ~= '1' then
+    return '*'
+  end
+  local last_entries = redis.call('XREVRANGE', KEYS[1], '+', '-', 'COUNT', 1)
+  local last_ms = last_entries[1] and tonumber(string.match(last_entries[1][1], '^(%d+)')) or 0
+  return string.format('%d-0', last_ms + 1)
+end
+
+local function finish_stream(sequential, status, event, details)
+  local next_id = next_entry_id(sequential)
+  if details == '' then
+    local id = redis.call('XADD', KEYS[1], next_id, ARGV[4], event)
+    redis.call('HSET', KEYS[2], ARGV[1], status)
+    return id
+  end
+
+  local id = redis.call('XADD', KEYS[1], next_id, ARGV[4], event, ARGV[7], details, ARGV[8], ARGV[9])
+  redis.call('HSET', KEYS[2], ARGV[1], status, ARGV[10], details)
+  return id
+end
+"#;
+
+/// Atomically record a consumer's request to cancel an active stream, and cancel the stream
+/// right away if its settings allow it.
+///
+/// Key contract:
+/// - `KEYS[1]`: Redis stream key
+/// - `KEYS[2]`: stream metadata hash key
+///
+/// Argument contract:
+/// - `ARGV[1..11]`: as for [`FINISH_STREAM_SCRIPT`], with the cancelled status and event,
+///   and the finish details of the cancelled stream
+/// - `ARGV[12]`: metadata cancel requested field name
+/// - `ARGV[13]`: metadata cancel on request field name
+/// - `ARGV[14]`: cancel requested event value
+///
+/// Return contract:
+/// - `recorded` when the `cancel_requested` event was written
+/// - `already` when cancellation was already requested
+/// - `cancelled` when the stream was also cancelled
+/// - `nil` when the stream is not active
+static REQUEST_CANCEL_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    let lua = r#"
+local meta = redis.call('HMGET', KEYS[2], ARGV[1], ARGV[6], ARGV[12], ARGV[13])
+if meta[1] ~= ARGV[2] then
+  return nil
+end
+if meta[3] == '1' then
+  return 'already'
+end
+
+redis.call('XADD', KEYS[1], next_entry_id(meta[2]), ARGV[4], ARGV[14])
+redis.call('HSET', KEYS[2], ARGV[12], '1')
+if meta[4] ~= '1' then
+  return 'recorded'
+end
+
+finish_stream(meta[2], ARGV[3], ARGV[5], ARGV[11])
+
+return 'cancelled'
+"#;
+    Script::from_lua([FINISH_STREAM_LUA, lua].concat())
+});
+
+/// Atomically expire an active stream whose TTL has passed, appending the `expired` terminal
+/// event. The TTL has passed once the remaining TTL of the metadata is within the retention
+/// period of expired streams. Streams with an inactivity timeout that had no writes for the
+/// timeout are timed out instead, appending the `timed_out` terminal event.
+///
+/// Key contract:
+/// - `KEYS[1]`: Redis stream key
+/// - `KEYS[2]`: stream metadata hash key
+///
+/// Argument contract:
+/// - `ARGV[1..11]`: as for [`FINISH_STREAM_SCRIPT`], with the expired status and event,
+///   and no finish details
+/// - `ARGV[12]`: retention period of expired streams in milliseconds
+/// - `ARGV[13]`: metadata inactivity timeout field name
+/// - `ARGV[14]`: metadata last write time field name
+/// - `ARGV[15]`: timed out status value
+/// - `ARGV[16]`: timed out event value
+///
+/// Return contract:
+/// - the final status (expired or timed out) and the stream ID of the terminal event
+/// - `pending` and the milliseconds until the stream expires or times out, if its TTL was
+///   extended or it was written to
+/// - `nil` when the stream is not active, or its keys don't expire
+static EXPIRE_STREAM_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    let lua = r#"
+local meta = redis.call('HMGET', KEYS[2], ARGV[1], ARGV[6], ARGV[13], ARGV[14])
+if meta[1] ~= ARGV[2] then
+  return nil
+end
+
+local ttl = redis.call('PTTL', KEYS[2])
+if ttl < 0 then
+  return nil
+end
+local remaining = ttl - tonumber(ARGV[12])
+local status, event = ARGV[3], ARGV[5]
+if meta[3] and meta[4] then
+  local time = redis.call('TIME')
+  local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+  local idle_remaining = tonumber(meta[4]) + tonumber(meta[3]) * 1000 - now
+  if idle_remaining <= 0 then
+    status, event, remaining = ARGV[15], ARGV[16], 0
+  elseif idle_remaining < remaining then
+    remaining = idle_remaining
+  end
+end
+if remaining > 0 then
+  return {'pending', tostring(remaining)}
+end
+
+local id = finish_stream(meta[2], status, event, ARGV[11])
+
+return {status, id}
+"#;
+    Script::from_lua([FINISH_STREAM_LUA, lua].concat())
+});
+
+/// Atomically remove stream keys from the sorted set of expirations, if they're still due.
+///
+/// Key contract:
+/// - `KEYS[1]`: sorted set of stream keys, scored by expiration time (unix ms)
+///
+/// Argument contract:
+/// - `ARGV[1]`: current time (unix ms)
+/// - `ARGV[2..]`: stream keys to remove
+///
+/// Return contract:
+/// - `nil`
+static REMOVE_EXPIRIES_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    let lua = r#"
+local now = tonumber(ARGV[1])
+for i = 2, #ARGV do
+  local score = tonumber(redis.call('ZSCORE', KEYS[1], ARGV[i]))
+  if score and score <= now then
+    redis.call('ZREM', KEYS[1], ARGV[i])
+  end
+end
+
+return nil
+"#;
+    Script::from_lua(lua)
+});
+
+/// Atomically compact a chunk of a finished stream, merging the runs of consecutive events to
+/// compact and appending the result to the temporary compacted stream. A run continuing from
+/// the previous chunk is merged into the last entry of the compacted stream. Compacting in
+/// chunks avoids blocking Redis for the whole rewrite of long streams.
+///
+/// Key contract:
+/// - `KEYS[1]`: Redis stream key
+/// - `KEYS[2]`: stream metadata hash key
+/// - `KEYS[3]`: temporary compacted stream key (deleted when starting from the beginning)
+///
+/// Fixed argument contract:
+/// - `ARGV[1]`: metadata status field name
+/// - `ARGV[2]`: active status value
+/// - `ARGV[3]`: stream entry event field name
+/// - `ARGV[4]`: stream entry data field name
+/// - `ARGV[5]`: stream entry content type field name
+/// - `ARGV[6]`: JSON content type (not merged)
+/// - `ARGV[7]`: ID of the last entry compacted so far, or empty to start from the beginning
+/// - `ARGV[8]`: maximum number of entries to read
+///
+/// Repeated argument contract, starting at `ARGV[9]`:
+/// - name of an event to compact
+///
+/// Return contract:
+/// - `[removed, read, last_id]`: number of removed and read entries, and the ID of the last
+///   read entry (empty if none were read)
+/// - `nil` when the stream doesn't exist or is still active
+static COMPACT_STREAM_CHUNK_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    let lua = r#"
+local status = redis.call('HGET', KEYS[2], ARGV[1])
+if not status or status == ARGV[2] then
+  return nil
+end
+
+local compact = {}
+for i = 9, #ARGV do
+  compact[ARGV[i]] = true
+end
+
+local function parse_entry(entry)
+  local fields = {}
+  for i = 1, #entry[2], 2 do
+    fields[entry[2][i]] = entry[2][i + 1]
+  end
+  return {
+    id = entry[1],
+    fields = entry[2],
+    event = fields[ARGV[3]],
+    content_type = fields[ARGV[5]] or '',
+    has_data = fields[ARGV[4]] ~= nil,
+    data = {fields[ARGV[4]] or ''},
+  }
+end
+
+local merged = {}
+local start = '-'
+if ARGV[7] == '' then
+  redis.call('DEL', KEYS[3])
+else
+  start = '(' .. ARGV[7]
+  local last = redis.call('XREVRANGE', KEYS[3], '+', '-', 'COUNT', 1)[1]
+  if last then
+    local previous = parse_entry(last)
+    previous.stored_id = last[1]
+    table.insert(merged, previous)
+  end
+end
+
+local entries = redis.call('XRANGE', KEYS[1], start, '+', 'COUNT', ARGV[8])
+local removed = 0
+for _, entry in ipairs(entries) do
+  local current = parse_entry(entry)
+  local previous = merged[#merged]
+  if previous and compact[current.event] and previous.event == current.event
+      and previous.content_type == current.content_type and current.content_type ~= ARGV[6] then
+    previous.id = current.id
+    previous.merged = true
+    previous.has_data = previous.has_data or current.has_data
+    table.insert(previous.data, current.data[1])
+    removed = removed + 1
+  else
+    table.insert(merged, current)
+  end
+end
+
+for _, entry in ipairs(merged) do
+  if entry.merged then
+    if entry.stored_id then
+      redis.call('XDEL', KEYS[3], entry.stored_id)
+    end
+    local command = {'XADD', KEYS[3], entry.id, ARGV[3], entry.event}
+    if entry.has_data then
+      table.insert(command, ARGV[4])
+      table.insert(command, table.concat(entry.data))
+    end
+    if entry.content_type ~= '' then
+      table.insert(command, ARGV[5])
+      table.insert(command, entry.content_type)
+    end
+    redis.call(unpack(command))
+  elseif not entry.stored_id then
+    redis.call('XADD', KEYS[3], entry.id, unpack(entry.fields))
+  end
+end
+
+local ttl = redis.call('PTTL', KEYS[1])
+if ttl > 0 and #entries > 0 then
+  redis.call('PEXPIRE', KEYS[3], ttl)
+end
+
+local last_id = ''
+if #entries > 0 then
+  last_id = entries[#entries][1]
+end
+return {removed, #entries, last_id}
+"#;
+    Script::from_lua(lua)
+});
+
+/// Atomically replace a finished stream with its compacted stream, keeping the stream's TTL.
+/// The compacted stream is deleted instead if the stream is active or no longer ends with the
+/// last compacted entry (e.g. it was restarted while compacting).
+///
+/// Key contract:
+/// - `KEYS[1]`: Redis stream key
+/// - `KEYS[2]`: stream metadata hash key
+/// - `KEYS[3]`: temporary compacted stream key
+///
+/// Argument contract:
+/// - `ARGV[1]`: metadata status field name
+/// - `ARGV[2]`: active status value
+/// - `ARGV[3]`: ID of the last compacted entry
+///
+/// Return contract:
+/// - `1` when the stream was replaced
+/// - `nil` when the stream was not replaced
+static REPLACE_COMPACTED_STREAM_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    let lua = r#"
+local status = redis.call('HGET', KEYS[2], ARGV[1])
+local last = redis.call('XREVRANGE', KEYS[1], '+', '-', 'COUNT', 1)[1]
+if not status or status == ARGV[2] or not last or last[1] ~= ARGV[3] then
+  redis.call('DEL', KEYS[3])
+  return nil
+end
+
+local ttl = redis.call('PTTL', KEYS[1])
+redis.call('RENAME', KEYS[3], KEYS[1])
+if ttl > 0 then
+  redis.call('PEXPIRE', KEYS[1], ttl)
+end
+
+return 1
 "#;
     Script::from_lua(lua)
 });
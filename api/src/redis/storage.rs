@@ -0,0 +1,248 @@
+use fred::{clients::Pool, interfaces::ClientLike};
+use futures::{FutureExt, future::BoxFuture};
+
+use crate::{
+    config::AppConfig,
+    redis::{
+        AddEvent, ExclusiveClientManager, RedisClient, RedisConnection, StreamService,
+        constants::StreamStatus,
+        types::{FinishDetails, RedisStr, StreamEntry, StreamMeta, StreamSettings},
+    },
+    storage::{
+        CancelRequestOutcome, PageQuery, StorageError, StorageResult, StreamConnection,
+        StreamListing, StreamRange, StreamStorage, WriteOutcome,
+    },
+};
+
+/// Stream storage in Redis. Uses the static pool for quick operations, and exclusive
+/// connections for long-running operations.
+pub struct RedisStorage {
+    static_pool: Pool,
+    exclusive_clients: ExclusiveClientManager,
+    stream: StreamService,
+    max_len: u32,
+    dedup_window: u32,
+    expired_retention: u32,
+    batch_size: u32,
+}
+
+impl RedisStorage {
+    pub fn new(
+        static_pool: Pool,
+        exclusive_clients: ExclusiveClientManager,
+        config: &AppConfig,
+    ) -> Self {
+        Self {
+            static_pool,
+            exclusive_clients,
+            stream: StreamService::new(config),
+            max_len: config.max_stream_len,
+            dedup_window: config.dedup_window,
+            expired_retention: config.expired_retention,
+            batch_size: config.read_batch_size,
+        }
+    }
+
+    /// Get a client from the static pool
+    fn client(&self) -> RedisClient {
+        RedisClient::new(
+            self.static_pool.next().to_owned(),
+            self.max_len,
+            self.dedup_window,
+            self.expired_retention,
+            self.stream.clone(),
+        )
+    }
+}
+
+impl StreamStorage for RedisStorage {
+    fn stream_info<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, StorageResult<(StreamMeta, u64, i64)>> {
+        async move { Ok(self.client().stream_info(key).await?) }.boxed()
+    }
+
+    fn active_stream_ttl<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, StorageResult<Option<(u32, bool)>>> {
+        async move { Ok(self.client().active_stream_ttl(key).await?) }.boxed()
+    }
+
+    fn scan_streams<'a>(
+        &'a self,
+        pattern: Option<&'a str>,
+        status: StreamStatus,
+        attributes: &'a [(&'a str, &'a str)],
+    ) -> BoxFuture<'a, StorageResult<Vec<StreamListing>>> {
+        async move {
+            let client = self.client();
+            Ok(client.scan_streams(pattern, status, attributes).await?)
+        }
+        .boxed()
+    }
+
+    fn start_stream<'a>(
+        &'a self,
+        key: &'a str,
+        settings: &'a StreamSettings,
+    ) -> BoxFuture<'a, StorageResult<Option<RedisStr>>> {
+        async move { Ok(self.client().start_stream(key, settings).await?) }.boxed()
+    }
+
+    fn touch_stream<'a>(
+        &'a self,
+        key: &'a str,
+        ttl: Option<u32>,
+    ) -> BoxFuture<'a, StorageResult<Option<u32>>> {
+        async move { Ok(self.client().touch_stream(key, ttl).await?) }.boxed()
+    }
+
+    fn write_events<'a>(
+        &'a self,
+        key: &'a str,
+        events: &'a [AddEvent],
+        expected_last_id: Option<&'a str>,
+        event_schema: Option<&'a str>,
+    ) -> BoxFuture<'a, StorageResult<WriteOutcome>> {
+        async move {
+            let client = self.client();
+            let outcome = client
+                .write_events(key, events, expected_last_id, event_schema)
+                .await?;
+            Ok(outcome)
+        }
+        .boxed()
+    }
+
+    fn finish_stream<'a>(
+        &'a self,
+        key: &'a str,
+        status: StreamStatus,
+        details: Option<&'a FinishDetails>,
+    ) -> BoxFuture<'a, StorageResult<Option<RedisStr>>> {
+        async move { Ok(self.client().finish_stream(key, status, details).await?) }.boxed()
+    }
+
+    fn request_cancel<'a>(
+        &'a self,
+        key: &'a str,
+        details: &'a FinishDetails,
+    ) -> BoxFuture<'a, StorageResult<Option<CancelRequestOutcome>>> {
+        async move { Ok(self.client().request_cancel(key, details).await?) }.boxed()
+    }
+
+    fn expire_streams(&self) -> BoxFuture<'_, StorageResult<Vec<(String, StreamStatus)>>> {
+        async move { Ok(self.client().expire_streams().await?) }.boxed()
+    }
+
+    fn compact_stream<'a>(
+        &'a self,
+        key: &'a str,
+        events: &'a [String],
+    ) -> BoxFuture<'a, StorageResult<Option<u64>>> {
+        async move { Ok(self.client().compact_stream(key, events).await?) }.boxed()
+    }
+
+    fn range<'a>(
+        &'a self,
+        streams: &'a [(&'a str, &'a str)],
+    ) -> BoxFuture<'a, StorageResult<Vec<StreamRange>>> {
+        async move {
+            self.client()
+                .range(streams)
+                .await?
+                .into_iter()
+                .map(|(entries, status)| {
+                    let status = status.ok_or(StorageError::StreamNotFound)?;
+                    let is_active = *status == StreamStatus::Active;
+                    Ok(StreamRange { entries, is_active })
+                })
+                .collect()
+        }
+        .boxed()
+    }
+
+    fn range_page<'a>(
+        &'a self,
+        key: &'a str,
+        query: &'a PageQuery,
+    ) -> BoxFuture<'a, StorageResult<Vec<StreamEntry>>> {
+        async move {
+            match self.client().range_page(key, query).await? {
+                (entries, Some(_)) => Ok(entries),
+                (_, None) => Err(StorageError::StreamNotFound),
+            }
+        }
+        .boxed()
+    }
+
+    fn inactive_streams<'a>(
+        &'a self,
+        keys: &'a [&'a str],
+    ) -> BoxFuture<'a, StorageResult<Vec<String>>> {
+        async move { Ok(self.client().inactive_streams(keys).await?) }.boxed()
+    }
+
+    fn revoke_token<'a>(&'a self, token_id: &'a str, ttl: i64) -> BoxFuture<'a, StorageResult<()>> {
+        async move { Ok(self.client().revoke_token(token_id, ttl).await?) }.boxed()
+    }
+
+    fn revoke_key_tokens<'a>(
+        &'a self,
+        key: &'a str,
+        revoked_at: i64,
+        ttl: i64,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            let client = self.client();
+            Ok(client.revoke_key_tokens(key, revoked_at, ttl).await?)
+        }
+        .boxed()
+    }
+
+    fn is_token_revoked<'a>(
+        &'a self,
+        token_id: &'a str,
+        issued_at: i64,
+        keys: &'a [&'a str],
+    ) -> BoxFuture<'a, StorageResult<bool>> {
+        async move {
+            let client = self.client();
+            Ok(client.is_token_revoked(token_id, issued_at, keys).await?)
+        }
+        .boxed()
+    }
+
+    fn connect(&self) -> BoxFuture<'_, StorageResult<Option<Box<dyn StreamConnection>>>> {
+        async move {
+            let Some(client) = self.exclusive_clients.get().await? else {
+                return Ok(None);
+            };
+            let connection = RedisConnection::new(
+                client,
+                self.stream.clone(),
+                self.max_len,
+                self.dedup_window,
+                self.expired_retention,
+                self.batch_size,
+            );
+
+            Ok(Some(Box::new(connection) as Box<dyn StreamConnection>))
+        }
+        .boxed()
+    }
+
+    fn available_connections(&self) -> usize {
+        self.exclusive_clients.num_available()
+    }
+
+    fn shutdown(&self) -> BoxFuture<'_, ()> {
+        async move {
+            let _ = self.static_pool.quit().await;
+            self.exclusive_clients.shutdown().await;
+        }
+        .boxed()
+    }
+}
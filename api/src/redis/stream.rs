@@ -1,37 +1,90 @@
-use std::sync::Arc;
-
-use crate::{config::AppConfig, redis::constants};
+use crate::{
+    config::AppConfig,
+    redis::{Revocation, constants},
+};
 
 /// Utilities for managing Redis streams
+#[derive(Clone)]
 pub struct StreamService {
-    config: Arc<AppConfig>,
+    key_prefix: String,
+    base_url: String,
+    redis_cluster: bool,
 }
 
 impl StreamService {
-    pub fn new(config: Arc<AppConfig>) -> Self {
-        Self { config }
+    pub fn new(config: &AppConfig) -> Self {
+        Self {
+            key_prefix: config.key_prefix.clone(),
+            base_url: config.base_url.clone(),
+            redis_cluster: config.redis_cluster,
+        }
     }
 
     /// Get the full stream key/prefix
     pub fn stream_key(&self, key: &str) -> String {
-        [&self.config.key_prefix, constants::STREAM_PREFIX, key].concat()
+        self.full_key(constants::STREAM_PREFIX, key)
     }
 
     /// Get the full key for the metadata associated with a given stream key/prefix
     pub fn meta_key(&self, key: &str) -> String {
-        [&self.config.key_prefix, constants::META_PREFIX, key].concat()
+        self.full_key(constants::META_PREFIX, key)
+    }
+
+    /// Get the full key for the recent idempotency keys of a given stream key
+    pub fn dedup_key(&self, key: &str) -> String {
+        self.full_key(constants::DEDUP_PREFIX, key)
+    }
+
+    /// Get the full key of the temporary stream written while compacting a given stream key
+    pub fn compact_key(&self, key: &str) -> String {
+        self.full_key(constants::COMPACT_PREFIX, key)
+    }
+
+    /// Get the full key of the sorted set of stream expirations
+    pub fn expiries_key(&self) -> String {
+        [&self.key_prefix, constants::EXPIRIES_KEY].concat()
+    }
+
+    /// Get the stream key from the full metadata key
+    pub fn key_from_meta_key<'k>(&self, meta_key: &'k str) -> Option<&'k str> {
+        let key = meta_key
+            .strip_prefix(&self.key_prefix)?
+            .strip_prefix(constants::META_PREFIX)?;
+        match self.redis_cluster {
+            true => key.strip_prefix('{')?.strip_suffix('}'),
+            false => Some(key),
+        }
     }
 
-    /// Length of the meta key prefix
-    pub fn meta_key_prefix_len(&self) -> usize {
-        self.config.key_prefix.len() + constants::META_PREFIX.len()
+    /// In cluster mode, the stream key is wrapped in a hash tag so that the stream and its
+    /// associated keys are stored in the same hash slot (required for the Lua scripts)
+    fn full_key(&self, type_prefix: &str, key: &str) -> String {
+        match self.redis_cluster {
+            true => [&self.key_prefix, type_prefix, "{", key, "}"].concat(),
+            false => [&self.key_prefix, type_prefix, key].concat(),
+        }
+    }
+
+    /// Get the full denylist key for a revoked token ID
+    pub fn revoked_token_key(&self, token_id: &str) -> String {
+        [&self.key_prefix, constants::REVOKED_TOKEN_PREFIX, token_id].concat()
+    }
+
+    /// Get the full key of the sorted set of stream keys/prefixes with revoked tokens
+    pub fn revoked_keys_key(&self) -> String {
+        [&self.key_prefix, constants::REVOKED_KEYS_KEY].concat()
+    }
+
+    /// Get the full pub/sub channel for token revocations
+    pub fn revocation_channel(&self) -> String {
+        Revocation::channel(&self.key_prefix)
     }
 
     /// Get the URL for streaming SSE events from the given Redis stream
     pub fn sse_url(&self, key: &str) -> String {
         format!(
             "{}/api/client/sse?key={}",
-            self.config.base_url,
+            self.base_url,
             urlencoding::encode(key)
         )
     }
@@ -40,8 +93,48 @@ impl StreamService {
     pub fn ws_url(&self, key: &str) -> String {
         format!(
             "{}/api/client/ws?key={}",
-            &self.config.base_url,
+            self.base_url,
             urlencoding::encode(key)
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_service(redis_cluster: bool) -> StreamService {
+        StreamService::new(&AppConfig {
+            key_prefix: "test:".into(),
+            redis_cluster,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn standalone_keys() {
+        let streams = get_test_service(false);
+        assert_eq!(streams.stream_key("user:42"), "test:stream:user:42");
+        assert_eq!(streams.meta_key("user:42"), "test:meta:user:42");
+        assert_eq!(
+            streams.key_from_meta_key("test:meta:user:42"),
+            Some("user:42")
+        );
+        assert_eq!(streams.key_from_meta_key("other:meta:user:42"), None);
+    }
+
+    #[test]
+    fn cluster_keys_use_hash_tags() {
+        let streams = get_test_service(true);
+        assert_eq!(streams.stream_key("user:42"), "test:stream:{user:42}");
+        assert_eq!(streams.meta_key("user:42"), "test:meta:{user:42}");
+        assert_eq!(streams.dedup_key("user:42"), "test:dedup:{user:42}");
+        assert_eq!(streams.compact_key("user:42"), "test:compact:{user:42}");
+        assert_eq!(streams.meta_key("user:*"), "test:meta:{user:*}");
+        assert_eq!(
+            streams.key_from_meta_key("test:meta:{user:42}"),
+            Some("user:42")
+        );
+        assert_eq!(streams.key_from_meta_key("test:meta:user:42"), None);
+    }
+}
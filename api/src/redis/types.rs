@@ -1,9 +1,14 @@
+use std::{borrow::Cow, collections::HashMap};
+
 use axum::response::sse;
+use base64::{Engine, prelude::BASE64_STANDARD};
 use fred::types::FromValue;
+use itertools::Itertools;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 
-use crate::redis::constants;
+use crate::redis::{constants, util};
 
 /// An axum SSE event
 pub type SseEvent = sse::Event;
@@ -11,74 +16,442 @@ pub type SseEvent = sse::Event;
 pub type WsMessage = axum::extract::ws::Message;
 /// An intermediate, bytes-backed representation of a string from Redis (avoids re-allocation)
 pub type RedisStr = fred::bytes_utils::Str;
+/// Raw bytes from Redis
+pub type RedisBytes = fred::bytes::Bytes;
 
 /// Represents a Redis stream entry retrieved via the fred client
-pub struct RedisEntry {
+#[derive(Clone)]
+pub struct StreamEntry {
     pub id: RedisStr,
-    fields: Vec<(RedisStr, RedisStr)>,
+    fields: Vec<(RedisStr, RedisBytes)>,
 }
-impl FromValue for RedisEntry {
+impl FromValue for StreamEntry {
     fn from_value(value: fred::prelude::Value) -> Result<Self, fred::prelude::Error> {
         let (id, fields) = value.convert()?;
         Ok(Self { id, fields })
     }
 }
 
-impl RedisEntry {
-    /// Check if this entry is an ending event (i.e. event field is `end` or `cancel`)
+impl StreamEntry {
+    /// Create an entry with the given ID and event, without data
+    pub fn new(id: RedisStr, event: &str) -> Self {
+        Self {
+            id,
+            fields: vec![(
+                constants::EVENT_KEY.into(),
+                RedisBytes::copy_from_slice(event.as_bytes()),
+            )],
+        }
+    }
+
+    /// Get the event field of this entry
+    pub fn event(&self) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| *key == constants::EVENT_KEY)
+            .and_then(|(_, value)| std::str::from_utf8(value).ok())
+    }
+
+    /// Get the value of a field of this entry
+    fn field(&self, name: &str) -> Option<&RedisBytes> {
+        self.fields
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Merge the next entry into this one if it's the same event with data of the same
+    /// content type (JSON data is never merged), concatenating the data and taking the ID
+    /// of the next entry. Returns the next entry if it can't be merged.
+    pub fn merge(&mut self, next: StreamEntry) -> Result<(), StreamEntry> {
+        let content_type = self.field(constants::CONTENT_TYPE_KEY);
+        if self.event() != next.event()
+            || content_type != next.field(constants::CONTENT_TYPE_KEY)
+            || content_type.is_some_and(|content_type| content_type == constants::JSON_CONTENT_TYPE)
+        {
+            return Err(next);
+        }
+
+        self.id = next.id.clone();
+        let Some(next_data) = next.field(constants::DATA_KEY) else {
+            return Ok(());
+        };
+        match self
+            .fields
+            .iter_mut()
+            .find(|(key, _)| *key == constants::DATA_KEY)
+        {
+            Some((_, data)) => *data = [data.as_ref(), next_data.as_ref()].concat().into(),
+            None => self
+                .fields
+                .push((constants::DATA_KEY.into(), next_data.clone())),
+        }
+
+        Ok(())
+    }
+
+    /// Check if this entry is an ending event (i.e. event field is `end`, `cancel`, `expired`,
+    /// or `timed_out`)
     pub fn is_end_event(&self) -> bool {
-        self.fields.iter().any(|(key, val)| {
-            (&**key, &**val) == constants::END_ENTRY || (&**key, &**val) == constants::CANCEL_ENTRY
-        })
+        matches!(
+            self.event(),
+            Some(constants::END | constants::CANCEL | constants::EXPIRED | constants::TIMED_OUT)
+        )
     }
 
-    /// Convert this entry into a SSE event
+    /// Convert this entry into a SSE event (JSON data is sent as serialized text, and
+    /// binary data as base64)
     pub fn into_sse_event(self) -> SseEvent {
         let (id, event, data) = self.into_parts();
 
         SseEvent::default()
             .id(&*id)
             .event(&*event)
-            .data(data.as_deref().unwrap_or(" "))
+            .data(data.as_ref().map_or(Cow::Borrowed(" "), EventData::to_text))
     }
 
-    /// Convert this entry into a JSON WebSocket message
+    /// Convert this entry into a WebSocket message (see [`JsonEvent::into_ws_message`])
     pub fn into_ws_message(self) -> WsMessage {
-        let text = serde_json::to_string(&self.into_json()).unwrap_or_default();
-        WsMessage::text(text)
+        self.into_json().into_ws_message()
     }
 
     /// Convert this entry into a JSON event (adds the entry ID as the `id` field)
     pub fn into_json(self) -> JsonEvent {
         let (id, event, data) = self.into_parts();
-        JsonEvent { id, event, data }
+        JsonEvent {
+            id,
+            event,
+            encoding: data.as_ref().and_then(EventData::encoding),
+            data,
+            key: None,
+        }
+    }
+
+    /// Convert this entry into a JSON event tagged with the key of its stream
+    pub fn into_tagged_json(self, key: &str) -> JsonEvent {
+        JsonEvent {
+            key: Some(key.into()),
+            ..self.into_json()
+        }
     }
 
-    /// Returns the id, event field, and data field
-    pub fn into_parts(self) -> (RedisStr, RedisStr, Option<RedisStr>) {
-        let (mut event, mut data) = (None, None);
+    /// Convert this entry into a SSE event for a multiplexed subscription, with the JSON event
+    /// (tagged with the stream key) as the data, and the cursor of all streams as the ID
+    pub fn into_tagged_sse_event(self, key: &str, cursor: &MultiCursor) -> SseEvent {
+        let json_event = self.into_tagged_json(key);
+        SseEvent::default()
+            .id(cursor.to_event_id())
+            .event(&*json_event.event)
+            .data(serde_json::to_string(&json_event).unwrap_or_default())
+    }
+
+    /// Convert this entry into a WebSocket message tagged with the key of its stream
+    pub fn into_tagged_ws_message(self, key: &str) -> WsMessage {
+        self.into_tagged_json(key).into_ws_message()
+    }
+
+    /// Parse the ID into its timestamp and sequence number, for ordering entries across streams
+    pub fn id_parts(&self) -> (u64, u64) {
+        util::parse_entry_id(&self.id)
+    }
+
+    /// Returns the id, event field, and data field (along with its content type)
+    pub fn into_parts(self) -> (RedisStr, RedisStr, Option<EventData>) {
+        let (mut event, mut data, mut content_type) = (None, None, None);
         for (key, value) in self.fields {
             match &*key {
-                constants::EVENT_KEY => event = Some(value),
+                constants::EVENT_KEY => event = Some(bytes_to_str(value)),
                 constants::DATA_KEY => data = Some(value),
+                constants::CONTENT_TYPE_KEY => content_type = Some(bytes_to_str(value)),
                 _ => {}
             }
         }
+        let data = data.map(|data| match content_type.as_deref() {
+            Some(constants::JSON_CONTENT_TYPE) => EventData::Json(bytes_to_str(data)),
+            Some(constants::BINARY_CONTENT_TYPE) => EventData::Binary(data),
+            _ => EventData::Text(bytes_to_str(data)),
+        });
 
         (self.id, event.unwrap_or_else(|| "unknown".into()), data)
     }
 }
 
+/// Convert a field value from Redis into a string, replacing any invalid UTF-8
+fn bytes_to_str(bytes: RedisBytes) -> RedisStr {
+    RedisStr::from_inner(bytes).unwrap_or_else(|err| {
+        String::from_utf8_lossy(&err.into_inner())
+            .into_owned()
+            .into()
+    })
+}
+
+/// Data of a stream entry
+#[derive(Debug, Clone)]
+pub enum EventData {
+    /// Plain text
+    Text(RedisStr),
+    /// Serialized JSON value
+    Json(RedisStr),
+    /// Raw binary data
+    Binary(RedisBytes),
+}
+
+impl EventData {
+    /// Get the data as text (serialized JSON for JSON data, and base64 for binary data)
+    pub fn to_text(&self) -> Cow<'_, str> {
+        match self {
+            EventData::Text(text) | EventData::Json(text) => Cow::Borrowed(text),
+            EventData::Binary(bytes) => Cow::Owned(BASE64_STANDARD.encode(bytes)),
+        }
+    }
+
+    /// Convert the data into a JSON value (a string for text data, and a base64 string
+    /// for binary data)
+    pub fn into_value(self) -> serde_json::Value {
+        match self {
+            EventData::Text(text) => serde_json::Value::String((*text).to_owned()),
+            EventData::Json(json) => {
+                serde_json::from_str(&json).unwrap_or_else(|_| (*json).to_owned().into())
+            }
+            EventData::Binary(bytes) => BASE64_STANDARD.encode(bytes).into(),
+        }
+    }
+
+    /// Get the encoding of the data when represented as JSON (only binary data is encoded)
+    pub fn encoding(&self) -> Option<DataEncoding> {
+        match self {
+            EventData::Binary(_) => Some(DataEncoding::Base64),
+            _ => None,
+        }
+    }
+
+    /// Get the content type to store in the stream entry (`None` for plain text)
+    pub fn content_type(&self) -> Option<&'static str> {
+        match self {
+            EventData::Text(_) => None,
+            EventData::Json(_) => Some(constants::JSON_CONTENT_TYPE),
+            EventData::Binary(_) => Some(constants::BINARY_CONTENT_TYPE),
+        }
+    }
+
+    /// Get the raw bytes to store in the stream entry
+    pub fn to_bytes(&self) -> RedisBytes {
+        match self {
+            EventData::Text(text) | EventData::Json(text) => text.clone().into_inner(),
+            EventData::Binary(bytes) => bytes.clone(),
+        }
+    }
+}
+
+/// Serializes text as a JSON string, JSON data as the embedded JSON value, and binary data
+/// as a base64 string
+impl Serialize for EventData {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            EventData::Text(text) => serializer.serialize_str(text),
+            EventData::Json(json) => match serde_json::from_str::<&RawValue>(json) {
+                Ok(value) => value.serialize(serializer),
+                Err(_) => serializer.serialize_str(json),
+            },
+            EventData::Binary(bytes) => serializer.serialize_str(&BASE64_STANDARD.encode(bytes)),
+        }
+    }
+}
+
+/// Encoding of event data in JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DataEncoding {
+    /// Binary data encoded as a base64 string
+    Base64,
+}
+
+/// Settings for creating a new stream
+pub struct StreamSettings {
+    /// TTL of the stream in seconds
+    pub ttl: u32,
+    /// Approximate maximum number of events in the stream
+    pub max_len: u32,
+    /// Custom attributes stored in the stream metadata
+    pub attributes: HashMap<String, String>,
+    /// Assign sequential event IDs (`1-0`, `2-0`, ...) instead of timestamp IDs
+    pub sequential_ids: bool,
+    /// Refresh the TTL of the stream on every write
+    pub sliding_ttl: bool,
+    /// Time out the stream after this many seconds without writes
+    pub inactivity_timeout: Option<u32>,
+    /// Cancel the stream right away when a consumer requests cancellation
+    pub cancel_on_request: bool,
+    /// Rules for the events that can be added to the stream, as JSON
+    pub event_schema: Option<String>,
+}
+
+/// Why a stream was ended or cancelled, written as the JSON data of its terminal event and
+/// stored in its metadata
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FinishDetails {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+impl FinishDetails {
+    /// Serialize the details as JSON, or `None` if there is no reason or data
+    pub fn to_json(&self) -> Option<String> {
+        if self.reason.is_none() && self.data.is_none() {
+            return None;
+        }
+        serde_json::to_string(self).ok()
+    }
+}
+
+/// Stream metadata retrieved from the Redis metadata hash
+#[derive(Default)]
+pub struct StreamMeta {
+    /// Status of the stream, if it exists
+    pub status: Option<String>,
+    /// Custom attributes of the stream
+    pub attributes: HashMap<String, String>,
+    /// Reason and final data given when the stream was ended or cancelled
+    pub finish: Option<FinishDetails>,
+    /// Whether a consumer has requested cancellation of the stream
+    pub cancel_requested: bool,
+}
+impl FromValue for StreamMeta {
+    fn from_value(value: fred::prelude::Value) -> Result<Self, fred::prelude::Error> {
+        let fields: HashMap<String, String> = value.convert()?;
+        let mut meta = Self::default();
+        for (field, value) in fields {
+            if field == constants::META_STATUS_FIELD {
+                meta.status = Some(value);
+            } else if field == constants::META_FINISH_FIELD {
+                meta.finish = serde_json::from_str(&value).ok();
+            } else if field == constants::META_CANCEL_REQUESTED_FIELD {
+                meta.cancel_requested = value == "1";
+            } else if let Some(name) = field.strip_prefix(constants::META_ATTR_PREFIX) {
+                meta.attributes.insert(name.to_owned(), value);
+            }
+        }
+
+        Ok(meta)
+    }
+}
+
+impl StreamMeta {
+    /// Check if the stream is active
+    pub fn is_active(&self) -> bool {
+        self.has_status(constants::StreamStatus::Active)
+    }
+
+    /// Check if the stream exists with the given status
+    pub fn has_status(&self, status: constants::StreamStatus) -> bool {
+        self.status.as_deref().is_some_and(|s| *s == status)
+    }
+
+    /// Status of the stream, if it exists
+    pub fn stream_status(&self) -> Option<constants::StreamStatus> {
+        self.status
+            .as_deref()
+            .and_then(constants::StreamStatus::parse)
+    }
+
+    /// Check if the stream has all of the given attribute values
+    pub fn matches_attributes<'a>(
+        &self,
+        mut filters: impl Iterator<Item = (&'a str, &'a str)>,
+    ) -> bool {
+        filters.all(|(name, value)| self.attributes.get(name).is_some_and(|v| v == value))
+    }
+}
+
 /// JSON event
 #[derive(Serialize)]
 pub struct JsonEvent {
+    /// Key of the stream (only for multiplexed subscriptions)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<RedisStr>,
     /// ID of the event
     pub id: RedisStr,
     /// Name/type of the event
     pub event: RedisStr,
     /// Event data
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub data: Option<RedisStr>,
+    pub data: Option<EventData>,
+    /// Encoding of the data (only for binary data)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<DataEncoding>,
+}
+
+impl JsonEvent {
+    /// Convert this event into a WebSocket message. Binary data is sent as a binary frame,
+    /// with the JSON event (without data) as a header line followed by the raw bytes. Other
+    /// events are sent as JSON text frames.
+    pub fn into_ws_message(self) -> WsMessage {
+        match self.data {
+            Some(EventData::Binary(bytes)) => {
+                let header = JsonEvent {
+                    data: None,
+                    encoding: None,
+                    ..self
+                };
+                let mut frame = serde_json::to_vec(&header).unwrap_or_default();
+                frame.push(b'\n');
+                frame.extend_from_slice(&bytes);
+                WsMessage::binary(frame)
+            }
+            data => {
+                let text = serde_json::to_string(&JsonEvent { data, ..self }).unwrap_or_default();
+                WsMessage::text(text)
+            }
+        }
+    }
+}
+
+/// The last event IDs of the streams in a multiplexed subscription. Sent as the SSE event ID
+/// and parsed from the `Last-Event-ID` header, in the URL-encoded form of `key1=id1&key2=id2`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MultiCursor(Vec<(String, String)>);
+
+impl MultiCursor {
+    /// Parse the cursor from the `Last-Event-ID`, ignoring any invalid parts
+    pub fn parse(last_event_id: &str) -> Self {
+        let positions = last_event_id
+            .split('&')
+            .filter_map(|part| {
+                let (key, id) = part.split_once('=')?;
+                let key = urlencoding::decode(key).ok()?;
+                let id = urlencoding::decode(id).ok()?;
+                Some((key.into_owned(), id.into_owned()))
+            })
+            .collect();
+
+        Self(positions)
+    }
+
+    /// Get the last event ID of the stream
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, id)| id.as_str())
+    }
+
+    /// Set the last event ID of the stream
+    pub fn set(&mut self, key: &str, id: &str) {
+        match self.0.iter_mut().find(|(k, _)| k == key) {
+            Some((_, last_id)) => id.clone_into(last_id),
+            None => self.0.push((key.to_owned(), id.to_owned())),
+        }
+    }
+
+    /// Serialize the cursor for use as an SSE event ID
+    pub fn to_event_id(&self) -> String {
+        self.0
+            .iter()
+            .map(|(key, id)| format!("{}={}", urlencoding::encode(key), urlencoding::encode(id)))
+            .join("&")
+    }
 }
 
 /// Formatted stream event
@@ -90,16 +463,203 @@ pub struct StreamEvent {
     pub time: String,
     /// Name/type of the event
     pub event: String,
-    /// Event data
+    /// Event data (a string, or any JSON value). Binary data is a base64 string.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub data: Option<String>,
+    pub data: Option<serde_json::Value>,
+    /// Encoding of the data (only for binary data)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<DataEncoding>,
 }
 
 /// Event to ingest / add to the stream
-#[derive(Serialize, Deserialize, JsonSchema)]
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "AddEventInput")]
 pub struct AddEvent {
     /// Name/type of the event
     pub event: String,
     /// Event data
-    pub data: Option<String>,
+    pub data: Option<EventData>,
+    /// Key for skipping the event if it was already written
+    pub idempotency_key: Option<String>,
+    /// Explicit ID of the event (millisecond time and sequence number)
+    pub id: Option<(u64, u64)>,
+}
+
+/// Event to ingest / add to the stream
+#[derive(Deserialize, JsonSchema)]
+struct AddEventInput {
+    /// Name/type of the event
+    event: String,
+    /// Event data. Strings are stored as text, and any other JSON value is stored
+    /// as JSON and delivered to consumers as JSON (SSE consumers receive it serialized).
+    data: Option<serde_json::Value>,
+    /// Encoding of the data. With `base64`, the data must be a base64 string, which is
+    /// stored as raw bytes and delivered to WebSocket consumers as a binary frame.
+    encoding: Option<DataEncoding>,
+    /// Optional idempotency key (e.g. a producer ID and sequence number). The event is
+    /// skipped if an event with the same key was written to the stream within the dedup
+    /// window, so that retried writes don't add duplicate events.
+    idempotency_key: Option<String>,
+    /// Optional explicit event ID, as a `<millis>-<seq>` stream ID or just a number (for
+    /// `<number>-0`). It must be greater than the ID of the last event in the stream, and
+    /// can be used to correlate events with your own records (e.g. a row ID).
+    id: Option<String>,
+}
+
+/// Largest part of an explicit event ID, so IDs can be compared exactly in Lua scripts
+const MAX_ID_PART: u64 = (1 << 53) - 1;
+
+/// Documented with the schema of the JSON input
+impl JsonSchema for AddEvent {
+    fn schema_name() -> Cow<'static, str> {
+        "AddEvent".into()
+    }
+
+    fn json_schema(generator: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        AddEventInput::json_schema(generator)
+    }
+}
+
+impl TryFrom<AddEventInput> for AddEvent {
+    type Error = String;
+
+    fn try_from(input: AddEventInput) -> Result<Self, Self::Error> {
+        let data = match (input.data, input.encoding) {
+            (None | Some(serde_json::Value::Null), _) => None,
+            (Some(serde_json::Value::String(data)), Some(DataEncoding::Base64)) => {
+                let bytes = BASE64_STANDARD
+                    .decode(data)
+                    .map_err(|err| format!("invalid base64 data: {err}"))?;
+                Some(EventData::Binary(bytes.into()))
+            }
+            (Some(_), Some(DataEncoding::Base64)) => {
+                return Err("base64 data must be a string".to_owned());
+            }
+            (Some(serde_json::Value::String(text)), None) => Some(EventData::Text(text.into())),
+            (Some(value), None) => Some(EventData::Json(value.to_string().into())),
+        };
+
+        let id = match input.id.as_deref() {
+            Some(id) => match util::parse_range_bound(id, false) {
+                Some(id @ (millis, seq))
+                    if id > (0, 0) && millis <= MAX_ID_PART && seq <= MAX_ID_PART =>
+                {
+                    Some(id)
+                }
+                _ => return Err(format!("invalid event ID: {id}")),
+            },
+            None => None,
+        };
+
+        Ok(Self {
+            event: input.event,
+            data,
+            idempotency_key: input.idempotency_key.filter(|key| !key.is_empty()),
+            id,
+        })
+    }
+}
+
+impl AddEvent {
+    /// Convert the event into a stream entry with the given ID
+    pub fn to_entry(&self, id: RedisStr) -> StreamEntry {
+        let mut entry = StreamEntry::new(id, &self.event);
+        if let Some(data) = &self.data {
+            entry
+                .fields
+                .push((constants::DATA_KEY.into(), data.to_bytes()));
+            if let Some(content_type) = data.content_type() {
+                entry.fields.push((
+                    constants::CONTENT_TYPE_KEY.into(),
+                    RedisBytes::from_static(content_type.as_bytes()),
+                ));
+            }
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_data_is_embedded() {
+        let events: Vec<AddEvent> = serde_json::from_value(serde_json::json!([
+            { "event": "text", "data": "{\"a\":1}" },
+            { "event": "json", "data": { "a": [1, true] } },
+            { "event": "none", "data": null },
+        ]))
+        .unwrap();
+        let [text, json, none] = events
+            .iter()
+            .map(|event| event.to_entry("1-0".into()))
+            .collect::<Vec<_>>()
+            .try_into()
+            .unwrap_or_else(|_| panic!("should have 3 entries"));
+
+        let text_json = serde_json::to_value(text.clone().into_json()).unwrap();
+        assert_eq!(text_json["data"], "{\"a\":1}");
+        let json_json = serde_json::to_value(json.clone().into_json()).unwrap();
+        assert_eq!(json_json["data"], serde_json::json!({ "a": [1, true] }));
+        let none_json = serde_json::to_value(none.into_json()).unwrap();
+        assert!(none_json.get("data").is_none());
+
+        let (_, _, data) = json.into_parts();
+        assert_eq!(data.unwrap().to_text(), r#"{"a":[1,true]}"#);
+        let (_, _, data) = text.into_parts();
+        assert_eq!(data.unwrap().into_value(), "{\"a\":1}");
+    }
+
+    #[test]
+    fn binary_data_is_base64() {
+        let event: AddEvent = serde_json::from_value(serde_json::json!({
+            "event": "audio", "data": "AAH/", "encoding": "base64"
+        }))
+        .unwrap();
+        let entry = event.to_entry("1-0".into());
+
+        let json = serde_json::to_value(entry.clone().into_json()).unwrap();
+        assert_eq!(json["data"], "AAH/");
+        assert_eq!(json["encoding"], "base64");
+
+        let WsMessage::Binary(frame) = entry.clone().into_tagged_ws_message("k") else {
+            panic!("should be a binary frame");
+        };
+        let (header, payload) = frame.split_at(frame.iter().position(|b| *b == b'\n').unwrap());
+        let header: serde_json::Value = serde_json::from_slice(header).unwrap();
+        assert_eq!(
+            header,
+            serde_json::json!({ "key": "k", "id": "1-0", "event": "audio" })
+        );
+        assert_eq!(&payload[1..], [0, 1, 255]);
+
+        let (_, _, data) = entry.into_parts();
+        assert_eq!(data.unwrap().to_text(), "AAH/");
+
+        for invalid in [
+            serde_json::json!({ "event": "audio", "data": "not base64!", "encoding": "base64" }),
+            serde_json::json!({ "event": "audio", "data": [1], "encoding": "base64" }),
+        ] {
+            assert!(serde_json::from_value::<AddEvent>(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn multi_cursor_round_trip() {
+        let mut cursor = MultiCursor::default();
+        cursor.set("user:42:chat 1", "1700000000000-0");
+        cursor.set("a&b=c", "1700000000001-0");
+        cursor.set("user:42:chat 1", "1700000000002-1");
+
+        let parsed = MultiCursor::parse(&cursor.to_event_id());
+        assert_eq!(parsed, cursor);
+        assert_eq!(parsed.get("user:42:chat 1"), Some("1700000000002-1"));
+        assert_eq!(parsed.get("a&b=c"), Some("1700000000001-0"));
+        assert_eq!(parsed.get("other"), None);
+        assert_eq!(
+            MultiCursor::parse("1700000000000-0"),
+            MultiCursor::default()
+        );
+    }
 }
@@ -1,10 +1,10 @@
-use crate::redis::{constants, types::RedisEntry};
+use crate::redis::{constants, types::StreamEntry};
 
 /// Convert stream events to JSON
-pub fn stream_entries_to_json(entries: Vec<RedisEntry>) -> String {
+pub fn stream_entries_to_json(entries: Vec<StreamEntry>) -> String {
     let entries_json = entries
         .into_iter()
-        .map(RedisEntry::into_json)
+        .map(StreamEntry::into_json)
         .collect::<Vec<_>>();
 
     serde_json::to_string(&serde_json::json!({
@@ -13,3 +13,70 @@ pub fn stream_entries_to_json(entries: Vec<RedisEntry>) -> String {
     }))
     .unwrap_or_default()
 }
+
+/// Convert events from several streams to JSON, tagged with their stream key
+pub fn tagged_stream_entries_to_json(entries: Vec<(String, StreamEntry)>) -> String {
+    let entries_json = entries
+        .into_iter()
+        .map(|(key, entry)| entry.into_tagged_json(&key))
+        .collect::<Vec<_>>();
+
+    serde_json::to_string(&serde_json::json!({
+        constants::EVENT_KEY: "prev_events",
+        constants::DATA_KEY: entries_json,
+    }))
+    .unwrap_or_default()
+}
+
+/// Create a JSON error event, tagged with the stream key if the error is specific to one stream
+pub fn tagged_error_json(key: Option<&str>, error: &str) -> String {
+    let mut error_event = serde_json::json!({
+        constants::EVENT_KEY: constants::ERROR,
+        constants::DATA_KEY: error,
+    });
+    if let Some(key) = key {
+        error_event["key"] = key.into();
+    }
+
+    error_event.to_string()
+}
+
+/// Parse the millisecond time and sequence number of a stream entry ID, for ordering entries
+pub fn parse_entry_id(id: &str) -> (u64, u64) {
+    let (millis, seq) = id.split_once('-').unwrap_or((id, "0"));
+    (
+        millis.parse().unwrap_or_default(),
+        seq.parse().unwrap_or_default(),
+    )
+}
+
+/// Format the millisecond time and sequence number as a stream entry ID
+pub fn format_entry_id((millis, seq): (u64, u64)) -> String {
+    format!("{millis}-{seq}")
+}
+
+/// Parse an event ID given as a range bound, either a full `<millis>-<seq>` ID or just the
+/// millisecond time (which includes all sequence numbers at that time). Returns `None` if invalid.
+pub fn parse_range_bound(id: &str, is_end: bool) -> Option<(u64, u64)> {
+    match id.split_once('-') {
+        Some((millis, seq)) => Some((millis.parse().ok()?, seq.parse().ok()?)),
+        None => Some((id.parse().ok()?, if is_end { u64::MAX } else { 0 })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_bounds() {
+        assert_eq!(parse_range_bound("1700-3", false), Some((1700, 3)));
+        assert_eq!(parse_range_bound("1700-3", true), Some((1700, 3)));
+        assert_eq!(parse_range_bound("1700", false), Some((1700, 0)));
+        assert_eq!(parse_range_bound("1700", true), Some((1700, u64::MAX)));
+        assert_eq!(parse_range_bound("", false), None);
+        assert_eq!(parse_range_bound("1700-", false), None);
+        assert_eq!(parse_range_bound("abc-1", true), None);
+        assert_eq!(format_entry_id((1700, 3)), "1700-3");
+    }
+}
@@ -7,7 +7,11 @@ use axum_plugin::{AppState, TypeMap};
 use crate::{
     auth::{ClientToken, TokenEncryption},
     config::AppConfig,
-    redis::{ExclusiveClientManager, StreamService},
+    redis::{RevocationListener, StreamService},
+    storage::{
+        EventValidators, ExpiryWatcher, Fanout, StreamHistory, StreamReaders, StreamStorage,
+    },
+    webhooks::Webhooks,
 };
 
 /// App state stored in the Axum router
@@ -18,8 +22,12 @@ pub struct AppState(Arc<AppStateInner>);
 pub struct AppStateInner {
     pub config: Arc<AppConfig>,
     pub encryptor: TokenEncryption,
-    pub static_pool: fred::clients::Pool,
-    pub exclusive_clients: ExclusiveClientManager,
+    pub storage: Arc<dyn StreamStorage>,
+    pub revocations: RevocationListener,
+    pub stream_readers: StreamReaders,
+    pub event_validators: EventValidators,
+    pub webhooks: Webhooks,
+    pub expiry_watcher: ExpiryWatcher,
 }
 
 impl Deref for AppState {
@@ -43,6 +51,12 @@ impl AppState {
         ClientToken::new(&self.encryptor)
     }
     pub fn streams(&self) -> StreamService {
-        StreamService::new(Arc::clone(&self.config))
+        StreamService::new(&self.config)
+    }
+    pub fn history(&self) -> StreamHistory {
+        StreamHistory::new(Arc::clone(&self.storage))
+    }
+    pub fn fanout(&self) -> Fanout<'_> {
+        Fanout::new(&self.stream_readers, &self.storage)
     }
 }
@@ -0,0 +1,24 @@
+use crate::error::AppError;
+
+/// Result type for storage operations
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// Error while reading/writing to the storage backend
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("Redis client error: {0}")]
+    Redis(#[from] fred::prelude::Error),
+    #[error("Stream not found")]
+    StreamNotFound,
+    #[error("Storage is shutting down")]
+    Shutdown,
+}
+
+impl From<StorageError> for AppError {
+    fn from(error: StorageError) -> Self {
+        match error {
+            StorageError::StreamNotFound => Self::not_found("stream not found"),
+            err => Self::internal(err.into()),
+        }
+    }
+}
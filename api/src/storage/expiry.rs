@@ -0,0 +1,61 @@
+//! Background watcher that expires the active streams whose TTL has passed, and times out
+//! the ones without writes for their inactivity timeout
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+
+use crate::{
+    redis::StreamStatus,
+    storage::StreamStorage,
+    webhooks::{WebhookEvent, Webhooks},
+};
+
+/// Interval between checks for expired streams
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Periodically expires the active streams whose TTL has passed (or times out the inactive
+/// ones), so that live consumers receive the `expired` or `timed_out` terminal event, and
+/// sends the `stream.expired` and `stream.timed_out` webhooks. Every server instance runs a
+/// watcher, and each stream is finished by only one of them.
+#[derive(Default)]
+pub struct ExpiryWatcher(Mutex<Option<JoinHandle<()>>>);
+
+impl ExpiryWatcher {
+    /// Start checking for expired streams in the background
+    pub fn start(&self, storage: Arc<dyn StreamStorage>, webhooks: Webhooks) {
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                match storage.expire_streams().await {
+                    Ok(finished) => {
+                        for (key, status) in finished {
+                            tracing::debug!("Stream {key} finished as {}", status.as_str());
+                            let event = match status {
+                                StreamStatus::TimedOut => WebhookEvent::TimedOut,
+                                _ => WebhookEvent::Expired,
+                            };
+                            webhooks.send(event, &key);
+                        }
+                    }
+                    Err(err) => tracing::warn!("Failed to expire streams: {err}"),
+                }
+            }
+        });
+        if let Some(previous) = self.0.lock().unwrap().replace(handle) {
+            previous.abort();
+        }
+    }
+
+    /// Stop checking for expired streams
+    pub fn stop(&self) {
+        if let Some(handle) = self.0.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! Shared readers that fan out the live events of each stream to all local subscribers
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use futures::{Stream, StreamExt};
+use tokio::sync::{
+    Notify,
+    broadcast::{self, error::RecvError},
+};
+
+use crate::{
+    redis::{MultiCursor, RedisStr, SseEvent, StreamEntry, WsMessage, constants, util},
+    storage::{EventFilter, StorageResult, StreamHistory, StreamReader, StreamStorage},
+};
+
+/// Capacity of the in-process channel for each stream. Subscribers that fall further
+/// behind catch up by reading the missed events from storage.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Error sent to subscribers if the shared reader stops before the stream ends
+const READER_STOPPED: &str = "stream reader stopped";
+
+/// An item broadcast by the shared reader of a stream
+#[derive(Clone)]
+enum FanoutItem {
+    Entry(StreamEntry),
+    Error(String),
+}
+
+/// Handle to the shared reader of a stream
+#[derive(Clone)]
+struct SharedReader {
+    sender: broadcast::Sender<FanoutItem>,
+    /// Notified when a subscriber leaves, so the reader can stop if it was the last one
+    unsubscribed: Arc<Notify>,
+}
+
+/// The shared readers of the currently subscribed streams, by stream key
+type Readers = Arc<Mutex<HashMap<String, SharedReader>>>;
+
+/// Registry of the shared stream readers on this server instance
+#[derive(Default)]
+pub struct StreamReaders(Readers);
+
+impl StreamReaders {
+    /// Number of streams currently being read
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Service for subscribing to the live events of streams. Each stream is read by one blocking
+/// reader with an exclusive connection, which broadcasts the events to all local subscribers.
+pub struct Fanout<'a> {
+    readers: &'a StreamReaders,
+    storage: &'a Arc<dyn StreamStorage>,
+}
+
+impl<'a> Fanout<'a> {
+    pub fn new(readers: &'a StreamReaders, storage: &'a Arc<dyn StreamStorage>) -> Self {
+        Self { readers, storage }
+    }
+
+    /// Subscribe to the live events of the stream after the given event ID, starting the shared
+    /// reader of the stream if needed. Will return `None` if there are too many connections.
+    pub async fn subscribe(
+        &self,
+        key: &str,
+        last_event_id: &str,
+    ) -> StorageResult<Option<Subscription>> {
+        if let Some(subscription) = self.join(key, last_event_id) {
+            return Ok(Some(subscription));
+        }
+
+        let Some(connection) = self.storage.connect().await? else {
+            return Ok(None);
+        };
+        let mut readers = self.readers.0.lock().unwrap();
+        if let Some(reader) = readers.get(key) {
+            // Another subscriber started a reader in the meantime
+            return Ok(Some(self.subscription(key, reader, last_event_id)));
+        }
+
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let reader = SharedReader {
+            sender,
+            unsubscribed: Arc::new(Notify::new()),
+        };
+        readers.insert(key.to_owned(), reader.clone());
+        let subscription = self.subscription(key, &reader, last_event_id);
+
+        // The reader starts after the first subscriber's last event ID, and later
+        // subscribers catch up on anything before their subscription from storage
+        let stream_reader = StreamReader::new(Arc::clone(self.storage), connection);
+        tokio::spawn(run_reader(
+            stream_reader,
+            key.to_owned(),
+            RedisStr::from(last_event_id),
+            reader,
+            Arc::clone(&self.readers.0),
+        ));
+
+        Ok(Some(subscription))
+    }
+
+    /// Subscribe to the already running reader of the stream, if any
+    fn join(&self, key: &str, last_event_id: &str) -> Option<Subscription> {
+        let readers = self.readers.0.lock().unwrap();
+        let reader = readers.get(key)?;
+
+        Some(self.subscription(key, reader, last_event_id))
+    }
+
+    fn subscription(&self, key: &str, reader: &SharedReader, last_event_id: &str) -> Subscription {
+        Subscription {
+            key: key.to_owned(),
+            last_event_id: RedisStr::from(last_event_id),
+            receiver: Some(reader.sender.subscribe()),
+            unsubscribed: Arc::clone(&reader.unsubscribed),
+            history: StreamHistory::new(Arc::clone(self.storage)),
+        }
+    }
+}
+
+/// Read the stream and broadcast its entries to the subscribers, until the stream ends
+/// or the last subscriber leaves
+async fn run_reader(
+    stream_reader: StreamReader,
+    key: String,
+    mut last_event_id: RedisStr,
+    reader: SharedReader,
+    readers: Readers,
+) {
+    loop {
+        let result = {
+            let mut read = std::pin::pin!(stream_reader.next_stream_events(&key, &last_event_id));
+            loop {
+                tokio::select! {
+                    result = &mut read => break Some(result),
+                    () = reader.unsubscribed.notified() => {
+                        if remove_reader(&readers, &key, &reader, false) {
+                            break None;
+                        }
+                    }
+                }
+            }
+        };
+
+        let finished = match result {
+            // No subscribers left, so cancel the blocking read
+            None => return,
+            Some(Ok(entries)) => {
+                let mut is_end = false;
+                for entry in entries {
+                    last_event_id = entry.id.clone();
+                    is_end = entry.is_end_event();
+                    let _ = reader.sender.send(FanoutItem::Entry(entry));
+                    if is_end {
+                        break;
+                    }
+                }
+                is_end
+            }
+            Some(Err(err)) => {
+                let _ = reader.sender.send(FanoutItem::Error(err.to_string()));
+                true
+            }
+        };
+        if remove_reader(&readers, &key, &reader, finished) {
+            return;
+        }
+    }
+}
+
+/// Remove the shared reader from the registry if it's finished or has no subscribers left.
+/// Returns whether the reader was removed.
+fn remove_reader(readers: &Readers, key: &str, reader: &SharedReader, finished: bool) -> bool {
+    // Subscribers are added while holding the lock, so none can join during this check
+    let mut readers_lock = readers.lock().unwrap();
+    if !finished && reader.sender.receiver_count() > 0 {
+        return false;
+    }
+    if readers_lock
+        .get(key)
+        .is_some_and(|r| r.sender.same_channel(&reader.sender))
+    {
+        readers_lock.remove(key);
+    }
+
+    true
+}
+
+/// A subscription to the live events of a stream via its shared reader
+pub struct Subscription {
+    key: String,
+    last_event_id: RedisStr,
+    receiver: Option<broadcast::Receiver<FanoutItem>>,
+    unsubscribed: Arc<Notify>,
+    history: StreamHistory,
+}
+
+impl Subscription {
+    /// Get the live events of the stream in SSE format
+    pub fn into_sse_events(self, filter: EventFilter) -> impl Stream<Item = SseEvent> {
+        self.into_entries(filter).map(|item| match item {
+            Ok(entry) => entry.into_sse_event(),
+            Err(error) => SseEvent::default().event(constants::ERROR).data(error),
+        })
+    }
+
+    /// Get the live events of the stream as JSON-serialized WebSocket messages
+    pub fn into_ws_events(self, filter: EventFilter) -> impl Stream<Item = WsMessage> {
+        self.into_entries(filter)
+            .map(|item| match item {
+                Ok(entry) => entry.into_ws_message(),
+                Err(error) => WsMessage::text(util::tagged_error_json(None, &error)),
+            })
+            .chain(futures::stream::once(async { WsMessage::Close(None) }))
+    }
+
+    /// Merge the subscriptions to several streams into SSE events tagged with their stream key,
+    /// with the cursor of all streams as the event ID
+    pub fn merge_sse_events(
+        subscriptions: Vec<Self>,
+        cursor: MultiCursor,
+        filter: EventFilter,
+    ) -> impl Stream<Item = SseEvent> {
+        Self::merge_entries(subscriptions, filter).scan(cursor, |cursor, (key, item)| {
+            let event = match item {
+                Ok(entry) => {
+                    cursor.set(&key, &entry.id);
+                    entry.into_tagged_sse_event(&key, cursor)
+                }
+                Err(error) => SseEvent::default()
+                    .event(constants::ERROR)
+                    .data(util::tagged_error_json(Some(&key), &error)),
+            };
+            futures::future::ready(Some(event))
+        })
+    }
+
+    /// Merge the subscriptions to several streams into JSON-serialized WebSocket messages
+    /// tagged with their stream key
+    pub fn merge_ws_events(
+        subscriptions: Vec<Self>,
+        filter: EventFilter,
+    ) -> impl Stream<Item = WsMessage> {
+        Self::merge_entries(subscriptions, filter)
+            .map(|(key, item)| match item {
+                Ok(entry) => entry.into_tagged_ws_message(&key),
+                Err(error) => WsMessage::text(util::tagged_error_json(Some(&key), &error)),
+            })
+            .chain(futures::stream::once(async { WsMessage::Close(None) }))
+    }
+
+    /// Interleave the live entries of several streams as they arrive, tagged with their stream key
+    fn merge_entries(
+        subscriptions: Vec<Self>,
+        filter: EventFilter,
+    ) -> impl Stream<Item = (String, Result<StreamEntry, String>)> {
+        futures::stream::select_all(subscriptions.into_iter().map(|subscription| {
+            let key = subscription.key.clone();
+            subscription
+                .into_entries(filter.clone())
+                .map(move |item| (key.clone(), item))
+                .boxed()
+        }))
+    }
+
+    /// Get the live entries of the stream that match the filter until it ends. Missed entries
+    /// (from before the subscription, or when lagging behind the reader) are read from storage.
+    fn into_entries(self, filter: EventFilter) -> impl Stream<Item = Result<StreamEntry, String>> {
+        self.into_all_entries().filter(move |item| {
+            let matches = item.as_ref().map_or(true, |entry| filter.matches(entry));
+            futures::future::ready(matches)
+        })
+    }
+
+    /// Get all of the live entries of the stream until it ends
+    fn into_all_entries(mut self) -> impl Stream<Item = Result<StreamEntry, String>> {
+        async_stream::stream! {
+            let Some(receiver) = self.receiver.as_mut() else {
+                return;
+            };
+            let mut last_event_id = self.last_event_id.clone();
+            let mut last_id = util::parse_entry_id(&last_event_id);
+            let mut catch_up = true;
+            'read: loop {
+                if catch_up {
+                    catch_up = false;
+                    match self.history.entries_after(&self.key, &last_event_id).await {
+                        Ok(entries) => {
+                            for entry in entries {
+                                last_id = entry.id_parts();
+                                last_event_id = entry.id.clone();
+                                let is_end = entry.is_end_event();
+                                yield Ok(entry);
+                                if is_end {
+                                    break 'read;
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            yield Err(err.to_string());
+                            break;
+                        }
+                    }
+                }
+
+                match receiver.recv().await {
+                    Ok(FanoutItem::Entry(entry)) => {
+                        // Skip entries already read while catching up
+                        if entry.id_parts() <= last_id {
+                            continue;
+                        }
+                        last_id = entry.id_parts();
+                        last_event_id = entry.id.clone();
+                        let is_end = entry.is_end_event();
+                        yield Ok(entry);
+                        if is_end {
+                            break;
+                        }
+                    }
+                    Ok(FanoutItem::Error(error)) => {
+                        yield Err(error);
+                        break;
+                    }
+                    Err(RecvError::Lagged(_)) => catch_up = true,
+                    Err(RecvError::Closed) => {
+                        // The reader stopped before this subscriber caught up (e.g. the stream
+                        // just ended), so read any remaining entries from storage
+                        match self.history.entries_after(&self.key, &last_event_id).await {
+                            Ok(entries) => {
+                                let is_end = entries.last().is_some_and(StreamEntry::is_end_event);
+                                for entry in entries {
+                                    yield Ok(entry);
+                                }
+                                if !is_end {
+                                    yield Err(READER_STOPPED.to_owned());
+                                }
+                            }
+                            Err(err) => yield Err(err.to_string()),
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // Drop the receiver before notifying, so the reader sees the updated subscriber count
+        drop(self.receiver.take());
+        self.unsubscribed.notify_one();
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+
+use crate::redis::StreamEntry;
+
+/// Filter for the events delivered to consumers, parsed from a comma-separated list of
+/// event names to include, and/or names prefixed with `-` to exclude (e.g. `token,usage`
+/// or `-usage`). Terminal events (`end`/`cancel`) are always delivered.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    include: HashSet<String>,
+    exclude: HashSet<String>,
+}
+
+impl EventFilter {
+    /// Parse the filter from a comma-separated list of event names
+    pub fn parse(events: &str) -> Self {
+        let mut filter = Self::default();
+        for name in events
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match name.strip_prefix('-') {
+                Some(name) => filter.exclude.insert(name.to_owned()),
+                None => filter.include.insert(name.to_owned()),
+            };
+        }
+        filter
+    }
+
+    /// Check if the entry should be delivered
+    pub fn matches(&self, entry: &StreamEntry) -> bool {
+        if self.include.is_empty() && self.exclude.is_empty() || entry.is_end_event() {
+            return true;
+        }
+        match entry.event() {
+            Some(event) => {
+                (self.include.is_empty() || self.include.contains(event))
+                    && !self.exclude.contains(event)
+            }
+            None => self.include.is_empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn include_and_exclude_events() {
+        let entries: Vec<_> = ["start", "token", "tool_call", "usage", "end"]
+            .into_iter()
+            .map(|event| StreamEntry::new("1-0".into(), event))
+            .collect();
+        let matching = |filter: &EventFilter| -> Vec<_> {
+            entries
+                .iter()
+                .filter(|entry| filter.matches(entry))
+                .filter_map(StreamEntry::event)
+                .collect()
+        };
+
+        assert_eq!(matching(&EventFilter::parse("")).len(), 5);
+        assert_eq!(
+            matching(&EventFilter::parse("token, usage")),
+            ["token", "usage", "end"]
+        );
+        assert_eq!(
+            matching(&EventFilter::parse("-usage,-start")),
+            ["token", "tool_call", "end"]
+        );
+        assert_eq!(matching(&EventFilter::parse("token,-token")), ["end"]);
+        assert_eq!(matching(&EventFilter::parse("-end")).len(), 5);
+    }
+}
@@ -0,0 +1,198 @@
+use std::sync::Arc;
+
+use time::{UtcDateTime, format_description::well_known::Rfc3339};
+
+use crate::{
+    redis::{EventData, MultiCursor, RedisStr, SseEvent, StreamEntry, StreamEvent, util},
+    storage::{EventFilter, PageQuery, StorageError, StorageResult, StreamStorage},
+};
+
+/// Retrieves the previous events of streams. Uses quick range reads only, so it doesn't
+/// need an exclusive connection.
+pub struct StreamHistory {
+    storage: Arc<dyn StreamStorage>,
+}
+
+impl StreamHistory {
+    pub fn new(storage: Arc<dyn StreamStorage>) -> Self {
+        Self { storage }
+    }
+
+    /// Retrieve the previous events of the stream in SSE format, along with the last event ID
+    /// and whether the stream has ended
+    pub async fn prev_sse_events(
+        &self,
+        key: &str,
+        start_event_id: Option<&str>,
+        filter: &EventFilter,
+    ) -> StorageResult<(Vec<SseEvent>, RedisStr, bool)> {
+        let (prev_events, last_event_id, is_end) =
+            self.get_prev_events(key, start_event_id).await?;
+        let sse_events = prev_events
+            .into_iter()
+            .filter(|entry| filter.matches(entry))
+            .map(StreamEntry::into_sse_event)
+            .collect();
+
+        Ok((sse_events, last_event_id, is_end))
+    }
+
+    /// Retrieve the previous events of the stream as stringified JSON, along with the last
+    /// event ID and whether the stream has ended
+    pub async fn prev_json_events(
+        &self,
+        key: &str,
+        start_event_id: Option<&str>,
+        filter: &EventFilter,
+    ) -> StorageResult<(String, RedisStr, bool)> {
+        let (mut prev_events, last_event_id, is_end) =
+            self.get_prev_events(key, start_event_id).await?;
+        prev_events.retain(|entry| filter.matches(entry));
+        let json_events = util::stream_entries_to_json(prev_events);
+
+        Ok((json_events, last_event_id, is_end))
+    }
+
+    /// Retrieve a page of the stream's events in a human-readable format for returning via API,
+    /// along with the ID of the first event of the next page (if there are more events)
+    pub async fn formatted_events_page(
+        &self,
+        key: &str,
+        mut query: PageQuery,
+    ) -> StorageResult<(Vec<StreamEvent>, Option<String>)> {
+        let limit = query.count;
+        query.count += 1; // Get one extra entry to check for a next page
+        let mut entries = self.storage.range_page(key, &query).await?;
+        let next_cursor = match entries.len() > limit {
+            true => entries.pop().map(|entry| (*entry.id).to_owned()),
+            false => None,
+        };
+        let events = entries.into_iter().filter_map(format_entry).collect();
+
+        Ok((events, next_cursor))
+    }
+
+    /// Retrieve the entries of the stream after the given event ID
+    pub async fn entries_after(
+        &self,
+        key: &str,
+        last_event_id: &str,
+    ) -> StorageResult<Vec<StreamEntry>> {
+        let (entries, _, _) = self.get_prev_events(key, Some(last_event_id)).await?;
+        Ok(entries)
+    }
+
+    /// Returns a tuple containing the previous events in the stream, the last event ID,
+    /// and a boolean indicating if the stream has already ended.
+    async fn get_prev_events(
+        &self,
+        key: &str,
+        start_event_id: Option<&str>,
+    ) -> StorageResult<(Vec<StreamEntry>, RedisStr, bool)> {
+        let start_event_id = start_event_id.unwrap_or("0-0");
+        let range = self
+            .storage
+            .range(&[(key, start_event_id)])
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(StorageError::StreamNotFound)?;
+        let last_event_id = range
+            .entries
+            .last()
+            .map(|entry| entry.id.to_owned())
+            .unwrap_or_else(|| start_event_id.into());
+
+        Ok((range.entries, last_event_id, !range.is_active))
+    }
+
+    /// Retrieve the previous events of several streams in SSE format (tagged with their stream
+    /// key and ordered by ID), along with the cursor and the keys of the streams still active
+    pub async fn prev_multi_sse_events(
+        &self,
+        keys: &[String],
+        start_cursor: MultiCursor,
+        filter: &EventFilter,
+    ) -> StorageResult<(Vec<SseEvent>, MultiCursor, Vec<String>)> {
+        let (prev_events, cursor, active_keys) = self
+            .get_prev_multi_events(keys, start_cursor.clone())
+            .await?;
+        let mut event_cursor = start_cursor;
+        let sse_events = prev_events
+            .into_iter()
+            .filter_map(|(key, entry)| {
+                event_cursor.set(&key, &entry.id);
+                filter
+                    .matches(&entry)
+                    .then(|| entry.into_tagged_sse_event(&key, &event_cursor))
+            })
+            .collect();
+
+        Ok((sse_events, cursor, active_keys))
+    }
+
+    /// Retrieve the previous events of several streams as stringified JSON (tagged with their
+    /// stream key and ordered by ID), along with the cursor and the keys of the streams still active
+    pub async fn prev_multi_json_events(
+        &self,
+        keys: &[String],
+        start_cursor: MultiCursor,
+        filter: &EventFilter,
+    ) -> StorageResult<(String, MultiCursor, Vec<String>)> {
+        let (mut prev_events, cursor, active_keys) =
+            self.get_prev_multi_events(keys, start_cursor).await?;
+        prev_events.retain(|(_, entry)| filter.matches(entry));
+        let json_events = util::tagged_stream_entries_to_json(prev_events);
+
+        Ok((json_events, cursor, active_keys))
+    }
+
+    /// Returns the previous events of several streams tagged with their stream key and
+    /// ordered by ID, the updated cursor, and the keys of the streams that are still active.
+    async fn get_prev_multi_events(
+        &self,
+        keys: &[String],
+        mut cursor: MultiCursor,
+    ) -> StorageResult<(Vec<(String, StreamEntry)>, MultiCursor, Vec<String>)> {
+        let streams: Vec<(&str, &str)> = keys
+            .iter()
+            .map(|key| (key.as_str(), cursor.get(key).unwrap_or("0-0")))
+            .collect();
+        let ranges = self.storage.range(&streams).await?;
+
+        let mut prev_events = Vec::new();
+        let mut active_keys = Vec::with_capacity(keys.len());
+        for (key, range) in keys.iter().zip(ranges) {
+            if let Some(last_entry) = range.entries.last() {
+                cursor.set(key, &last_entry.id);
+            }
+            if range.is_active {
+                active_keys.push(key.to_owned());
+            }
+            prev_events.extend(
+                range
+                    .entries
+                    .into_iter()
+                    .map(|entry| (key.to_owned(), entry)),
+            );
+        }
+        prev_events.sort_by_key(|(_, entry)| entry.id_parts());
+
+        Ok((prev_events, cursor, active_keys))
+    }
+}
+
+/// Convert the entry into a human-readable event
+fn format_entry(entry: StreamEntry) -> Option<StreamEvent> {
+    let (id, event, data) = entry.into_parts();
+    let unix_millis: i64 = id.split('-').next().unwrap_or_default().parse().ok()?;
+    let date_time = UtcDateTime::from_unix_timestamp(unix_millis / 1000).ok()?;
+
+    Some(StreamEvent {
+        id: (*id).to_owned(),
+        time: date_time.format(&Rfc3339).ok()?,
+        event: (*event).to_owned(),
+        encoding: data.as_ref().and_then(EventData::encoding),
+        data: data.map(EventData::into_value),
+    })
+}
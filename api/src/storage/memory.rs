@@ -0,0 +1,1451 @@
+//! In-memory storage of streams, for single-node deployments and testing
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{FutureExt, future::BoxFuture};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast, watch};
+
+use crate::{
+    redis::{
+        AddEvent, EventData, FinishDetails, RedisStr, Revocation, StreamEntry, StreamMeta,
+        StreamSettings, StreamStatus, constants, util,
+    },
+    storage::{
+        CancelRequestOutcome, PageQuery, StorageError, StorageResult, StreamConnection,
+        StreamListing, StreamRange, StreamStorage, WriteOutcome, WriteResult,
+    },
+};
+
+/// Stream storage in the memory of this server instance. Streams are lost on restart, and
+/// can't be shared with other instances.
+pub struct MemoryStorage {
+    inner: Arc<Inner>,
+    semaphore: Arc<Semaphore>,
+    wait_timeout: Duration,
+}
+
+struct Inner {
+    state: Mutex<MemoryState>,
+    /// Notified of every write, and set to `true` on shutdown
+    written: watch::Sender<bool>,
+    /// Forwards token revocations to the live consumers
+    revocations: broadcast::Sender<Revocation>,
+    dedup_window: Duration,
+    batch_size: u32,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    streams: HashMap<String, MemoryStream>,
+    /// Expiration of the individually revoked token IDs
+    revoked_tokens: HashMap<String, Instant>,
+    /// Revocation time (unix milliseconds) and expiration of the revoked stream keys/prefixes
+    revoked_keys: HashMap<String, (i64, Instant)>,
+    /// How long expired streams are kept with their `expired` event
+    expired_retention: Duration,
+    /// Keys and final status of the streams that expired or timed out since they were last
+    /// returned by `expire_streams`
+    finished_keys: Vec<(String, StreamStatus)>,
+}
+
+struct MemoryStream {
+    entries: VecDeque<StreamEntry>,
+    last_id: (u64, u64),
+    status: StreamStatus,
+    ttl: u32,
+    max_len: u32,
+    attributes: HashMap<String, String>,
+    /// Whether the entries get sequential IDs instead of timestamp IDs
+    sequential: bool,
+    /// Whether the TTL is refreshed on every write
+    sliding_ttl: bool,
+    /// Time out the stream after this long without writes
+    inactivity_timeout: Option<Duration>,
+    last_write: Instant,
+    /// Rules for the events that can be added, as JSON
+    event_schema: Option<String>,
+    /// Reason and final data given when the stream was ended or cancelled
+    finish: Option<FinishDetails>,
+    /// Whether the stream is cancelled right away when a consumer requests cancellation
+    cancel_on_request: bool,
+    /// Whether a consumer has requested cancellation
+    cancel_requested: bool,
+    expires_at: Instant,
+    /// Write time of the recent idempotency keys
+    idempotency_keys: HashMap<String, Instant>,
+}
+
+impl MemoryStorage {
+    pub fn new(
+        max_clients: usize,
+        wait_timeout_secs: u32,
+        dedup_window_secs: u32,
+        expired_retention_secs: u32,
+        batch_size: u32,
+        revocations: broadcast::Sender<Revocation>,
+    ) -> Self {
+        let (written, _) = watch::channel(false);
+        let state = MemoryState {
+            expired_retention: Duration::from_secs(expired_retention_secs.into()),
+            ..Default::default()
+        };
+        let inner = Inner {
+            state: Mutex::new(state),
+            written,
+            revocations,
+            dedup_window: Duration::from_secs(dedup_window_secs.into()),
+            batch_size,
+        };
+
+        Self {
+            inner: Arc::new(inner),
+            semaphore: Arc::new(Semaphore::new(max_clients)),
+            wait_timeout: Duration::from_secs(wait_timeout_secs.into()),
+        }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut MemoryState) -> T) -> T {
+        self.inner.with_state(f)
+    }
+}
+
+impl Inner {
+    fn with_state<T>(&self, f: impl FnOnce(&mut MemoryState) -> T) -> T {
+        f(&mut self.state.lock().unwrap())
+    }
+
+    /// Notify blocked readers of new entries
+    fn notify_written(&self) {
+        self.written.send_modify(|_| {});
+    }
+}
+
+impl MemoryState {
+    /// Remove all expired revocations and finished streams, and expire or time out the active
+    /// streams whose TTL or inactivity timeout has passed
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+        let retention = self.expired_retention;
+        let finished_keys = &mut self.finished_keys;
+        self.streams.retain(|key, stream| {
+            if stream.status != StreamStatus::Active {
+                return stream.expires_at > now;
+            }
+            if let Some(status) = stream.finish_if_due(now, retention) {
+                finished_keys.push((key.to_owned(), status));
+            }
+            true
+        });
+        self.revoked_tokens
+            .retain(|_, expires_at| *expires_at > now);
+        self.revoked_keys
+            .retain(|_, (_, expires_at)| *expires_at > now);
+    }
+
+    /// Get the stream with the given key, removing it if it has finished and its TTL has
+    /// passed, or expiring / timing it out if it's still active
+    fn stream(&mut self, key: &str) -> Option<&mut MemoryStream> {
+        let now = Instant::now();
+        let stream = self.streams.get_mut(key)?;
+        if stream.status != StreamStatus::Active {
+            if stream.expires_at <= now {
+                self.streams.remove(key);
+                return None;
+            }
+        } else if let Some(status) = stream.finish_if_due(now, self.expired_retention) {
+            self.finished_keys.push((key.to_owned(), status));
+        }
+        self.streams.get_mut(key)
+    }
+
+    fn active_stream(&mut self, key: &str) -> Option<&mut MemoryStream> {
+        self.stream(key)
+            .filter(|stream| stream.status == StreamStatus::Active)
+    }
+}
+
+impl MemoryStream {
+    /// Get the ID of the entry after the given ID: the next sequence number for streams
+    /// with sequential IDs, or else the current time
+    fn next_id(&self, (last_ms, seq): (u64, u64)) -> (u64, u64) {
+        if self.sequential {
+            return (last_ms + 1, 0);
+        }
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+        match now_ms <= last_ms {
+            true => (last_ms, seq + 1),
+            false => (now_ms, 0),
+        }
+    }
+
+    /// Append the entry created with the given ID (or the next ID), and trim the stream
+    /// to the max length
+    fn add(
+        &mut self,
+        id: Option<(u64, u64)>,
+        entry: impl FnOnce(RedisStr) -> StreamEntry,
+    ) -> RedisStr {
+        self.last_id = id.unwrap_or_else(|| self.next_id(self.last_id));
+        let id = RedisStr::from(util::format_entry_id(self.last_id));
+
+        self.entries.push_back(entry(id.clone()));
+        while self.entries.len() > self.max_len as usize {
+            self.entries.pop_front();
+        }
+
+        id
+    }
+
+    /// Time out the active stream if it had no writes for its inactivity timeout, or else
+    /// expire it if its TTL has passed, keeping it for the given retention period. Writes the
+    /// terminal event, and returns the new status.
+    fn finish_if_due(&mut self, now: Instant, retention: Duration) -> Option<StreamStatus> {
+        let status = match self.inactivity_timeout {
+            Some(timeout) if self.last_write + timeout <= now => StreamStatus::TimedOut,
+            _ if self.expires_at <= now => StreamStatus::Expired,
+            _ => return None,
+        };
+        self.add(None, |id| StreamEntry::new(id, status.status_event()));
+        self.status = status;
+        if status == StreamStatus::Expired {
+            self.expires_at = now + retention;
+        }
+
+        Some(status)
+    }
+
+    /// Extend the expiry to the TTL setting from now
+    fn refresh_expiry(&mut self) {
+        self.expires_at = Instant::now() + Duration::from_secs(self.ttl.into());
+    }
+
+    fn meta(&self) -> StreamMeta {
+        StreamMeta {
+            status: Some(self.status.as_str().to_owned()),
+            attributes: self.attributes.clone(),
+            finish: self.finish.clone(),
+            cancel_requested: self.cancel_requested,
+        }
+    }
+
+    /// Get the remaining TTL in seconds. Expired streams are only kept for the retention
+    /// period after their TTL has passed, so their TTL is 0.
+    fn ttl_secs(&self) -> i64 {
+        if self.status == StreamStatus::Expired {
+            return 0;
+        }
+        let remaining = self.expires_at.saturating_duration_since(Instant::now());
+        remaining.as_secs().try_into().unwrap_or(i64::MAX)
+    }
+
+    /// Get the entries after the given ID, up to the given count
+    fn entries_after(&self, start_event_id: &str, count: Option<usize>) -> Vec<StreamEntry> {
+        let start_id = util::parse_entry_id(start_event_id);
+        self.entries
+            .iter()
+            .filter(|entry| entry.id_parts() > start_id)
+            .take(count.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+}
+
+impl StreamStorage for MemoryStorage {
+    fn stream_info<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, StorageResult<(StreamMeta, u64, i64)>> {
+        let info = self.with_state(|state| match state.stream(key) {
+            Some(stream) => (
+                stream.meta(),
+                stream.entries.len() as u64,
+                stream.ttl_secs(),
+            ),
+            None => (StreamMeta::default(), 0, -2),
+        });
+        futures::future::ok(info).boxed()
+    }
+
+    fn active_stream_ttl<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, StorageResult<Option<(u32, bool)>>> {
+        let ttl = self.with_state(|state| {
+            let stream = state.active_stream(key)?;
+            let ttl = u32::try_from(stream.ttl_secs())
+                .ok()
+                .filter(|ttl| *ttl > 0)?;
+            Some((ttl, stream.sliding_ttl))
+        });
+        futures::future::ok(ttl).boxed()
+    }
+
+    fn scan_streams<'a>(
+        &'a self,
+        pattern: Option<&'a str>,
+        status: StreamStatus,
+        attributes: &'a [(&'a str, &'a str)],
+    ) -> BoxFuture<'a, StorageResult<Vec<StreamListing>>> {
+        let streams = self.with_state(|state| {
+            state.remove_expired();
+            state
+                .streams
+                .iter()
+                .filter(|(key, _)| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+                .map(|(key, stream)| (key, stream.meta(), stream))
+                .filter(|(_, meta, _)| {
+                    meta.has_status(status) && meta.matches_attributes(attributes.iter().copied())
+                })
+                .map(|(key, meta, stream)| {
+                    let len = stream.entries.len() as u64;
+                    (key.to_owned(), meta, len, stream.ttl_secs())
+                })
+                .collect()
+        });
+        futures::future::ok(streams).boxed()
+    }
+
+    fn start_stream<'a>(
+        &'a self,
+        key: &'a str,
+        settings: &'a StreamSettings,
+    ) -> BoxFuture<'a, StorageResult<Option<RedisStr>>> {
+        let id = self.with_state(|state| {
+            state.remove_expired();
+            if state.active_stream(key).is_some() {
+                return None;
+            }
+            let mut stream = MemoryStream {
+                entries: VecDeque::new(),
+                last_id: (0, 0),
+                status: StreamStatus::Active,
+                ttl: settings.ttl,
+                max_len: settings.max_len,
+                attributes: settings.attributes.clone(),
+                sequential: settings.sequential_ids,
+                sliding_ttl: settings.sliding_ttl,
+                inactivity_timeout: settings
+                    .inactivity_timeout
+                    .map(|timeout| Duration::from_secs(timeout.into())),
+                last_write: Instant::now(),
+                event_schema: settings.event_schema.clone(),
+                finish: None,
+                cancel_on_request: settings.cancel_on_request,
+                cancel_requested: false,
+                expires_at: Instant::now() + Duration::from_secs(settings.ttl.into()),
+                idempotency_keys: HashMap::new(),
+            };
+            let start_id = settings.sequential_ids.then_some((0, 1));
+            let id = stream.add(start_id, |id| {
+                StreamEntry::new(id, StreamStatus::Active.status_event())
+            });
+            state.streams.insert(key.to_owned(), stream);
+            Some(id)
+        });
+        self.inner.notify_written();
+
+        futures::future::ok(id).boxed()
+    }
+
+    fn touch_stream<'a>(
+        &'a self,
+        key: &'a str,
+        ttl: Option<u32>,
+    ) -> BoxFuture<'a, StorageResult<Option<u32>>> {
+        let ttl = self.with_state(|state| {
+            let stream = state.active_stream(key)?;
+            if let Some(ttl) = ttl {
+                stream.ttl = ttl;
+            }
+            stream.refresh_expiry();
+            Some(stream.ttl)
+        });
+        futures::future::ok(ttl).boxed()
+    }
+
+    fn write_events<'a>(
+        &'a self,
+        key: &'a str,
+        events: &'a [AddEvent],
+        expected_last_id: Option<&'a str>,
+        event_schema: Option<&'a str>,
+    ) -> BoxFuture<'a, StorageResult<WriteOutcome>> {
+        let outcome = self
+            .inner
+            .write_events(key, events, expected_last_id, event_schema);
+        futures::future::ok(outcome).boxed()
+    }
+
+    fn finish_stream<'a>(
+        &'a self,
+        key: &'a str,
+        status: StreamStatus,
+        details: Option<&'a FinishDetails>,
+    ) -> BoxFuture<'a, StorageResult<Option<RedisStr>>> {
+        let id = self.with_state(|state| {
+            let stream = state.active_stream(key)?;
+            let terminal_event = AddEvent {
+                event: status.status_event().to_owned(),
+                data: details
+                    .and_then(FinishDetails::to_json)
+                    .map(|json| EventData::Json(json.into())),
+                idempotency_key: None,
+                id: None,
+            };
+            let id = stream.add(None, |id| terminal_event.to_entry(id));
+            stream.status = status;
+            stream.finish = details.cloned();
+            Some(id)
+        });
+        self.inner.notify_written();
+
+        futures::future::ok(id).boxed()
+    }
+
+    fn request_cancel<'a>(
+        &'a self,
+        key: &'a str,
+        details: &'a FinishDetails,
+    ) -> BoxFuture<'a, StorageResult<Option<CancelRequestOutcome>>> {
+        let outcome = self.with_state(|state| {
+            let stream = state.active_stream(key)?;
+            if stream.cancel_requested {
+                return Some(CancelRequestOutcome::AlreadyRequested);
+            }
+            stream.add(None, |id| StreamEntry::new(id, constants::CANCEL_REQUESTED));
+            stream.cancel_requested = true;
+            if !stream.cancel_on_request {
+                return Some(CancelRequestOutcome::Recorded);
+            }
+
+            let cancel_event = AddEvent {
+                event: StreamStatus::Cancelled.status_event().to_owned(),
+                data: details.to_json().map(|json| EventData::Json(json.into())),
+                idempotency_key: None,
+                id: None,
+            };
+            stream.add(None, |id| cancel_event.to_entry(id));
+            stream.status = StreamStatus::Cancelled;
+            stream.finish = Some(details.clone());
+            Some(CancelRequestOutcome::Cancelled)
+        });
+        self.inner.notify_written();
+
+        futures::future::ok(outcome).boxed()
+    }
+
+    fn expire_streams(&self) -> BoxFuture<'_, StorageResult<Vec<(String, StreamStatus)>>> {
+        let finished_keys = self.with_state(|state| {
+            state.remove_expired();
+            std::mem::take(&mut state.finished_keys)
+        });
+        if !finished_keys.is_empty() {
+            self.inner.notify_written();
+        }
+
+        futures::future::ok(finished_keys).boxed()
+    }
+
+    fn compact_stream<'a>(
+        &'a self,
+        key: &'a str,
+        events: &'a [String],
+    ) -> BoxFuture<'a, StorageResult<Option<u64>>> {
+        let removed = self.with_state(|state| {
+            let stream = state
+                .stream(key)
+                .filter(|stream| stream.status != StreamStatus::Active)?;
+            let len = stream.entries.len();
+            let mut compacted: VecDeque<StreamEntry> = VecDeque::with_capacity(len);
+            for entry in stream.entries.drain(..) {
+                let should_merge = entry
+                    .event()
+                    .is_some_and(|event| events.iter().any(|name| name == event));
+                let entry = match compacted.back_mut() {
+                    Some(previous) if should_merge => match previous.merge(entry) {
+                        Ok(()) => continue,
+                        Err(entry) => entry,
+                    },
+                    _ => entry,
+                };
+                compacted.push_back(entry);
+            }
+            stream.entries = compacted;
+
+            Some((len - stream.entries.len()) as u64)
+        });
+        futures::future::ok(removed).boxed()
+    }
+
+    fn range<'a>(
+        &'a self,
+        streams: &'a [(&'a str, &'a str)],
+    ) -> BoxFuture<'a, StorageResult<Vec<StreamRange>>> {
+        let ranges = self.with_state(|state| {
+            streams
+                .iter()
+                .map(|(key, start_event_id)| {
+                    let stream = state.stream(key).ok_or(StorageError::StreamNotFound)?;
+                    Ok(StreamRange {
+                        entries: stream.entries_after(start_event_id, None),
+                        is_active: stream.status == StreamStatus::Active,
+                    })
+                })
+                .collect()
+        });
+        futures::future::ready(ranges).boxed()
+    }
+
+    fn range_page<'a>(
+        &'a self,
+        key: &'a str,
+        query: &'a PageQuery,
+    ) -> BoxFuture<'a, StorageResult<Vec<StreamEntry>>> {
+        let entries = self.with_state(|state| {
+            let stream = state.stream(key).ok_or(StorageError::StreamNotFound)?;
+            let in_range =
+                |entry: &&StreamEntry| (query.start..=query.end).contains(&entry.id_parts());
+            let entries = match query.reverse {
+                true => stream
+                    .entries
+                    .iter()
+                    .rev()
+                    .filter(in_range)
+                    .take(query.count)
+                    .cloned()
+                    .collect(),
+                false => stream
+                    .entries
+                    .iter()
+                    .filter(in_range)
+                    .take(query.count)
+                    .cloned()
+                    .collect(),
+            };
+            Ok(entries)
+        });
+        futures::future::ready(entries).boxed()
+    }
+
+    fn inactive_streams<'a>(
+        &'a self,
+        keys: &'a [&'a str],
+    ) -> BoxFuture<'a, StorageResult<Vec<String>>> {
+        let inactive_keys = self.with_state(|state| {
+            keys.iter()
+                .filter(|key| state.active_stream(key).is_none())
+                .map(|key| (*key).to_owned())
+                .collect()
+        });
+        futures::future::ok(inactive_keys).boxed()
+    }
+
+    fn revoke_token<'a>(&'a self, token_id: &'a str, ttl: i64) -> BoxFuture<'a, StorageResult<()>> {
+        let expires_at = Instant::now() + Duration::from_secs(ttl.max(0) as u64);
+        self.with_state(|state| {
+            state.revoked_tokens.insert(token_id.to_owned(), expires_at);
+        });
+        let _ = self
+            .inner
+            .revocations
+            .send(Revocation::Token(token_id.to_owned()));
+
+        futures::future::ok(()).boxed()
+    }
+
+    fn revoke_key_tokens<'a>(
+        &'a self,
+        key: &'a str,
+        revoked_at: i64,
+        ttl: i64,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        let expires_at = Instant::now() + Duration::from_secs(ttl.max(0) as u64);
+        self.with_state(|state| {
+            state
+                .revoked_keys
+                .insert(key.to_owned(), (revoked_at, expires_at));
+        });
+        let _ = self.inner.revocations.send(Revocation::Key(key.to_owned()));
+
+        futures::future::ok(()).boxed()
+    }
+
+    fn is_token_revoked<'a>(
+        &'a self,
+        token_id: &'a str,
+        issued_at: i64,
+        keys: &'a [&'a str],
+    ) -> BoxFuture<'a, StorageResult<bool>> {
+        let revoked = self.with_state(|state| {
+            state.remove_expired();
+            state.revoked_tokens.contains_key(token_id)
+                || keys.iter().any(|key| {
+                    state
+                        .revoked_keys
+                        .get(*key)
+                        .is_some_and(|(revoked_at, _)| issued_at <= *revoked_at)
+                })
+        });
+        futures::future::ok(revoked).boxed()
+    }
+
+    fn connect(&self) -> BoxFuture<'_, StorageResult<Option<Box<dyn StreamConnection>>>> {
+        async move {
+            let permit = match Arc::clone(&self.semaphore).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => match tokio::time::timeout(
+                    self.wait_timeout,
+                    Arc::clone(&self.semaphore).acquire_owned(),
+                )
+                .await
+                {
+                    Ok(Ok(permit)) => permit,
+                    _ => return Ok(None),
+                },
+            };
+            let connection = MemoryConnection {
+                inner: Arc::clone(&self.inner),
+                _permit: permit,
+            };
+
+            Ok(Some(Box::new(connection) as Box<dyn StreamConnection>))
+        }
+        .boxed()
+    }
+
+    fn available_connections(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+
+    fn shutdown(&self) -> BoxFuture<'_, ()> {
+        self.semaphore.close();
+        self.inner.written.send_replace(true);
+
+        futures::future::ready(()).boxed()
+    }
+}
+
+impl Inner {
+    fn write_events(
+        &self,
+        key: &str,
+        events: &[AddEvent],
+        expected_last_id: Option<&str>,
+        event_schema: Option<&str>,
+    ) -> WriteOutcome {
+        let outcome = self.with_state(|state| {
+            let Some(stream) = state.active_stream(key) else {
+                return WriteOutcome::NotActive;
+            };
+            if stream.event_schema.as_deref() != event_schema {
+                return WriteOutcome::SchemaChanged(stream.event_schema.clone());
+            }
+            if events.is_empty() {
+                return WriteOutcome::Written(Vec::new());
+            }
+            let last_id = stream.entries.back().map(|entry| entry.id.clone());
+            if let Some(expected_last_id) = expected_last_id
+                && last_id.as_deref() != Some(expected_last_id)
+            {
+                return WriteOutcome::Conflict(last_id.unwrap_or_default());
+            }
+            let now = Instant::now();
+            stream
+                .idempotency_keys
+                .retain(|_, written_at| now.duration_since(*written_at) < self.dedup_window);
+
+            // Assign the IDs before writing, so the batch is rejected as a whole if an
+            // explicit ID isn't increasing (duplicates get no ID)
+            let mut ids = Vec::with_capacity(events.len());
+            let mut seen_keys = HashSet::new();
+            let mut next_id = stream.last_id;
+            for event in events {
+                if let Some(idempotency_key) = &event.idempotency_key
+                    && (stream.idempotency_keys.contains_key(idempotency_key)
+                        || !seen_keys.insert(idempotency_key))
+                {
+                    ids.push(None);
+                    continue;
+                }
+                next_id = match event.id {
+                    Some(id) if id <= next_id => {
+                        return WriteOutcome::StaleId(last_id.unwrap_or_default());
+                    }
+                    Some(id) => id,
+                    None => stream.next_id(next_id),
+                };
+                ids.push(Some(next_id));
+            }
+
+            let results = events
+                .iter()
+                .zip(ids)
+                .map(|(event, id)| match id {
+                    None => WriteResult::Duplicate,
+                    Some(id) => {
+                        if let Some(idempotency_key) = &event.idempotency_key {
+                            stream
+                                .idempotency_keys
+                                .insert(idempotency_key.to_owned(), now);
+                        }
+                        WriteResult::Added(stream.add(Some(id), |id| event.to_entry(id)))
+                    }
+                })
+                .collect();
+            if stream.sliding_ttl {
+                stream.refresh_expiry();
+            }
+            stream.last_write = now;
+            WriteOutcome::Written(results)
+        });
+        self.notify_written();
+
+        outcome
+    }
+}
+
+/// A connection to the in-memory storage, holding one of the limited connection permits
+struct MemoryConnection {
+    inner: Arc<Inner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl StreamConnection for MemoryConnection {
+    fn read<'a>(
+        &'a self,
+        streams: &'a [(&'a str, &'a str)],
+        block_ms: u64,
+    ) -> BoxFuture<'a, StorageResult<Vec<(usize, StreamEntry)>>> {
+        async move {
+            let deadline = tokio::time::Instant::now() + Duration::from_millis(block_ms);
+            let mut written = self.inner.written.subscribe();
+            loop {
+                // Mark the current writes as seen before reading, so later ones wake us up
+                if *written.borrow_and_update() {
+                    return Err(StorageError::Shutdown);
+                }
+                let batch_size = Some(self.inner.batch_size as usize);
+                let entries: Vec<_> = self.inner.with_state(|state| {
+                    streams
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(idx, (key, start_event_id))| {
+                            let stream = state.stream(key)?;
+                            let entries = stream.entries_after(start_event_id, batch_size);
+                            Some(entries.into_iter().map(move |entry| (idx, entry)))
+                        })
+                        .flatten()
+                        .collect()
+                });
+                if !entries.is_empty() {
+                    return Ok(entries);
+                }
+
+                match tokio::time::timeout_at(deadline, written.changed()).await {
+                    Ok(Ok(())) => continue,
+                    Ok(Err(_)) => return Err(StorageError::Shutdown),
+                    Err(_) => return Ok(Vec::new()),
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn write_events<'a>(
+        &'a self,
+        key: &'a str,
+        events: &'a [AddEvent],
+        expected_last_id: Option<&'a str>,
+        event_schema: Option<&'a str>,
+    ) -> BoxFuture<'a, StorageResult<WriteOutcome>> {
+        let outcome = self
+            .inner
+            .write_events(key, events, expected_last_id, event_schema);
+        futures::future::ok(outcome).boxed()
+    }
+}
+
+/// Match a key against a glob-style pattern with `*` and `?` wildcards
+fn glob_match(pattern: &str, key: &str) -> bool {
+    let (pattern, key): (Vec<char>, Vec<char>) = (pattern.chars().collect(), key.chars().collect());
+    let (mut p, mut k) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_k)) => {
+                    p = star_p + 1;
+                    k = star_k + 1;
+                    backtrack = Some((star_p, star_k + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_storage(max_clients: usize) -> MemoryStorage {
+        let (revocations, _) = broadcast::channel(16);
+        MemoryStorage::new(max_clients, 0, 60, 60, 100, revocations)
+    }
+
+    fn settings(ttl: u32, max_len: u32) -> StreamSettings {
+        StreamSettings {
+            ttl,
+            max_len,
+            attributes: HashMap::from([("user".to_owned(), "42".to_owned())]),
+            sequential_ids: false,
+            sliding_ttl: false,
+            inactivity_timeout: None,
+            cancel_on_request: false,
+            event_schema: None,
+        }
+    }
+
+    fn events(names: &[&str]) -> Vec<AddEvent> {
+        names
+            .iter()
+            .map(|name| AddEvent {
+                event: (*name).to_owned(),
+                data: Some(EventData::Text("data".into())),
+                idempotency_key: None,
+                id: None,
+            })
+            .collect()
+    }
+
+    fn written(outcome: WriteOutcome) -> Vec<WriteResult> {
+        match outcome {
+            WriteOutcome::Written(results) => results,
+            outcome => panic!("events not written: {outcome:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_expire() {
+        let storage = get_test_storage(1);
+        storage.start_stream("a", &settings(0, 100)).await.unwrap();
+        storage.start_stream("c", &settings(60, 100)).await.unwrap();
+
+        assert_eq!(
+            storage.expire_streams().await.unwrap(),
+            vec![("a".to_owned(), StreamStatus::Expired)]
+        );
+        assert!(storage.expire_streams().await.unwrap().is_empty());
+
+        // The expired stream is kept with its terminal event, and can't be written to
+        let (meta, len, ttl) = storage.stream_info("a").await.unwrap();
+        assert_eq!(meta.stream_status(), Some(StreamStatus::Expired));
+        assert_eq!(len, 2);
+        assert_eq!(ttl, 0);
+        let range = storage.range(&[("a", "0-0")]).await.unwrap();
+        assert!(!range[0].is_active);
+        assert!(range[0].entries[1].is_end_event());
+        assert_eq!(
+            storage
+                .write_events("a", &events(&["one"]), None, None)
+                .await
+                .unwrap(),
+            WriteOutcome::NotActive
+        );
+        let expired = storage
+            .scan_streams(None, StreamStatus::Expired, &[])
+            .await
+            .unwrap();
+        assert_eq!(expired.len(), 1);
+
+        assert!(storage.active_stream_ttl("c").await.unwrap().is_some());
+
+        // Expired streams are removed after the retention period
+        let (revocations, _) = broadcast::channel(16);
+        let storage = MemoryStorage::new(1, 0, 60, 0, 100, revocations);
+        storage.start_stream("a", &settings(0, 100)).await.unwrap();
+        assert_eq!(
+            storage.expire_streams().await.unwrap(),
+            vec![("a".to_owned(), StreamStatus::Expired)]
+        );
+        let (meta, _, ttl) = storage.stream_info("a").await.unwrap();
+        assert!(meta.status.is_none());
+        assert_eq!(ttl, -2);
+        assert!(storage.active_stream_ttl("a").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn touch_and_sliding_ttl() {
+        let storage = get_test_storage(1);
+        storage.start_stream("a", &settings(60, 100)).await.unwrap();
+        let sliding = StreamSettings {
+            sliding_ttl: true,
+            ..settings(60, 100)
+        };
+        storage.start_stream("b", &sliding).await.unwrap();
+        storage.start_stream("c", &settings(60, 100)).await.unwrap();
+
+        // Touching with a new TTL extends the stream and updates its TTL setting
+        assert_eq!(
+            storage.touch_stream("a", Some(120)).await.unwrap(),
+            Some(120)
+        );
+        let (ttl, sliding) = storage.active_stream_ttl("a").await.unwrap().unwrap();
+        assert!(ttl > 60 && !sliding);
+        assert!(storage.stream_info("a").await.unwrap().2 > 60);
+        assert_eq!(storage.touch_stream("a", None).await.unwrap(), Some(120));
+        assert_eq!(storage.touch_stream("d", Some(60)).await.unwrap(), None);
+
+        // Writes only refresh the expiry of streams with a sliding TTL
+        storage.with_state(|state| {
+            for key in ["b", "c"] {
+                state.streams.get_mut(key).unwrap().expires_at =
+                    Instant::now() + Duration::from_secs(5);
+            }
+        });
+        for key in ["b", "c"] {
+            let outcome = storage.write_events(key, &events(&["one"]), None, None);
+            assert!(matches!(outcome.await.unwrap(), WriteOutcome::Written(_)));
+        }
+        assert!(storage.stream_info("b").await.unwrap().2 > 5);
+        assert!(storage.stream_info("c").await.unwrap().2 <= 5);
+    }
+
+    #[tokio::test]
+    async fn inactive_streams_time_out() {
+        let storage = get_test_storage(1);
+        let settings = StreamSettings {
+            inactivity_timeout: Some(10),
+            ..settings(60, 100)
+        };
+        storage.start_stream("a", &settings).await.unwrap();
+        storage.start_stream("b", &settings).await.unwrap();
+        let outcome = storage
+            .write_events("a", &events(&["one"]), None, None)
+            .await;
+        assert!(matches!(outcome.unwrap(), WriteOutcome::Written(_)));
+        assert!(storage.expire_streams().await.unwrap().is_empty());
+
+        // The stream without writes for the timeout is finished with a `timed_out` event
+        storage.with_state(|state| {
+            let stream = state.streams.get_mut("b").unwrap();
+            stream.last_write -= Duration::from_secs(10);
+        });
+        assert_eq!(
+            storage.expire_streams().await.unwrap(),
+            vec![("b".to_owned(), StreamStatus::TimedOut)]
+        );
+        let (meta, len, ttl) = storage.stream_info("b").await.unwrap();
+        assert_eq!(meta.stream_status(), Some(StreamStatus::TimedOut));
+        assert_eq!(len, 2);
+        assert!(ttl > 50);
+        let range = storage.range(&[("b", "0-0")]).await.unwrap();
+        assert!(range[0].entries[1].is_end_event());
+        assert!(storage.active_stream_ttl("a").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn finish_details_are_stored() {
+        let storage = get_test_storage(1);
+        storage.start_stream("a", &settings(60, 100)).await.unwrap();
+        let details = FinishDetails {
+            reason: Some("provider_error".to_owned()),
+            data: Some(serde_json::json!({ "message": "overloaded" })),
+        };
+        let id = storage
+            .finish_stream("a", StreamStatus::Cancelled, Some(&details))
+            .await
+            .unwrap();
+        assert!(id.is_some());
+
+        let (meta, _, _) = storage.stream_info("a").await.unwrap();
+        assert_eq!(meta.finish, Some(details));
+        let range = storage.range(&[("a", "0-0")]).await.unwrap();
+        let (_, event, data) = range[0].entries[1].clone().into_parts();
+        assert_eq!(&*event, "cancel");
+        assert_eq!(
+            data.unwrap().into_value(),
+            serde_json::json!({ "reason": "provider_error", "data": { "message": "overloaded" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel_requests() {
+        let storage = get_test_storage(1);
+        let details = FinishDetails {
+            reason: Some("cancel_requested".to_owned()),
+            data: None,
+        };
+        assert!(
+            storage
+                .request_cancel("a", &details)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        storage.start_stream("a", &settings(60, 100)).await.unwrap();
+        let outcome = storage.request_cancel("a", &details).await.unwrap();
+        assert_eq!(outcome, Some(CancelRequestOutcome::Recorded));
+        let outcome = storage.request_cancel("a", &details).await.unwrap();
+        assert_eq!(outcome, Some(CancelRequestOutcome::AlreadyRequested));
+        let (meta, length, _) = storage.stream_info("a").await.unwrap();
+        assert_eq!(meta.status.as_deref(), Some("active"));
+        assert!(meta.cancel_requested);
+        assert_eq!(length, 2);
+
+        let cancel_settings = StreamSettings {
+            cancel_on_request: true,
+            ..settings(60, 100)
+        };
+        storage.start_stream("b", &cancel_settings).await.unwrap();
+        let outcome = storage.request_cancel("b", &details).await.unwrap();
+        assert_eq!(outcome, Some(CancelRequestOutcome::Cancelled));
+        let (meta, _, _) = storage.stream_info("b").await.unwrap();
+        assert_eq!(meta.status.as_deref(), Some("cancelled"));
+        assert_eq!(meta.finish, Some(details));
+        let range = storage.range(&[("b", "0-0")]).await.unwrap();
+        let names: Vec<_> = range[0]
+            .entries
+            .iter()
+            .map(|entry| entry.clone().into_parts().1)
+            .collect();
+        assert_eq!(names, ["start", "cancel_requested", "cancel"]);
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("*", "user:42:chat"));
+        assert!(glob_match("user:*", "user:42:chat"));
+        assert!(glob_match("user:*:chat", "user:42:chat"));
+        assert!(glob_match("user:4?:*", "user:42:chat"));
+        assert!(!glob_match("user:*:other", "user:42:chat"));
+        assert!(!glob_match("user:4?", "user:420"));
+    }
+
+    #[tokio::test]
+    async fn stream_lifecycle() {
+        let storage = get_test_storage(1);
+        assert!(
+            storage
+                .start_stream("a", &settings(60, 100))
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            storage
+                .start_stream("a", &settings(60, 100))
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let outcome = storage
+            .write_events("a", &events(&["one", "two"]), None, None)
+            .await
+            .unwrap();
+        assert!(matches!(outcome, WriteOutcome::Written(results) if results.len() == 2));
+        let end_id = storage
+            .finish_stream("a", StreamStatus::Ended, None)
+            .await
+            .unwrap();
+        assert!(end_id.is_some());
+        assert_eq!(
+            storage
+                .write_events("a", &events(&["three"]), None, None)
+                .await
+                .unwrap(),
+            WriteOutcome::NotActive
+        );
+        assert!(
+            storage
+                .finish_stream("a", StreamStatus::Cancelled, None)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let (meta, len, ttl) = storage.stream_info("a").await.unwrap();
+        assert_eq!(meta.status.as_deref(), Some("ended"));
+        assert_eq!(len, 4);
+        assert!(ttl > 0 && ttl <= 60);
+        assert_eq!(storage.inactive_streams(&["a"]).await.unwrap(), vec!["a"]);
+
+        let range = storage.range(&[("a", "0-0")]).await.unwrap().remove(0);
+        assert!(!range.is_active);
+        assert!(range.entries.last().unwrap().is_end_event());
+        assert!(
+            range
+                .entries
+                .windows(2)
+                .all(|w| w[0].id_parts() < w[1].id_parts())
+        );
+        assert!(matches!(
+            storage.range(&[("a", "0-0"), ("b", "0-0")]).await,
+            Err(StorageError::StreamNotFound)
+        ));
+
+        // An inactive stream can be restarted
+        assert!(
+            storage
+                .start_stream("a", &settings(60, 100))
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(storage.stream_info("a").await.unwrap().1, 1);
+    }
+
+    #[tokio::test]
+    async fn trims_and_scans_streams() {
+        let storage = get_test_storage(1);
+        storage
+            .start_stream("user:42:a", &settings(60, 3))
+            .await
+            .unwrap();
+        storage
+            .start_stream("user:42:b", &settings(60, 3))
+            .await
+            .unwrap();
+        storage
+            .start_stream("user:43:a", &settings(60, 3))
+            .await
+            .unwrap();
+        storage
+            .write_events("user:42:a", &events(&["1", "2", "3", "4"]), None, None)
+            .await
+            .unwrap();
+        storage
+            .finish_stream("user:42:b", StreamStatus::Cancelled, None)
+            .await
+            .unwrap();
+
+        let (_, len, _) = storage.stream_info("user:42:a").await.unwrap();
+        assert_eq!(len, 3);
+
+        let streams = storage
+            .scan_streams(Some("user:42:*"), StreamStatus::Active, &[("user", "42")])
+            .await
+            .unwrap();
+        let keys: Vec<_> = streams.iter().map(|(key, ..)| key.as_str()).collect();
+        assert_eq!(keys, vec!["user:42:a"]);
+        let streams = storage
+            .scan_streams(None, StreamStatus::Active, &[("user", "43")])
+            .await
+            .unwrap();
+        assert!(streams.is_empty());
+    }
+
+    #[tokio::test]
+    async fn blocking_reads_wake_on_write() {
+        let storage = Arc::new(get_test_storage(1));
+        let start_id = storage
+            .start_stream("a", &settings(60, 100))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let connection = storage.connect().await.unwrap().unwrap();
+        assert_eq!(storage.available_connections(), 0);
+        assert!(storage.connect().await.unwrap().is_none());
+
+        let writer = Arc::clone(&storage);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            writer
+                .write_events("a", &events(&["one"]), None, None)
+                .await
+                .unwrap();
+        });
+        let entries = connection
+            .read(&[("b", "0-0"), ("a", &start_id)], 5_000)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, 1);
+
+        let last_id = entries[0].1.id.clone();
+        let entries = connection.read(&[("a", &last_id)], 10).await.unwrap();
+        assert!(entries.is_empty());
+
+        drop(connection);
+        assert_eq!(storage.available_connections(), 1);
+        storage.shutdown().await;
+        assert!(storage.connect().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn duplicate_events_are_skipped() {
+        let storage = get_test_storage(1);
+        storage.start_stream("a", &settings(60, 100)).await.unwrap();
+        let keyed_events = |keys: &[&str]| -> Vec<AddEvent> {
+            keys.iter()
+                .map(|key| AddEvent {
+                    idempotency_key: Some((*key).to_owned()),
+                    ..events(&["keyed"]).remove(0)
+                })
+                .chain(events(&["unkeyed"]))
+                .collect()
+        };
+
+        let results = written(
+            storage
+                .write_events("a", &keyed_events(&["1", "2", "1"]), None, None)
+                .await
+                .unwrap(),
+        );
+        assert!(matches!(
+            results.as_slice(),
+            [
+                WriteResult::Added(_),
+                WriteResult::Added(_),
+                WriteResult::Duplicate,
+                WriteResult::Added(_)
+            ]
+        ));
+        let results = written(
+            storage
+                .write_events("a", &keyed_events(&["2", "3"]), None, None)
+                .await
+                .unwrap(),
+        );
+        assert!(matches!(
+            results.as_slice(),
+            [
+                WriteResult::Duplicate,
+                WriteResult::Added(_),
+                WriteResult::Added(_)
+            ]
+        ));
+        let (_, len, _) = storage.stream_info("a").await.unwrap();
+        assert_eq!(len, 6);
+
+        // Idempotency keys are cleared when a new stream is started at the same key
+        storage
+            .finish_stream("a", StreamStatus::Ended, None)
+            .await
+            .unwrap();
+        storage.start_stream("a", &settings(60, 100)).await.unwrap();
+        let results = written(
+            storage
+                .write_events("a", &keyed_events(&["1"]), None, None)
+                .await
+                .unwrap(),
+        );
+        assert!(matches!(results[0], WriteResult::Added(_)));
+    }
+
+    #[tokio::test]
+    async fn expected_last_id_conflicts() {
+        let storage = get_test_storage(1);
+        let start_id = storage
+            .start_stream("a", &settings(60, 100))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let results = written(
+            storage
+                .write_events("a", &events(&["one", "two"]), Some(&start_id), None)
+                .await
+                .unwrap(),
+        );
+        let WriteResult::Added(last_id) = results[1].clone() else {
+            panic!("should add event");
+        };
+        let outcome = storage
+            .write_events("a", &events(&["three"]), Some(&start_id), None)
+            .await
+            .unwrap();
+        assert_eq!(outcome, WriteOutcome::Conflict(last_id.clone()));
+        let results = written(
+            storage
+                .write_events("a", &events(&["three"]), Some(&last_id), None)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(results.len(), 1);
+
+        let (_, len, _) = storage.stream_info("a").await.unwrap();
+        assert_eq!(len, 4);
+    }
+
+    #[tokio::test]
+    async fn changed_event_schema() {
+        let storage = get_test_storage(1);
+        let schema = r#"{"type":"object"}"#;
+        let with_schema = StreamSettings {
+            event_schema: Some(schema.to_owned()),
+            ..settings(60, 100)
+        };
+        storage.start_stream("a", &with_schema).await.unwrap();
+
+        let outcome = storage
+            .write_events("a", &events(&["one"]), None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            WriteOutcome::SchemaChanged(Some(schema.to_owned()))
+        );
+        let results = written(
+            storage
+                .write_events("a", &events(&["one"]), None, Some(schema))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(results.len(), 1);
+
+        let (_, len, _) = storage.stream_info("a").await.unwrap();
+        assert_eq!(len, 2);
+    }
+
+    #[tokio::test]
+    async fn sequential_and_explicit_ids() {
+        let storage = get_test_storage(1);
+        let sequential = StreamSettings {
+            sequential_ids: true,
+            ..settings(60, 100)
+        };
+        let start_id = storage.start_stream("a", &sequential).await.unwrap();
+        assert_eq!(start_id.as_deref(), Some("0-1"));
+
+        let mut batch = events(&["one", "two", "three"]);
+        batch[1].id = Some((10, 0));
+        let results = written(storage.write_events("a", &batch, None, None).await.unwrap());
+        let ids: Vec<_> = results
+            .iter()
+            .map(|result| match result {
+                WriteResult::Added(id) => id.to_string(),
+                WriteResult::Duplicate => panic!("should add event"),
+            })
+            .collect();
+        assert_eq!(ids, ["1-0", "10-0", "11-0"]);
+
+        // Explicit IDs must be increasing, and the whole batch is rejected otherwise
+        let mut batch = events(&["four", "five"]);
+        batch[1].id = Some((11, 0));
+        let outcome = storage.write_events("a", &batch, None, None).await.unwrap();
+        assert_eq!(outcome, WriteOutcome::StaleId("11-0".into()));
+        let (_, len, _) = storage.stream_info("a").await.unwrap();
+        assert_eq!(len, 4);
+
+        let id = storage
+            .finish_stream("a", StreamStatus::Ended, None)
+            .await
+            .unwrap();
+        assert_eq!(id.as_deref(), Some("12-0"));
+    }
+
+    #[tokio::test]
+    async fn compact_finished_stream() {
+        let storage = get_test_storage(1);
+        storage.start_stream("a", &settings(60, 100)).await.unwrap();
+        let batch = events(&["delta", "delta", "usage", "delta", "delta", "delta"]);
+        let results = written(storage.write_events("a", &batch, None, None).await.unwrap());
+        let compact = ["delta".to_owned()];
+        assert_eq!(storage.compact_stream("a", &compact).await.unwrap(), None);
+
+        storage
+            .finish_stream("a", StreamStatus::Ended, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.compact_stream("a", &compact).await.unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            storage.compact_stream("a", &compact).await.unwrap(),
+            Some(0)
+        );
+
+        let ranges = storage.range(&[("a", "0-0")]).await.unwrap();
+        let entries: Vec<_> = ranges[0]
+            .entries
+            .iter()
+            .map(|entry| {
+                let (id, event, data) = entry.clone().into_parts();
+                let data = data.map(|data| data.to_text().into_owned());
+                (id.to_string(), event.to_string(), data)
+            })
+            .collect();
+        let added_id = |idx: usize| match &results[idx] {
+            WriteResult::Added(id) => id.to_string(),
+            WriteResult::Duplicate => panic!("should add event"),
+        };
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0].1, "start");
+        assert_eq!(
+            entries[1..4],
+            [
+                (added_id(1), "delta".into(), Some("datadata".into())),
+                (added_id(2), "usage".into(), Some("data".into())),
+                (added_id(5), "delta".into(), Some("datadatadata".into())),
+            ]
+        );
+        assert_eq!(entries[4].1, "end");
+    }
+
+    #[tokio::test]
+    async fn token_revocations() {
+        let storage = get_test_storage(1);
+        let mut revocation_rx = storage.inner.revocations.subscribe();
+
+        storage.revoke_token("abc123", 60).await.unwrap();
+        storage
+            .revoke_key_tokens("user:42:*", 1_000, 60)
+            .await
+            .unwrap();
+        assert_eq!(
+            revocation_rx.recv().await.unwrap(),
+            Revocation::Token("abc123".into())
+        );
+        assert_eq!(
+            revocation_rx.recv().await.unwrap(),
+            Revocation::Key("user:42:*".into())
+        );
+
+        assert!(
+            storage
+                .is_token_revoked("abc123", 2_000, &[])
+                .await
+                .unwrap()
+        );
+        assert!(
+            storage
+                .is_token_revoked("def456", 1_000, &["user:42:*"])
+                .await
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .is_token_revoked("def456", 2_000, &["user:42:*"])
+                .await
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .is_token_revoked("def456", 1_000, &["user:43:*"])
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Storage backends for streams, their metadata, and token revocations
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+
+use crate::redis::{
+    AddEvent, FinishDetails, RedisStr, StreamEntry, StreamMeta, StreamSettings, StreamStatus,
+};
+
+mod error;
+mod expiry;
+mod fanout;
+mod filter;
+mod history;
+mod memory;
+mod reader;
+mod schema;
+
+pub use error::{StorageError, StorageResult};
+pub use expiry::ExpiryWatcher;
+pub use fanout::{Fanout, StreamReaders, Subscription};
+pub use filter::EventFilter;
+pub use history::StreamHistory;
+pub use memory::MemoryStorage;
+pub use reader::StreamReader;
+pub use schema::{EventSchema, EventValidator, EventValidators, StreamSchema};
+
+/// Storage backend to use
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Redis (or Redis Cluster) server
+    #[default]
+    Redis,
+    /// In-process memory, for single-node deployments and testing
+    Memory,
+}
+
+/// The key, metadata, length, and TTL of a stream
+pub type StreamListing = (String, StreamMeta, u64, i64);
+
+/// The entries of a stream after a given ID, and whether the stream is still active
+pub struct StreamRange {
+    pub entries: Vec<StreamEntry>,
+    pub is_active: bool,
+}
+
+/// Result of writing an event to a stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteResult {
+    /// The event was added with the given ID
+    Added(RedisStr),
+    /// The event was skipped, as an event with the same idempotency key was already
+    /// written within the dedup window
+    Duplicate,
+}
+
+/// Outcome of writing a batch of events to a stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOutcome {
+    /// The batch was written, with the result of each event
+    Written(Vec<WriteResult>),
+    /// The stream is not active, so no events were written
+    NotActive,
+    /// The last event ID of the stream didn't match the expected ID, so no events were
+    /// written. Contains the current last event ID.
+    Conflict(RedisStr),
+    /// An explicit event ID wasn't greater than the previous event ID, so no events were
+    /// written. Contains the current last event ID.
+    StaleId(RedisStr),
+    /// The stream's event schema isn't the one the events were validated against (e.g. the
+    /// stream was created again with another schema), so no events were written. Contains
+    /// the stream's current event schema.
+    SchemaChanged(Option<String>),
+}
+
+/// Outcome of a consumer's request to cancel a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelRequestOutcome {
+    /// The request was recorded with a `cancel_requested` event
+    Recorded,
+    /// Cancellation was already requested, so nothing was written
+    AlreadyRequested,
+    /// The request was recorded, and the stream was cancelled right away
+    Cancelled,
+}
+
+/// Query for a page of stream entries
+pub struct PageQuery {
+    /// ID of the first entry to include
+    pub start: (u64, u64),
+    /// ID of the last entry to include
+    pub end: (u64, u64),
+    /// Maximum number of entries
+    pub count: usize,
+    /// Return the entries from newest to oldest
+    pub reverse: bool,
+}
+
+/// Storage of streams, their metadata, and token revocations. Quick operations are run
+/// directly, while long-running operations use an exclusive [`StreamConnection`].
+pub trait StreamStorage: Send + Sync {
+    /// Get metadata, length, and TTL of a stream
+    fn stream_info<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, StorageResult<(StreamMeta, u64, i64)>>;
+
+    /// Get the remaining TTL (in seconds) of the active stream with the given key, and whether
+    /// the stream has a sliding TTL. Returns `None` if the stream is not active, or its TTL
+    /// has passed.
+    fn active_stream_ttl<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, StorageResult<Option<(u32, bool)>>>;
+
+    /// Get the key, metadata, length, and TTL of all streams with the given status matching
+    /// the given pattern and attribute values.
+    fn scan_streams<'a>(
+        &'a self,
+        pattern: Option<&'a str>,
+        status: StreamStatus,
+        attributes: &'a [(&'a str, &'a str)],
+    ) -> BoxFuture<'a, StorageResult<Vec<StreamListing>>>;
+
+    /// Start a new stream by writing a `start` entry and setting the expiration.
+    /// Deletes any old inactive stream at the same key.
+    /// Returns `None` if the stream is already active.
+    fn start_stream<'a>(
+        &'a self,
+        key: &'a str,
+        settings: &'a StreamSettings,
+    ) -> BoxFuture<'a, StorageResult<Option<RedisStr>>>;
+
+    /// Extend the TTL of an active stream, so that it expires `ttl` seconds from now. The given
+    /// TTL is stored as the stream's new TTL setting, or the current setting is used if not given.
+    /// Returns the applied TTL in seconds, or `None` if the stream is not active.
+    fn touch_stream<'a>(
+        &'a self,
+        key: &'a str,
+        ttl: Option<u32>,
+    ) -> BoxFuture<'a, StorageResult<Option<u32>>>;
+
+    /// Write multiple events to the stream, with an atomic check if the stream is active,
+    /// its last event ID matches `expected_last_id` (if given), and its event schema is
+    /// `event_schema` (the schema the events were validated against), skipping duplicate
+    /// events. Streams with a sliding TTL get their expiry refreshed after writing. An empty
+    /// batch only checks that the stream is active and has the given event schema.
+    fn write_events<'a>(
+        &'a self,
+        key: &'a str,
+        events: &'a [AddEvent],
+        expected_last_id: Option<&'a str>,
+        event_schema: Option<&'a str>,
+    ) -> BoxFuture<'a, StorageResult<WriteOutcome>>;
+
+    /// Write the terminal event for the given final status and mark the stream inactive. The
+    /// finish details (if any) are the JSON data of the terminal event, and are stored in the
+    /// stream's metadata. Returns `None` if the stream is not active.
+    fn finish_stream<'a>(
+        &'a self,
+        key: &'a str,
+        status: StreamStatus,
+        details: Option<&'a FinishDetails>,
+    ) -> BoxFuture<'a, StorageResult<Option<RedisStr>>>;
+
+    /// Record a consumer's request to cancel the active stream by writing a `cancel_requested`
+    /// event and flagging the stream's metadata, unless cancellation was already requested.
+    /// Streams that are cancelled on request are also cancelled right away, with the given
+    /// finish details. Returns `None` if the stream is not active.
+    fn request_cancel<'a>(
+        &'a self,
+        key: &'a str,
+        details: &'a FinishDetails,
+    ) -> BoxFuture<'a, StorageResult<Option<CancelRequestOutcome>>>;
+
+    /// Expire the active streams whose TTL has passed by writing their `expired` terminal event
+    /// and marking them expired. Expired streams are kept for the expired retention period.
+    /// Streams without writes for their inactivity timeout are timed out instead, with the
+    /// `timed_out` terminal event. Returns the keys and final status of the finished streams.
+    fn expire_streams(&self) -> BoxFuture<'_, StorageResult<Vec<(String, StreamStatus)>>>;
+
+    /// Compact a finished stream by merging each run of consecutive events with one of the
+    /// given names into a single event with the concatenated data and the ID of the run's
+    /// last event. The stream is replaced atomically. Returns the number of removed entries,
+    /// or `None` if the stream doesn't exist or is still active.
+    fn compact_stream<'a>(
+        &'a self,
+        key: &'a str,
+        events: &'a [String],
+    ) -> BoxFuture<'a, StorageResult<Option<u64>>>;
+
+    /// Get the entries of each stream after the given event ID, in the same order as the
+    /// given `(key, event ID)` pairs. Fails with [`StorageError::StreamNotFound`] if any
+    /// of the streams don't exist.
+    fn range<'a>(
+        &'a self,
+        streams: &'a [(&'a str, &'a str)],
+    ) -> BoxFuture<'a, StorageResult<Vec<StreamRange>>>;
+
+    /// Get a page of the stream's entries between the IDs of the query (inclusive). Fails with
+    /// [`StorageError::StreamNotFound`] if the stream doesn't exist.
+    fn range_page<'a>(
+        &'a self,
+        key: &'a str,
+        query: &'a PageQuery,
+    ) -> BoxFuture<'a, StorageResult<Vec<StreamEntry>>>;
+
+    /// Get the keys of the given streams that are no longer active
+    fn inactive_streams<'a>(
+        &'a self,
+        keys: &'a [&'a str],
+    ) -> BoxFuture<'a, StorageResult<Vec<String>>>;
+
+    /// Add the token ID to the denylist for the given TTL, and notify live consumers
+    fn revoke_token<'a>(&'a self, token_id: &'a str, ttl: i64) -> BoxFuture<'a, StorageResult<()>>;
+
+    /// Revoke all tokens for the stream key/prefix that were issued up to the given time
+    /// (unix milliseconds), and notify live consumers. The revocation is kept for the given
+    /// TTL, which should outlast any of the revoked tokens.
+    fn revoke_key_tokens<'a>(
+        &'a self,
+        key: &'a str,
+        revoked_at: i64,
+        ttl: i64,
+    ) -> BoxFuture<'a, StorageResult<()>>;
+
+    /// Check if the token with the given ID and issue time (unix milliseconds) has been revoked,
+    /// either directly or for any of the given stream keys/prefixes
+    fn is_token_revoked<'a>(
+        &'a self,
+        token_id: &'a str,
+        issued_at: i64,
+        keys: &'a [&'a str],
+    ) -> BoxFuture<'a, StorageResult<bool>>;
+
+    /// Get an exclusive connection for long-running operations.
+    /// Will return `None` if there are too many connections.
+    fn connect(&self) -> BoxFuture<'_, StorageResult<Option<Box<dyn StreamConnection>>>>;
+
+    /// Number of currently available exclusive connections
+    fn available_connections(&self) -> usize;
+
+    /// Prevent new connections, and shut down all current connections
+    fn shutdown(&self) -> BoxFuture<'_, ()>;
+}
+
+/// An exclusive storage connection, for long-running read and write operations
+/// (e.g. streaming events to clients, or ingesting events)
+pub trait StreamConnection: Send + Sync {
+    /// Wait up to `block_ms` for a batch of new entries from the given `(key, event ID)` pairs.
+    /// Returns the entries in order, along with the index of their stream.
+    fn read<'a>(
+        &'a self,
+        streams: &'a [(&'a str, &'a str)],
+        block_ms: u64,
+    ) -> BoxFuture<'a, StorageResult<Vec<(usize, StreamEntry)>>>;
+
+    /// Write events to the stream, with an atomic check if the stream is active, its last
+    /// event ID matches `expected_last_id` (if given), and its event schema is `event_schema`,
+    /// skipping duplicate events.
+    fn write_events<'a>(
+        &'a self,
+        key: &'a str,
+        events: &'a [AddEvent],
+        expected_last_id: Option<&'a str>,
+        event_schema: Option<&'a str>,
+    ) -> BoxFuture<'a, StorageResult<WriteOutcome>>;
+}
@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use futures::{Stream, StreamExt};
+
+use crate::{
+    redis::{MultiCursor, SseEvent, StreamEntry, WsMessage, constants, util},
+    storage::{EventFilter, StorageError, StorageResult, StreamConnection, StreamStorage},
+};
+
+/// Maximum time to block on a read before re-checking stream state.
+const READ_BLOCK_MS: u64 = 30_000;
+
+/// An item read from a multiplexed subscription to several streams
+enum MultiItem {
+    /// An entry from one of the streams, along with the updated cursor of all streams
+    Entry {
+        key: String,
+        entry: StreamEntry,
+        cursor: MultiCursor,
+    },
+    /// An error reading one of the streams, or all of them if there's no key
+    Error {
+        key: Option<String>,
+        error: StorageError,
+    },
+}
+
+/// Stream reader with an exclusive storage connection, for long-running read
+/// operations (e.g. for streaming SSE events from storage to clients)
+pub struct StreamReader {
+    storage: Arc<dyn StreamStorage>,
+    connection: Box<dyn StreamConnection>,
+}
+
+impl StreamReader {
+    pub fn new(storage: Arc<dyn StreamStorage>, connection: Box<dyn StreamConnection>) -> Self {
+        Self {
+            storage,
+            connection,
+        }
+    }
+
+    /// Listen for new events in several streams and return SSE events (tagged with
+    /// their stream key) as a stream
+    pub fn stream_multi_sse_events(
+        self,
+        keys: Vec<String>,
+        cursor: MultiCursor,
+        filter: EventFilter,
+    ) -> impl Stream<Item = SseEvent> + use<> {
+        self.stream_multi_entries(keys, cursor, filter)
+            .map(|item| match item {
+                MultiItem::Entry { key, entry, cursor } => {
+                    entry.into_tagged_sse_event(&key, &cursor)
+                }
+                MultiItem::Error { key, error } => SseEvent::default()
+                    .event(constants::ERROR)
+                    .data(util::tagged_error_json(key.as_deref(), &error.to_string())),
+            })
+    }
+
+    /// Listen for new events in several streams and return JSON-serialized WebSocket
+    /// messages (tagged with their stream key)
+    pub fn stream_multi_ws_events(
+        self,
+        keys: Vec<String>,
+        cursor: MultiCursor,
+        filter: EventFilter,
+    ) -> impl Stream<Item = WsMessage> + use<> {
+        self.stream_multi_entries(keys, cursor, filter)
+            .map(|item| match item {
+                MultiItem::Entry { key, entry, .. } => entry.into_tagged_ws_message(&key),
+                MultiItem::Error { key, error } => {
+                    WsMessage::text(util::tagged_error_json(key.as_deref(), &error.to_string()))
+                }
+            })
+            .chain(futures::stream::once(async { WsMessage::Close(None) }))
+    }
+
+    /// Listen for new entries in several streams that match the filter with one blocking
+    /// read, until all of the streams have ended.
+    fn stream_multi_entries(
+        self,
+        keys: Vec<String>,
+        mut cursor: MultiCursor,
+        filter: EventFilter,
+    ) -> impl Stream<Item = MultiItem> + use<> {
+        async_stream::stream! {
+            let mut active_keys = keys;
+            while !active_keys.is_empty() {
+                let streams: Vec<(&str, &str)> = active_keys
+                    .iter()
+                    .map(|key| (key.as_str(), cursor.get(key).unwrap_or("0-0")))
+                    .collect();
+                let result = self.connection.read(&streams, READ_BLOCK_MS).await;
+                drop(streams);
+
+                match result {
+                    Ok(entries) if entries.is_empty() => {
+                        // Check for streams that are no longer active (e.g. expired)
+                        let keys: Vec<&str> = active_keys.iter().map(String::as_str).collect();
+                        match self.storage.inactive_streams(&keys).await {
+                            Ok(inactive_keys) => {
+                                for key in inactive_keys {
+                                    active_keys.retain(|k| *k != key);
+                                    yield MultiItem::Error { key: Some(key), error: StorageError::StreamNotFound };
+                                }
+                            }
+                            Err(error) => {
+                                yield MultiItem::Error { key: None, error };
+                                break;
+                            }
+                        }
+                    }
+                    Ok(mut entries) => {
+                        // Interleave the batches from each stream in order
+                        entries.sort_by_key(|(_, entry)| entry.id_parts());
+                        let read_keys = active_keys.clone();
+                        for (idx, entry) in entries {
+                            let Some(key) = read_keys.get(idx) else {
+                                continue;
+                            };
+                            cursor.set(key, &entry.id);
+                            if entry.is_end_event() {
+                                active_keys.retain(|k| k != key);
+                            }
+                            if !filter.matches(&entry) {
+                                continue;
+                            }
+                            yield MultiItem::Entry { key: key.to_owned(), entry, cursor: cursor.clone() };
+                        }
+                    }
+                    Err(error) => {
+                        yield MultiItem::Error { key: None, error };
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait for the next batch of events from the stream after the given event ID
+    pub async fn next_stream_events(
+        &self,
+        key: &str,
+        last_event_id: &str,
+    ) -> StorageResult<Vec<StreamEntry>> {
+        loop {
+            let entries = self
+                .connection
+                .read(&[(key, last_event_id)], READ_BLOCK_MS)
+                .await?;
+            if !entries.is_empty() {
+                return Ok(entries.into_iter().map(|(_, entry)| entry).collect());
+            }
+            if !self.storage.inactive_streams(&[key]).await?.is_empty() {
+                return Err(StorageError::StreamNotFound);
+            }
+        }
+    }
+}
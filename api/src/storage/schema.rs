@@ -0,0 +1,249 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::redis::{AddEvent, EventData};
+
+/// Maximum number of compiled validators to cache. The cache is cleared when it's full.
+const MAX_CACHED_VALIDATORS: usize = 1024;
+
+/// Rules for the events that can be added to a stream, stored with the stream as JSON
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EventSchema {
+    /// Names of the events that can be added (any event if not set)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_events: Option<HashSet<String>>,
+    /// JSON Schema for the data of each event name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub schemas: HashMap<String, serde_json::Value>,
+}
+
+impl EventSchema {
+    /// Check if there are no rules, i.e. any event can be added
+    pub fn is_empty(&self) -> bool {
+        self.allowed_events.is_none() && self.schemas.is_empty()
+    }
+
+    /// Compile the JSON Schemas of the events. Fails if any of them are invalid.
+    pub fn compile(self) -> Result<EventValidator, String> {
+        let validators = self
+            .schemas
+            .into_iter()
+            .map(|(event, schema)| match jsonschema::validator_for(&schema) {
+                Ok(validator) => Ok((event, validator)),
+                Err(err) => Err(format!("invalid schema for event '{event}': {err}")),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(EventValidator {
+            allowed_events: self.allowed_events,
+            validators,
+        })
+    }
+}
+
+/// Validates the events added to a stream against its [`EventSchema`]
+pub struct EventValidator {
+    allowed_events: Option<HashSet<String>>,
+    validators: HashMap<String, jsonschema::Validator>,
+}
+
+impl EventValidator {
+    /// Compile the event schema stored with a stream
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let schema: EventSchema =
+            serde_json::from_str(json).map_err(|err| format!("invalid event schema: {err}"))?;
+        schema.compile()
+    }
+
+    /// Check that the event is allowed, and that its data matches the event's schema (events
+    /// without data are checked as `null`)
+    pub fn validate(&self, event: &AddEvent) -> Result<(), String> {
+        let name = &event.event;
+        if self
+            .allowed_events
+            .as_ref()
+            .is_some_and(|allowed| !allowed.contains(name))
+        {
+            return Err(format!("event '{name}' is not allowed"));
+        }
+        let Some(validator) = self.validators.get(name) else {
+            return Ok(());
+        };
+
+        let data = match &event.data {
+            None => serde_json::Value::Null,
+            Some(EventData::Text(text)) => serde_json::Value::String(text.to_string()),
+            Some(EventData::Json(json)) => serde_json::from_str(json).unwrap_or_default(),
+            Some(EventData::Binary(_)) => {
+                return Err(format!(
+                    "binary data of event '{name}' can't be checked against its schema"
+                ));
+            }
+        };
+        validator
+            .validate(&data)
+            .map_err(|err| match err.instance_path().as_str() {
+                "" => format!("invalid data for event '{name}': {err}"),
+                path => format!("invalid data for event '{name}' at '{path}': {err}"),
+            })
+    }
+
+    /// Validate a batch of events, with an error listing each invalid event by its index
+    pub fn validate_all(&self, events: &[AddEvent]) -> Result<(), String> {
+        let errors: Vec<_> = events
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, event)| {
+                let err = self.validate(event).err()?;
+                Some(format!("event {idx}: {err}"))
+            })
+            .collect();
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join("; ")),
+        }
+    }
+}
+
+/// Event schema of a stream (if it has one), with its compiled validator
+#[derive(Clone, Default)]
+pub struct StreamSchema(Option<(Arc<str>, Arc<EventValidator>)>);
+
+impl StreamSchema {
+    /// Get the event schema JSON, which writes check against the schema stored with the stream
+    pub fn json(&self) -> Option<&str> {
+        self.0.as_ref().map(|(json, _)| &**json)
+    }
+
+    /// Get the validator, if the stream has an event schema
+    pub fn validator(&self) -> Option<&EventValidator> {
+        self.0.as_ref().map(|(_, validator)| &**validator)
+    }
+}
+
+/// Cache of the event schema of each stream on this server instance, with its compiled
+/// [`EventValidator`]. Streams that aren't cached are assumed to have no event schema, and
+/// writes check this assumption against the schema stored with the stream.
+#[derive(Default)]
+pub struct EventValidators(Mutex<HashMap<String, StreamSchema>>);
+
+impl EventValidators {
+    /// Get the last seen event schema of the given stream key
+    pub fn get(&self, key: &str) -> StreamSchema {
+        self.0.lock().unwrap().get(key).cloned().unwrap_or_default()
+    }
+
+    /// Update the event schema of the given stream key to the one stored with the stream (e.g.
+    /// when a write found that it changed), compiling it if it isn't cached yet. The schema is
+    /// compared with the cached one, as a stream can be deleted and created again with another
+    /// schema.
+    pub fn update(&self, key: &str, json: Option<&str>) -> Result<StreamSchema, String> {
+        let Some(json) = json else {
+            self.0.lock().unwrap().remove(key);
+            return Ok(StreamSchema::default());
+        };
+        let cached = self.get(key);
+        if cached.json() == Some(json) {
+            return Ok(cached);
+        }
+
+        let validator = Arc::new(EventValidator::from_json(json)?);
+        let schema = StreamSchema(Some((json.into(), validator)));
+        let mut schemas = self.0.lock().unwrap();
+        if schemas.len() >= MAX_CACHED_VALIDATORS {
+            schemas.clear();
+        }
+        schemas.insert(key.to_owned(), schema.clone());
+        Ok(schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(value: serde_json::Value) -> AddEvent {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn validate_events() {
+        let schema: EventSchema = serde_json::from_value(serde_json::json!({
+            "allowed_events": ["delta", "usage", "ping"],
+            "schemas": {
+                "delta": { "type": "string" },
+                "usage": {
+                    "type": "object",
+                    "properties": { "tokens": { "type": "integer" } },
+                    "required": ["tokens"],
+                },
+            },
+        }))
+        .unwrap();
+        let validator = schema.compile().unwrap();
+
+        let valid = [
+            event(serde_json::json!({ "event": "delta", "data": "Hi" })),
+            event(serde_json::json!({ "event": "usage", "data": { "tokens": 2 } })),
+            event(serde_json::json!({ "event": "ping" })),
+        ];
+        assert_eq!(validator.validate_all(&valid), Ok(()));
+
+        let invalid = [
+            event(serde_json::json!({ "event": "delta", "data": "Hi" })),
+            event(serde_json::json!({ "event": "other" })),
+            event(serde_json::json!({ "event": "usage", "data": { "tokens": "2" } })),
+            event(serde_json::json!({ "event": "delta", "data": [1] })),
+        ];
+        assert_eq!(
+            validator.validate_all(&invalid),
+            Err([
+                "event 1: event 'other' is not allowed",
+                r#"event 2: invalid data for event 'usage' at '/tokens': "2" is not of type "integer""#,
+                r#"event 3: invalid data for event 'delta': [1] is not of type "string""#,
+            ]
+            .join("; "))
+        );
+    }
+
+    #[test]
+    fn cached_validators() {
+        let validators = EventValidators::default();
+        assert!(validators.get("stream").json().is_none());
+
+        let json = r#"{"allowed_events":["delta"]}"#;
+        let first = validators.update("stream", Some(json)).unwrap();
+        let second = validators.update("stream", Some(json)).unwrap();
+        assert_eq!(validators.get("stream").json(), Some(json));
+        assert!(std::ptr::eq(
+            first.validator().unwrap(),
+            second.validator().unwrap()
+        ));
+
+        let changed = validators
+            .update("stream", Some(r#"{"allowed_events":["usage"]}"#))
+            .unwrap();
+        let event = event(serde_json::json!({ "event": "usage" }));
+        assert_eq!(changed.validator().unwrap().validate(&event), Ok(()));
+
+        let removed = validators.update("stream", None).unwrap();
+        assert!(removed.validator().is_none());
+        assert!(validators.get("stream").json().is_none());
+    }
+
+    #[test]
+    fn invalid_schema() {
+        let schema = EventSchema {
+            allowed_events: None,
+            schemas: HashMap::from([("delta".into(), serde_json::json!({ "type": 5 }))]),
+        };
+        let Err(err) = schema.compile() else {
+            panic!("should fail to compile");
+        };
+        assert!(err.starts_with("invalid schema for event 'delta'"));
+    }
+}
@@ -0,0 +1,86 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::webhooks::WebhookEvent;
+
+/// Status of a webhook delivery
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Waiting for the first attempt, or for a retry
+    Pending,
+    Delivered,
+    /// All attempts failed, or the endpoint rejected the webhook
+    Failed,
+}
+
+/// Delivery of a webhook to one endpoint
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct WebhookDelivery {
+    /// ID of the webhook (same for all endpoints)
+    pub id: String,
+    /// Lifecycle event of the stream
+    pub event: WebhookEvent,
+    /// Key of the stream
+    pub key: String,
+    /// URL of the endpoint
+    pub url: String,
+    pub status: DeliveryStatus,
+    /// Number of delivery attempts so far
+    pub attempts: u32,
+    /// HTTP status of the endpoint's last response
+    pub response_status: Option<u16>,
+    /// Error of the last failed attempt
+    pub error: Option<String>,
+    /// Time of the event (Unix timestamp in milliseconds)
+    pub created_at: i64,
+}
+
+/// Bounded log of the recent webhook deliveries, dropping the oldest when full
+pub struct DeliveryLog {
+    capacity: usize,
+    deliveries: Mutex<VecDeque<WebhookDelivery>>,
+}
+
+impl DeliveryLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            deliveries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, delivery: WebhookDelivery) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if deliveries.len() >= self.capacity {
+            deliveries.pop_front();
+        }
+        deliveries.push_back(delivery);
+    }
+
+    /// Update the delivery of the webhook to the endpoint, if it's still in the log
+    pub fn update(&self, id: &str, url: &str, update: impl FnOnce(&mut WebhookDelivery)) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if let Some(delivery) = deliveries
+            .iter_mut()
+            .rev()
+            .find(|delivery| delivery.id == id && delivery.url == url)
+        {
+            update(delivery);
+        }
+    }
+
+    /// Get the most recent deliveries, newest first, optionally filtered by status
+    pub fn recent(&self, limit: usize, status: Option<DeliveryStatus>) -> Vec<WebhookDelivery> {
+        let deliveries = self.deliveries.lock().unwrap();
+        deliveries
+            .iter()
+            .rev()
+            .filter(|delivery| status.is_none_or(|status| delivery.status == status))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,231 @@
+//! Signed webhooks for the lifecycle events of streams
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use chacha20poly1305::aead::Generate;
+use hmac::{Hmac, KeyInit, Mac};
+use reqwest::{StatusCode, header::CONTENT_TYPE};
+use schemars::JsonSchema;
+use serde::Serialize;
+use sha2::Sha256;
+use time::UtcDateTime;
+
+use crate::{auth::unix_millis, config::AppConfig};
+
+mod log;
+
+pub use log::{DeliveryLog, DeliveryStatus, WebhookDelivery};
+
+/// Header with the signature of the webhook request (`sha256=<hex HMAC>`)
+pub const SIGNATURE_HEADER: &str = "x-tinistream-signature";
+/// Header with the Unix timestamp (in seconds) of the webhook request, included in the signature
+pub const TIMESTAMP_HEADER: &str = "x-tinistream-timestamp";
+
+/// Delay before retrying a failed delivery, doubled after each attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Maximum delay between delivery attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Number of recent deliveries kept in the delivery log
+const DELIVERY_LOG_SIZE: usize = 200;
+
+/// Lifecycle event of a stream that is sent to the webhook endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+pub enum WebhookEvent {
+    #[serde(rename = "stream.created")]
+    Created,
+    #[serde(rename = "stream.ended")]
+    Ended,
+    #[serde(rename = "stream.cancelled")]
+    Cancelled,
+    #[serde(rename = "stream.expired")]
+    Expired,
+    #[serde(rename = "stream.timed_out")]
+    TimedOut,
+    #[serde(rename = "stream.cancel_requested")]
+    CancelRequested,
+}
+
+/// JSON body of a webhook request
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    event: WebhookEvent,
+    key: &'a str,
+    timestamp: i64,
+}
+
+/// Service that sends the lifecycle events of streams to the configured webhook endpoints
+/// in the background, retrying failed deliveries with exponential backoff
+#[derive(Clone)]
+pub struct Webhooks(Arc<WebhooksInner>);
+
+struct WebhooksInner {
+    client: reqwest::Client,
+    urls: Vec<reqwest::Url>,
+    secret: Vec<u8>,
+    max_attempts: u32,
+    log: DeliveryLog,
+}
+
+impl Webhooks {
+    pub fn new(config: &AppConfig) -> anyhow::Result<Self> {
+        let urls = config
+            .webhook_urls
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(|url| reqwest::Url::parse(url).with_context(|| format!("parse webhook URL {url}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let secret = config.webhook_secret.clone().unwrap_or_default();
+        if !urls.is_empty() && secret.is_empty() {
+            anyhow::bail!("a webhook secret must be set to send webhooks");
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.webhook_timeout.into()))
+            .build()
+            .context("build webhook client")?;
+
+        Ok(Self(Arc::new(WebhooksInner {
+            client,
+            urls,
+            secret: secret.into_bytes(),
+            max_attempts: config.webhook_attempts.max(1),
+            log: DeliveryLog::new(DELIVERY_LOG_SIZE),
+        })))
+    }
+
+    /// Log of the recent webhook deliveries on this server instance
+    pub fn log(&self) -> &DeliveryLog {
+        &self.0.log
+    }
+
+    /// Send the lifecycle event of the stream to all webhook endpoints in the background
+    pub fn send(&self, event: WebhookEvent, key: &str) {
+        if self.0.urls.is_empty() {
+            return;
+        }
+        let id = hex::encode(<[u8; 12]>::generate());
+        let timestamp = unix_millis(UtcDateTime::now());
+        let payload = WebhookPayload {
+            id: &id,
+            event,
+            key,
+            timestamp,
+        };
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(err) => {
+                tracing::error!("Failed to serialize webhook {id}: {err}");
+                return;
+            }
+        };
+
+        for url in &self.0.urls {
+            self.0.log.push(WebhookDelivery {
+                id: id.clone(),
+                event,
+                key: key.to_owned(),
+                url: url.to_string(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                response_status: None,
+                error: None,
+                created_at: timestamp,
+            });
+            tokio::spawn(self.clone().deliver(id.clone(), url.clone(), body.clone()));
+        }
+    }
+
+    /// Deliver the webhook to the endpoint, retrying with exponential backoff on network
+    /// errors, server errors, timeouts, and rate limiting
+    async fn deliver(self, id: String, url: reqwest::Url, body: Vec<u8>) {
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=self.0.max_attempts {
+            let (response_status, error, retryable) = match self.post(&url, &body).await {
+                Ok(status) if status.is_success() => {
+                    self.0.log.update(&id, url.as_str(), |delivery| {
+                        delivery.status = DeliveryStatus::Delivered;
+                        delivery.attempts = attempt;
+                        delivery.response_status = Some(status.as_u16());
+                        delivery.error = None;
+                    });
+                    return;
+                }
+                Ok(status) => (
+                    Some(status.as_u16()),
+                    format!("endpoint responded with {status}"),
+                    status.is_server_error()
+                        || matches!(
+                            status,
+                            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+                        ),
+                ),
+                Err(err) => (None, err.to_string(), true),
+            };
+
+            let retry = retryable && attempt < self.0.max_attempts;
+            if !retry {
+                tracing::warn!("Failed to deliver webhook {id} to {url}: {error}");
+            }
+            self.0.log.update(&id, url.as_str(), |delivery| {
+                delivery.status = match retry {
+                    true => DeliveryStatus::Pending,
+                    false => DeliveryStatus::Failed,
+                };
+                delivery.attempts = attempt;
+                delivery.response_status = response_status;
+                delivery.error = Some(error);
+            });
+            if !retry {
+                return;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Send one signed request to the endpoint
+    async fn post(&self, url: &reqwest::Url, body: &[u8]) -> Result<StatusCode, reqwest::Error> {
+        let timestamp = UtcDateTime::now().unix_timestamp();
+        let response = self
+            .0
+            .client
+            .post(url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(SIGNATURE_HEADER, sign(&self.0.secret, timestamp, body))
+            .body(body.to_vec())
+            .send()
+            .await?;
+
+        Ok(response.status())
+    }
+}
+
+/// Sign the webhook body sent at the given Unix timestamp (in seconds). The signature is the
+/// hex HMAC-SHA256 of `<timestamp>.<body>`, formatted as `sha256=<signature>`.
+pub fn sign(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(itoa::Buffer::new().format(timestamp).as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_payload() {
+        assert_eq!(
+            sign(b"secret", 1_700_000_000, br#"{"key":"a"}"#),
+            "sha256=8a4f6b2720f6800553c80302bf5d0d24e4ef8a34af47d688bdc99288cd141520"
+        );
+    }
+}
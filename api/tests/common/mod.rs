@@ -17,15 +17,20 @@ pub async fn setup_http_server() -> anyhow::Result<(
 
 /// Setup the tinistream Rust client with a backend API key
 pub fn setup_backend_client(port: u16) -> tinistream_client::Client {
+    let http_client = setup_backend_http_client();
+    tinistream_client::Client::new_with_client(&format!("http://localhost:{port}"), http_client)
+}
+
+/// Setup a reqwest client with a backend API key, for sending raw API requests
+pub fn setup_backend_http_client() -> reqwest::Client {
     use reqwest::header::HeaderMap;
 
     let api_key = dotenvy::var("STREAMER_API_KEY").expect("API key not set");
     let mut api_key_header = HeaderMap::new();
     api_key_header.insert("X-API-KEY", api_key.parse().unwrap());
 
-    let http_client = reqwest::Client::builder()
+    reqwest::Client::builder()
         .default_headers(api_key_header)
         .build()
-        .expect("build client");
-    tinistream_client::Client::new_with_client(&format!("http://localhost:{port}"), http_client)
+        .expect("build client")
 }
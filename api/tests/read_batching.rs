@@ -0,0 +1,165 @@
+//! End-to-end benchmark of the Redis commands issued for a live consumer under load
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{Shutdown, TcpStream},
+    thread::JoinHandle,
+};
+
+use eventsource_stream::Eventsource;
+use fred::{prelude::*, types::config::ServerConfig};
+use futures::StreamExt;
+use tinistream_client::{ClientStreamExt, types::StreamRequest};
+
+use crate::common::{setup_backend_client, setup_backend_http_client, setup_http_server};
+
+mod common;
+
+/// Number of events written while the consumer is connected
+const NUM_EVENTS: usize = 1000;
+/// Number of events per write request, like a fast LLM writer flushing tokens
+const EVENTS_PER_WRITE: usize = 10;
+
+#[tokio::test]
+async fn batched_reads_reduce_redis_commands() -> anyhow::Result<()> {
+    // The XREAD commands can only be counted in Redis
+    if dotenvy::var("STREAMER_STORAGE").is_ok_and(|storage| storage == "memory") {
+        return Ok(());
+    }
+
+    // Run the same load without batching, and then with the default batch size
+    // SAFETY: this is the only test in this binary, so no other threads read the environment
+    unsafe { std::env::set_var("STREAMER_READ_BATCH_SIZE", "1") };
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let unbatched_calls = xread_calls_under_load(port).await?;
+    shutdown.await.expect("failed to shutdown server");
+
+    // SAFETY: the previous server has shut down, and this is the only test in this binary
+    unsafe { std::env::remove_var("STREAMER_READ_BATCH_SIZE") };
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let batched_calls = xread_calls_under_load(port).await?;
+    shutdown.await.expect("failed to shutdown server");
+
+    assert!(
+        unbatched_calls >= NUM_EVENTS as u64,
+        "{unbatched_calls} unbatched XREAD calls for {NUM_EVENTS} events"
+    );
+    assert!(
+        batched_calls * 4 < unbatched_calls,
+        "{batched_calls} batched vs. {unbatched_calls} unbatched XREAD calls"
+    );
+
+    Ok(())
+}
+
+/// Stream events to an SSE consumer from the server (with its configured read batch size),
+/// and return the number of `XREAD` commands executed by Redis for the stream
+async fn xread_calls_under_load(port: u16) -> anyhow::Result<u64> {
+    let client = setup_backend_client(port);
+    let http_client = setup_backend_http_client();
+
+    // Create stream and connect consumer
+    let key = format!("bench:{}", rand::random::<u64>());
+    let token = client
+        .create_stream()
+        .body(StreamRequest::builder().key(&key))
+        .send()
+        .await
+        .expect("should create stream")
+        .into_inner()
+        .token;
+    let (monitor, calls) = monitor_xread_calls(&key)?;
+    let res = reqwest::Client::new()
+        .get(format!("http://localhost:{port}/api/client/sse?key={key}"))
+        .bearer_auth(&token)
+        .send()
+        .await?;
+    assert!(res.status().is_success());
+
+    // Write events as fast as possible, then end the stream
+    let writer = tokio::spawn({
+        let key = key.clone();
+        async move {
+            let events: Vec<_> = (0..EVENTS_PER_WRITE)
+                .map(|_| serde_json::json!({ "event": "token", "data": "test_data" }))
+                .collect();
+            for _ in 0..NUM_EVENTS / EVENTS_PER_WRITE {
+                let body = serde_json::json!({ "key": key, "events": events });
+                http_client
+                    .post(format!("http://localhost:{port}/api/event/add"))
+                    .json(&body)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            client
+                .end_stream()
+                .body(StreamRequest::builder().key(key))
+                .send()
+                .await
+                .expect("should end stream");
+            anyhow::Ok(())
+        }
+    });
+
+    // Read all events
+    let events: Vec<_> = res.bytes_stream().eventsource().collect().await;
+    writer.await??;
+    assert_eq!(events.len(), NUM_EVENTS + 2); // including start and end events
+
+    monitor.shutdown(Shutdown::Both)?;
+    let calls = calls.join().expect("monitor thread panicked");
+
+    Ok(calls)
+}
+
+/// Start a Redis `MONITOR` connection counting the `XREAD` commands that read the given stream,
+/// so that commands from other tests sharing the Redis server aren't counted. Returns the
+/// connection (shut it down to stop counting) and the thread returning the count.
+fn monitor_xread_calls(key: &str) -> anyhow::Result<(TcpStream, JoinHandle<u64>)> {
+    let redis_url = dotenvy::var("STREAMER_REDIS_URL").unwrap_or("redis://localhost".into());
+    let config = Config::from_url(&redis_url)?;
+    let ServerConfig::Centralized { server } = &config.server else {
+        anyhow::bail!("benchmark needs a centralized Redis server");
+    };
+
+    let mut connection = TcpStream::connect((server.host.as_str(), server.port))?;
+    let mut reader = BufReader::new(connection.try_clone()?);
+    let mut reply = String::new();
+    if let Some(password) = &config.password {
+        let username = config.username.as_deref().unwrap_or("default");
+        write_command(&mut connection, &["AUTH", username, password])?;
+        reader.read_line(&mut reply)?;
+        anyhow::ensure!(reply.starts_with("+OK"), "Redis AUTH failed: {reply}");
+    }
+    write_command(&mut connection, &["MONITOR"])?;
+    reply.clear();
+    reader.read_line(&mut reply)?;
+    anyhow::ensure!(reply.starts_with("+OK"), "Redis MONITOR failed: {reply}");
+
+    // Each monitored command is a line like `+<time> [<db> <addr>] "XREAD" ... "<stream key>"`
+    let quoted_key = format!("{key}\"");
+    let calls = std::thread::spawn(move || {
+        reader
+            .lines()
+            .map_while(Result::ok)
+            .filter(|line| {
+                line.split(' ')
+                    .nth(3)
+                    .is_some_and(|cmd| cmd.eq_ignore_ascii_case("\"XREAD\""))
+                    && line.contains(&quoted_key)
+            })
+            .count() as u64
+    });
+
+    Ok((connection, calls))
+}
+
+/// Write a command to the Redis connection, encoded as a RESP array of bulk strings
+fn write_command(connection: &mut TcpStream, args: &[&str]) -> std::io::Result<()> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command.push_str(&format!("${}\r\n{arg}\r\n", arg.len()));
+    }
+    connection.write_all(command.as_bytes())
+}
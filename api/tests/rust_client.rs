@@ -9,7 +9,7 @@ use tinistream_client::{
     types::{AddEvent, AddEventsRequest, StreamRequest},
 };
 
-use crate::common::{setup_backend_client, setup_http_server};
+use crate::common::{setup_backend_client, setup_backend_http_client, setup_http_server};
 
 mod common;
 
@@ -262,3 +262,919 @@ struct PrevEvents {
     event: String,
     data: Vec<HashMap<String, String>>,
 }
+
+#[tokio::test]
+async fn revoked_token_disconnects_sse() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let client = setup_backend_client(port);
+    let http_client = setup_backend_http_client();
+
+    // Create stream and get token
+    let key = rand::random::<u16>().to_string();
+    let res = client
+        .create_stream()
+        .body(StreamRequest::builder().key(&key))
+        .send()
+        .await
+        .expect("Should create stream")
+        .into_inner();
+    let frontend_client = setup_frontend_client(&res.token);
+    let sse_url = format!("http://localhost:{port}/api/client/sse?key={key}");
+
+    // Connect to SSE stream and receive the start event
+    let res = frontend_client.get(&sse_url).send().await?;
+    assert!(res.status().is_success());
+    let mut stream = res.bytes_stream().eventsource();
+    let start_event = stream.next().await.expect("should get event")?;
+    assert_eq!(start_event.event, "start");
+
+    // Revoke all tokens for the stream
+    let res = http_client
+        .post(format!("http://localhost:{port}/api/stream/revoke"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+
+    // Live consumer should be disconnected
+    let remaining: Vec<_> = tokio::time::timeout(Duration::from_secs(5), stream.collect())
+        .await
+        .expect("should disconnect consumer");
+    let last_event = remaining
+        .last()
+        .expect("should get event")
+        .as_ref()
+        .unwrap();
+    assert_eq!(last_event.event, "error");
+    assert_eq!(last_event.data, "token revoked");
+
+    // Token should no longer be accepted
+    let res = frontend_client.get(&sse_url).send().await?;
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_sse_multiplexed() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let client = setup_backend_client(port);
+    let http_client = setup_backend_http_client();
+
+    // Create two streams, and a token for both via the key prefix
+    let prefix = format!("dashboard:{}:", rand::random::<u16>());
+    let keys = [format!("{prefix}a"), format!("{prefix}b")];
+    for key in &keys {
+        client
+            .create_stream()
+            .body(StreamRequest::builder().key(key))
+            .send()
+            .await
+            .expect("Should create stream");
+    }
+    let res: serde_json::Value = http_client
+        .post(format!("http://localhost:{port}/api/stream/token"))
+        .json(&serde_json::json!({ "key": format!("{prefix}*") }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let frontend_client = setup_frontend_client(res["token"].as_str().unwrap());
+
+    // Add events to both streams and end them
+    for key in &keys {
+        let test_event = AddEvent::builder()
+            .data("test_data".to_owned())
+            .event("test_event");
+        let body = AddEventsRequest::builder()
+            .key(key)
+            .events(vec![test_event.try_into().unwrap()]);
+        client.add_events().body(body).send().await?;
+        client
+            .end_stream()
+            .body(StreamRequest::builder().key(key))
+            .send()
+            .await?;
+    }
+
+    // Connect to both streams over one SSE connection
+    let sse_url = format!(
+        "http://localhost:{port}/api/client/sse?key={}&key={}",
+        keys[0], keys[1]
+    );
+    let res = frontend_client.get(&sse_url).send().await?;
+    assert!(res.status().is_success());
+    let events: Vec<_> = res.bytes_stream().eventsource().collect().await;
+    let events: Vec<_> = events.into_iter().collect::<Result<_, _>>()?;
+    assert_eq!(events.len(), 6);
+    for key in &keys {
+        let stream_events: Vec<_> = events
+            .iter()
+            .map(|event| serde_json::from_str::<serde_json::Value>(&event.data).unwrap())
+            .filter(|data| data["key"] == key.as_str())
+            .map(|data| data["event"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(stream_events, ["start", "test_event", "end"]);
+    }
+
+    // Reconnect with the cursor of the 4th event to get only the remaining events
+    let res = frontend_client
+        .get(&sse_url)
+        .header("Last-Event-ID", &events[3].id)
+        .send()
+        .await?;
+    let remaining: Vec<_> = res.bytes_stream().eventsource().collect().await;
+    assert_eq!(remaining.len(), 2);
+
+    // Token must be valid for all of the keys
+    let res = frontend_client
+        .get(format!("{sse_url}&key=other"))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn consumers_share_stream_reader() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let client = setup_backend_client(port);
+    let http_client = setup_backend_http_client();
+    let info_url = format!("http://localhost:{port}/api/info");
+
+    // Create stream and get token
+    let key = rand::random::<u16>().to_string();
+    let res = client
+        .create_stream()
+        .body(StreamRequest::builder().key(&key))
+        .send()
+        .await
+        .expect("Should create stream")
+        .into_inner();
+    let frontend_client = setup_frontend_client(&res.token);
+    let sse_url = format!("http://localhost:{port}/api/client/sse?key={key}");
+
+    // Connect several consumers and receive the start event
+    let mut streams = Vec::new();
+    for _ in 0..5 {
+        let res = frontend_client.get(&sse_url).send().await?;
+        assert!(res.status().is_success());
+        let mut stream = res.bytes_stream().eventsource();
+        let start_event = stream.next().await.expect("should get event")?;
+        assert_eq!(start_event.event, "start");
+        streams.push(stream);
+    }
+
+    // All consumers should share one streaming connection
+    let info: serde_json::Value = http_client.get(&info_url).send().await?.json().await?;
+    assert_eq!(info["redis"]["shared_readers"], 1);
+    assert_eq!(info["redis"]["streaming_in_use"], 1);
+
+    // Add events and end the stream
+    let events: Vec<_> = (0..3)
+        .map(|i| {
+            AddEvent::builder()
+                .data(format!("data_{i}"))
+                .event("test_event")
+                .try_into()
+                .unwrap()
+        })
+        .collect();
+    let body = AddEventsRequest::builder().key(&key).events(events);
+    client.add_events().body(body).send().await?;
+    client
+        .end_stream()
+        .body(StreamRequest::builder().key(&key))
+        .send()
+        .await?;
+
+    // Every consumer should receive all events
+    for stream in streams {
+        let events: Vec<_> = tokio::time::timeout(Duration::from_secs(5), stream.collect())
+            .await
+            .expect("should end stream");
+        let events: Vec<_> = events.into_iter().collect::<Result<_, _>>()?;
+        let names: Vec<_> = events.iter().map(|event| event.event.as_str()).collect();
+        assert_eq!(names, ["test_event", "test_event", "test_event", "end"]);
+        assert_eq!(events[2].data, "data_2");
+    }
+
+    // Shared reader should be torn down after the stream ends
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let info: serde_json::Value = http_client.get(&info_url).send().await?.json().await?;
+    assert_eq!(info["redis"]["shared_readers"], 0);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn json_event_data() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+
+    // Create stream and add events with text and JSON data
+    let key = rand::random::<u16>().to_string();
+    let res: serde_json::Value = http_client
+        .post(format!("http://localhost:{port}/api/stream"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = res["token"].as_str().expect("should get token").to_owned();
+    let json_data = serde_json::json!({ "delta": "Hi", "index": [1, 2] });
+    http_client
+        .post(format!("http://localhost:{port}/api/event/add"))
+        .json(&serde_json::json!({
+            "key": key,
+            "events": [
+                { "event": "json_event", "data": json_data },
+                { "event": "text_event", "data": "{\"delta\":\"Hi\"}" },
+            ]
+        }))
+        .send()
+        .await?;
+    http_client
+        .post(format!("http://localhost:{port}/api/stream/end"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?;
+    let frontend_client = setup_frontend_client(&token);
+
+    // WebSocket consumers get the JSON data embedded, and text data as a string
+    let res = frontend_client
+        .get(format!("http://localhost:{port}/api/client/ws?key={key}"))
+        .upgrade()
+        .send()
+        .await?;
+    let mut websocket = res.into_websocket().await?;
+    let Some(Ok(reqwest_websocket::Message::Text(text))) = websocket.next().await else {
+        panic!("should get previous events");
+    };
+    let prev_events: serde_json::Value = serde_json::from_str(&text)?;
+    assert_eq!(prev_events["data"][1]["data"], json_data);
+    assert_eq!(prev_events["data"][2]["data"], "{\"delta\":\"Hi\"}");
+
+    // SSE consumers get the serialized JSON data
+    let res = frontend_client
+        .get(format!("http://localhost:{port}/api/client/sse?key={key}"))
+        .send()
+        .await?;
+    let events: Vec<_> = res.bytes_stream().eventsource().collect().await;
+    let events: Vec<_> = events.into_iter().collect::<Result<_, _>>()?;
+    assert_eq!(events[1].event, "json_event");
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&events[1].data)?,
+        json_data
+    );
+    assert_eq!(events[2].data, "{\"delta\":\"Hi\"}");
+
+    // Stored events are returned with the JSON data
+    let page: serde_json::Value = http_client
+        .get(format!("http://localhost:{port}/api/stream/events"))
+        .query(&[("key", &key)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(page["events"][1]["data"], json_data);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn binary_event_data() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+
+    // Create stream and subscribe via WebSocket
+    let key = rand::random::<u16>().to_string();
+    let res: serde_json::Value = http_client
+        .post(format!("http://localhost:{port}/api/stream"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = res["token"].as_str().expect("should get token").to_owned();
+    let frontend_client = setup_frontend_client(&token);
+    let res = frontend_client
+        .get(format!("http://localhost:{port}/api/client/ws?key={key}"))
+        .upgrade()
+        .send()
+        .await?;
+    let mut consumer = res.into_websocket().await?;
+    let Some(Ok(reqwest_websocket::Message::Text(_))) = consumer.next().await else {
+        panic!("should get previous events");
+    };
+
+    // Add binary events via a WebSocket binary frame, and via base64 in JSON
+    let res = http_client
+        .get(format!(
+            "http://localhost:{port}/api/event/add/ws-stream?key={key}"
+        ))
+        .upgrade()
+        .send()
+        .await?;
+    let mut ingest = res.into_websocket().await?;
+    let mut frame = b"{\"event\":\"audio\"}\n".to_vec();
+    frame.extend_from_slice(&[0, 1, 2, 255]);
+    ingest
+        .send(reqwest_websocket::Message::Binary(frame.into()))
+        .await?;
+    let Some(Ok(reqwest_websocket::Message::Text(text))) = ingest.next().await else {
+        panic!("should get ingest response");
+    };
+    let audio_response: serde_json::Value = serde_json::from_str(&text)?;
+    ingest
+        .send(reqwest_websocket::Message::Binary(
+            b"no header".to_vec().into(),
+        ))
+        .await?;
+    let Some(Ok(reqwest_websocket::Message::Text(text))) = ingest.next().await else {
+        panic!("should get ingest response");
+    };
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&text)?["status"],
+        "error"
+    );
+    // Binary frames with a JSON event (without a header line) are still accepted
+    ingest
+        .send(reqwest_websocket::Message::Binary(
+            b"{\"event\":\"text\",\"data\":\"hi\"}".to_vec().into(),
+        ))
+        .await?;
+    let Some(Ok(reqwest_websocket::Message::Text(text))) = ingest.next().await else {
+        panic!("should get ingest response");
+    };
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&text)?["status"],
+        "success"
+    );
+    ingest
+        .close(reqwest_websocket::CloseCode::Normal, None)
+        .await?;
+
+    http_client
+        .post(format!("http://localhost:{port}/api/event/add"))
+        .json(&serde_json::json!({
+            "key": key,
+            "events": [{ "event": "image", "data": "AAH/", "encoding": "base64" }]
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    http_client
+        .post(format!("http://localhost:{port}/api/stream/end"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?;
+
+    // WebSocket consumers get binary frames, with a JSON header line before the raw data
+    for (event, data) in [("audio", &[0u8, 1, 2, 255][..]), ("image", &[0, 1, 255])] {
+        if event == "image" {
+            let Some(Ok(reqwest_websocket::Message::Text(text))) = consumer.next().await else {
+                panic!("should get text event");
+            };
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&text)?["data"],
+                "hi"
+            );
+        }
+        let Some(Ok(reqwest_websocket::Message::Binary(frame))) = consumer.next().await else {
+            panic!("should get binary frame");
+        };
+        let header_len = frame.iter().position(|b| *b == b'\n').expect("header line");
+        let header: serde_json::Value = serde_json::from_slice(&frame[..header_len])?;
+        assert_eq!(header["event"], event);
+        assert!(header["id"].is_string());
+        assert_eq!(&frame[header_len + 1..], data);
+    }
+    let Some(Ok(reqwest_websocket::Message::Text(text))) = consumer.next().await else {
+        panic!("should get end event");
+    };
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&text)?["event"],
+        "end"
+    );
+
+    // SSE consumers get the data as base64
+    let res = frontend_client
+        .get(format!("http://localhost:{port}/api/client/sse?key={key}"))
+        .send()
+        .await?;
+    let events: Vec<_> = res.bytes_stream().eventsource().collect().await;
+    let events: Vec<_> = events.into_iter().collect::<Result<_, _>>()?;
+    assert_eq!(events[1].event, "audio");
+    assert_eq!(events[1].data, "AAEC/w==");
+    assert_eq!(events[3].data, "AAH/");
+
+    // Stored events are returned as base64 with the encoding
+    let page: serde_json::Value = http_client
+        .get(format!("http://localhost:{port}/api/stream/events"))
+        .query(&[("key", &key)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(page["events"][1]["data"], "AAEC/w==");
+    assert_eq!(page["events"][1]["encoding"], "base64");
+    assert!(page["events"][2].get("encoding").is_none());
+    assert_eq!(
+        audio_response,
+        serde_json::json!({
+            "status": "success",
+            "num_events": 1,
+            "last_id": page["events"][1]["id"]
+        })
+    );
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn consumer_event_filter() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let add_events = async |key: &str, events: &[&str]| -> anyhow::Result<()> {
+        let events: Vec<_> = events
+            .iter()
+            .map(|event| serde_json::json!({ "event": event, "data": "data" }))
+            .collect();
+        http_client
+            .post(format!("http://localhost:{port}/api/event/add"))
+            .json(&serde_json::json!({ "key": key, "events": events }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    };
+
+    // Create stream and add events before the WebSocket consumer connects
+    let key = rand::random::<u16>().to_string();
+    let res: serde_json::Value = http_client
+        .post(format!("http://localhost:{port}/api/stream"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = res["token"].as_str().expect("should get token").to_owned();
+    add_events(&key, &["token", "usage", "tool_call"]).await?;
+
+    // Excluded events are filtered from the previous events and live events
+    let frontend_client = setup_frontend_client(&token);
+    let res = frontend_client
+        .get(format!(
+            "http://localhost:{port}/api/client/ws?key={key}&events=-usage,-start"
+        ))
+        .upgrade()
+        .send()
+        .await?;
+    let mut websocket = res.into_websocket().await?;
+    let Some(Ok(reqwest_websocket::Message::Text(text))) = websocket.next().await else {
+        panic!("should get previous events");
+    };
+    let prev_events: serde_json::Value = serde_json::from_str(&text)?;
+    let prev_names: Vec<_> = prev_events["data"]
+        .as_array()
+        .expect("should be an array")
+        .iter()
+        .map(|event| event["event"].as_str().unwrap_or_default().to_owned())
+        .collect();
+    assert_eq!(prev_names, ["token", "tool_call"]);
+
+    add_events(&key, &["usage", "token"]).await?;
+    http_client
+        .post(format!("http://localhost:{port}/api/stream/end"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?;
+    let mut live_names = Vec::new();
+    while let Some(Ok(reqwest_websocket::Message::Text(text))) = websocket.next().await {
+        let event: serde_json::Value = serde_json::from_str(&text)?;
+        live_names.push(event["event"].as_str().unwrap_or_default().to_owned());
+    }
+    assert_eq!(live_names, ["token", "end"]);
+
+    // Included events are delivered to SSE consumers, along with the terminal event
+    let res = frontend_client
+        .get(format!("http://localhost:{port}/api/client/sse"))
+        .query(&[("key", key.as_str()), ("events", "token")])
+        .send()
+        .await?;
+    let events: Vec<_> = res.bytes_stream().eventsource().collect().await;
+    let events: Vec<_> = events.into_iter().collect::<Result<_, _>>()?;
+    let sse_names: Vec<_> = events.iter().map(|event| event.event.as_str()).collect();
+    assert_eq!(sse_names, ["token", "token", "end"]);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sequential_event_ids() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let add_url = format!("http://localhost:{port}/api/event/add");
+
+    let key = rand::random::<u16>().to_string();
+    let res: serde_json::Value = http_client
+        .post(format!("http://localhost:{port}/api/stream"))
+        .json(&serde_json::json!({ "key": key, "sequential_ids": true }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = res["token"].as_str().expect("should get token").to_owned();
+
+    // Events get the next sequence number, or their explicit ID
+    let res: serde_json::Value = http_client
+        .post(&add_url)
+        .json(&serde_json::json!({
+            "key": key,
+            "events": [{ "event": "token" }, { "event": "token" }, { "event": "row", "id": "100" }],
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    assert_eq!(res["last_id"], "100-0");
+
+    // Explicit IDs must be greater than the last event ID
+    let res = http_client
+        .post(&add_url)
+        .json(&serde_json::json!({ "key": key, "events": [{ "event": "row", "id": "100-0" }] }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+    let error: serde_json::Value = res.json().await?;
+    assert_eq!(error["error"]["last_id"], "100-0");
+    let res = http_client
+        .post(&add_url)
+        .json(&serde_json::json!({ "key": key, "events": [{ "event": "row", "id": "abc" }] }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    http_client
+        .post(format!("http://localhost:{port}/api/stream/end"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?;
+
+    // Consumers can resume after a sequence number
+    let res = setup_frontend_client(&token)
+        .get(format!("http://localhost:{port}/api/client/sse?key={key}"))
+        .header("Last-Event-ID", "1")
+        .send()
+        .await?;
+    let events: Vec<_> = res.bytes_stream().eventsource().collect().await;
+    let events: Vec<_> = events.into_iter().collect::<Result<_, _>>()?;
+    let ids: Vec<_> = events.iter().map(|event| event.id.as_str()).collect();
+    assert_eq!(ids, ["2-0", "100-0", "101-0"]);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn ended_stream_compaction() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+
+    let key = rand::random::<u16>().to_string();
+    let res: serde_json::Value = http_client
+        .post(format!("http://localhost:{port}/api/stream"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = res["token"].as_str().expect("should get token").to_owned();
+    http_client
+        .post(format!("http://localhost:{port}/api/event/add"))
+        .json(&serde_json::json!({
+            "key": key,
+            "events": [
+                { "event": "delta", "data": "Hello" },
+                { "event": "delta", "data": ", " },
+                { "event": "delta", "data": "world" },
+                { "event": "usage", "data": { "tokens": 3 } },
+                { "event": "delta", "data": "!" },
+            ],
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    let res: serde_json::Value = http_client
+        .post(format!("http://localhost:{port}/api/stream/end"))
+        .json(&serde_json::json!({ "key": key, "compact": ["delta"] }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    assert_eq!(res["compacted"], true);
+
+    // Late joiners replay the merged events, along with the start and end sentinels
+    let res = setup_frontend_client(&token)
+        .get(format!("http://localhost:{port}/api/client/sse?key={key}"))
+        .send()
+        .await?;
+    let events: Vec<_> = res.bytes_stream().eventsource().collect().await;
+    let events: Vec<_> = events.into_iter().collect::<Result<_, _>>()?;
+    let events: Vec<_> = events
+        .iter()
+        .map(|event| (event.event.as_str(), event.data.as_str()))
+        .collect();
+    assert_eq!(
+        events,
+        [
+            ("start", " "),
+            ("delta", "Hello, world"),
+            ("usage", r#"{"tokens":3}"#),
+            ("delta", "!"),
+            ("end", " "),
+        ]
+    );
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn expired_stream_notifies_consumers() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let stream_url = format!("http://localhost:{port}/api/stream");
+
+    let key = rand::random::<u16>().to_string();
+    let res: serde_json::Value = http_client
+        .post(&stream_url)
+        .json(&serde_json::json!({ "key": key, "ttl": 1 }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = res["token"].as_str().expect("should get token").to_owned();
+    http_client
+        .post(format!("http://localhost:{port}/api/event/add"))
+        .json(&serde_json::json!({ "key": key, "events": [{ "event": "delta", "data": "Hi" }] }))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    // Live consumers receive the expired event once the TTL passes
+    let res = setup_frontend_client(&token)
+        .get(format!("http://localhost:{port}/api/client/sse?key={key}"))
+        .send()
+        .await?;
+    let events = res.bytes_stream().eventsource().collect::<Vec<_>>();
+    let events = tokio::time::timeout(std::time::Duration::from_secs(5), events).await?;
+    let events: Vec<_> = events.into_iter().collect::<Result<_, _>>()?;
+    let events: Vec<_> = events.iter().map(|event| event.event.as_str()).collect();
+    assert_eq!(events, ["start", "delta", "expired"]);
+
+    // The expired stream is kept for the management API, but can't be written to
+    let info: serde_json::Value = http_client
+        .get(format!("{stream_url}/info"))
+        .query(&[("key", &key)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(info["status"], "expired");
+    assert_eq!(info["length"], 3);
+    let expired_streams: Vec<serde_json::Value> = http_client
+        .get(&stream_url)
+        .query(&[("status", "expired"), ("pattern", &key)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(expired_streams.len(), 1);
+    let res = http_client
+        .post(format!("http://localhost:{port}/api/event/add"))
+        .json(&serde_json::json!({ "key": key, "events": [{ "event": "late" }] }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn inactive_stream_times_out() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let stream_url = format!("http://localhost:{port}/api/stream");
+
+    let key = rand::random::<u16>().to_string();
+    let res: serde_json::Value = http_client
+        .post(&stream_url)
+        .json(&serde_json::json!({ "key": key, "inactivity_timeout": 1 }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = res["token"].as_str().expect("should get token").to_owned();
+
+    // Writes keep the stream alive, and consumers are notified once the writer goes quiet
+    let res = setup_frontend_client(&token)
+        .get(format!("http://localhost:{port}/api/client/sse?key={key}"))
+        .send()
+        .await?;
+    for _ in 0..3 {
+        tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+        http_client
+            .post(format!("http://localhost:{port}/api/event/add"))
+            .json(
+                &serde_json::json!({ "key": key, "events": [{ "event": "delta", "data": "Hi" }] }),
+            )
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+    let events = res.bytes_stream().eventsource().collect::<Vec<_>>();
+    let events = tokio::time::timeout(std::time::Duration::from_secs(5), events).await?;
+    let events: Vec<_> = events.into_iter().collect::<Result<_, _>>()?;
+    let events: Vec<_> = events.iter().map(|event| event.event.as_str()).collect();
+    assert_eq!(events, ["start", "delta", "delta", "delta", "timed_out"]);
+
+    let info: serde_json::Value = http_client
+        .get(format!("{stream_url}/info"))
+        .query(&[("key", &key)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(info["status"], "timed_out");
+
+    // The timeout can't exceed the stream's TTL
+    let res = http_client
+        .post(&stream_url)
+        .json(&serde_json::json!({ "key": "other", "ttl": 10, "inactivity_timeout": 20 }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn consumers_request_cancellation() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let stream_url = format!("http://localhost:{port}/api/stream");
+    let create_stream = async |body: serde_json::Value| -> anyhow::Result<String> {
+        http_client
+            .post(&stream_url)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        let res: serde_json::Value = http_client
+            .post(format!("{stream_url}/token"))
+            .json(&serde_json::json!({ "key": body["key"], "scopes": ["read", "cancel"] }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(res["token"].as_str().expect("should get token").to_owned())
+    };
+
+    // By default, the request is only recorded for the producer
+    let key = rand::random::<u16>().to_string();
+    let token = create_stream(serde_json::json!({ "key": key })).await?;
+    let cancel_url = format!("http://localhost:{port}/api/client/cancel?key={key}");
+    for _ in 0..2 {
+        let res: serde_json::Value = setup_frontend_client(&token)
+            .post(&cancel_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(res["status"], "active");
+    }
+    let info: serde_json::Value = http_client
+        .get(format!("{stream_url}/info"))
+        .query(&[("key", &key)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(info["status"], "active");
+    assert_eq!(info["cancel_requested"], true);
+    let page: serde_json::Value = http_client
+        .get(format!("{stream_url}/events?key={key}"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let events: Vec<_> = page["events"]
+        .as_array()
+        .expect("should get events")
+        .iter()
+        .map(|event| event["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(events, ["start", "cancel_requested"]);
+
+    // Streams can opt in to being cancelled right away, here via a WebSocket control message
+    let key = rand::random::<u16>().to_string();
+    let token = create_stream(serde_json::json!({ "key": key, "cancel_on_request": true })).await?;
+    let res = setup_frontend_client(&token)
+        .get(format!("http://localhost:{port}/api/client/ws?key={key}"))
+        .upgrade()
+        .send()
+        .await?;
+    let mut consumer = res.into_websocket().await?;
+    let Some(Ok(reqwest_websocket::Message::Text(_))) = consumer.next().await else {
+        panic!("should get previous events");
+    };
+    consumer
+        .send(reqwest_websocket::Message::Text(
+            "{\"type\":\"stop\"}".into(),
+        ))
+        .await?;
+    let Some(Ok(reqwest_websocket::Message::Text(text))) = consumer.next().await else {
+        panic!("should get error message");
+    };
+    let error: serde_json::Value = serde_json::from_str(&text)?;
+    assert_eq!(error["event"], "error");
+    assert_eq!(error["data"], "invalid control message");
+
+    // Like via `/api/client/cancel`, the token needs the cancel scope
+    let res: serde_json::Value = http_client
+        .post(format!("{stream_url}/token"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let read_token = res["token"].as_str().expect("should get token");
+    let res = setup_frontend_client(read_token)
+        .post(format!(
+            "http://localhost:{port}/api/client/cancel?key={key}"
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+    let res = setup_frontend_client(read_token)
+        .get(format!("http://localhost:{port}/api/client/ws?key={key}"))
+        .upgrade()
+        .send()
+        .await?;
+    let mut read_consumer = res.into_websocket().await?;
+    let Some(Ok(reqwest_websocket::Message::Text(_))) = read_consumer.next().await else {
+        panic!("should get previous events");
+    };
+    read_consumer
+        .send(reqwest_websocket::Message::Text(
+            "{\"type\":\"cancel\"}".into(),
+        ))
+        .await?;
+    let Some(Ok(reqwest_websocket::Message::Text(text))) = read_consumer.next().await else {
+        panic!("should get error message");
+    };
+    let error: serde_json::Value = serde_json::from_str(&text)?;
+    assert_eq!(error["event"], "error");
+    assert_eq!(error["data"], "forbidden");
+    drop(read_consumer);
+
+    consumer
+        .send(reqwest_websocket::Message::Text(
+            "{\"type\":\"cancel\"}".into(),
+        ))
+        .await?;
+    let mut events = Vec::new();
+    while let Some(Ok(reqwest_websocket::Message::Text(text))) = consumer.next().await {
+        let event: serde_json::Value = serde_json::from_str(&text)?;
+        events.push(event["event"].as_str().unwrap().to_owned());
+    }
+    assert_eq!(events, ["cancel_requested", "cancel"]);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
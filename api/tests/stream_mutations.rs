@@ -1,9 +1,11 @@
+use futures::{SinkExt, StreamExt};
+use reqwest_websocket::Upgrade;
 use tinistream_client::{
     ClientIngestExt, ClientStreamExt, Error, ResponseValue,
     types::{AddEvent, AddEventsRequest, ErrorResponse, StreamRequest},
 };
 
-use crate::common::{setup_backend_client, setup_http_server};
+use crate::common::{setup_backend_client, setup_backend_http_client, setup_http_server};
 
 mod common;
 
@@ -78,3 +80,911 @@ async fn mutations_are_state_guarded() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn stream_settings_are_bounded() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let client = setup_backend_client(port);
+    let http_client = setup_backend_http_client();
+    let key = rand::random::<u16>().to_string();
+    let create_url = format!("http://localhost:{port}/api/stream");
+
+    let over_limit = http_client
+        .post(&create_url)
+        .json(&serde_json::json!({ "key": key, "ttl": u32::MAX }))
+        .send()
+        .await?;
+    assert_eq!(over_limit.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    let zero_len = http_client
+        .post(&create_url)
+        .json(&serde_json::json!({ "key": key, "max_len": 0 }))
+        .send()
+        .await?;
+    assert_eq!(zero_len.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    let res = http_client
+        .post(&create_url)
+        .json(&serde_json::json!({ "key": key, "ttl": 60, "max_len": 100 }))
+        .send()
+        .await?;
+    assert!(res.status().is_success());
+
+    let info = client
+        .get_stream_info()
+        .key(&key)
+        .send()
+        .await
+        .expect("should get stream info");
+    assert!(info.ttl > 0 && info.ttl <= 60);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn streams_filtered_by_attributes() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let user = rand::random::<u32>().to_string();
+    let keys = [rand::random::<u16>(), rand::random::<u16>()].map(|k| format!("{user}:{k}"));
+    let stream_url = format!("http://localhost:{port}/api/stream");
+
+    for (key, model) in keys.iter().zip(["model-a", "model-b"]) {
+        let res = http_client
+            .post(&stream_url)
+            .json(&serde_json::json!({
+                "key": key,
+                "attributes": { "user": user, "model": model }
+            }))
+            .send()
+            .await?;
+        assert!(res.status().is_success());
+    }
+
+    let info: serde_json::Value = http_client
+        .get(format!("{stream_url}/info"))
+        .query(&[("key", &keys[0])])
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(info["attributes"]["user"], user.as_str());
+    assert_eq!(info["attributes"]["model"], "model-a");
+
+    let user_streams: Vec<serde_json::Value> = http_client
+        .get(&stream_url)
+        .query(&[("attr.user", &user)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(user_streams.len(), 2);
+
+    let model_streams: Vec<serde_json::Value> = http_client
+        .get(&stream_url)
+        .query(&[("attr.user", user.as_str()), ("attr.model", "model-b")])
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(model_streams.len(), 1);
+    assert_eq!(model_streams[0]["key"], keys[1].as_str());
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn scoped_tokens_guard_ingest() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let client = setup_backend_client(port);
+    let http_client = setup_backend_http_client();
+    let key = rand::random::<u16>().to_string();
+
+    let res = client
+        .create_stream()
+        .body(StreamRequest::builder().key(&key))
+        .send()
+        .await
+        .expect("should create stream");
+    let read_token = res.into_inner().token;
+
+    let res: serde_json::Value = http_client
+        .post(format!("http://localhost:{port}/api/stream/token"))
+        .json(&serde_json::json!({ "key": key, "scopes": ["write"] }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let write_token = res["token"].as_str().expect("should get token").to_owned();
+
+    let add_url = format!("http://localhost:{port}/api/event/add");
+    let body = serde_json::json!({ "key": key, "events": [{ "event": "test_event" }] });
+    let no_auth = reqwest::Client::new()
+        .post(&add_url)
+        .json(&body)
+        .send()
+        .await?;
+    assert_eq!(no_auth.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let read_only = reqwest::Client::new()
+        .post(&add_url)
+        .bearer_auth(&read_token)
+        .json(&body)
+        .send()
+        .await?;
+    assert_eq!(read_only.status(), reqwest::StatusCode::FORBIDDEN);
+
+    let other_stream = reqwest::Client::new()
+        .post(&add_url)
+        .bearer_auth(&write_token)
+        .json(&serde_json::json!({ "key": "other", "events": [{ "event": "test_event" }] }))
+        .send()
+        .await?;
+    assert_eq!(other_stream.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let res: serde_json::Value = reqwest::Client::new()
+        .post(&add_url)
+        .bearer_auth(&write_token)
+        .json(&body)
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(res["num_events"], 1);
+
+    let sse_with_write_token = reqwest::Client::new()
+        .get(format!("http://localhost:{port}/api/client/sse?key={key}"))
+        .bearer_auth(&write_token)
+        .send()
+        .await?;
+    assert_eq!(
+        sse_with_write_token.status(),
+        reqwest::StatusCode::FORBIDDEN
+    );
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn prefix_tokens_cover_matching_streams() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let client = setup_backend_client(port);
+    let http_client = setup_backend_http_client();
+    let prefix = format!("user:{}:", rand::random::<u16>());
+
+    for tab in ["tab-1", "tab-2"] {
+        client
+            .create_stream()
+            .body(StreamRequest::builder().key(format!("{prefix}{tab}")))
+            .send()
+            .await
+            .expect("should create stream");
+    }
+
+    let res: serde_json::Value = http_client
+        .post(format!("http://localhost:{port}/api/stream/token"))
+        .json(&serde_json::json!({ "key": format!("{prefix}*"), "scopes": ["write"] }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let prefix_token = res["token"].as_str().expect("should get token").to_owned();
+
+    let add_url = format!("http://localhost:{port}/api/event/add");
+    for tab in ["tab-1", "tab-2"] {
+        let res: serde_json::Value = reqwest::Client::new()
+            .post(&add_url)
+            .bearer_auth(&prefix_token)
+            .json(&serde_json::json!({ "key": format!("{prefix}{tab}"), "events": [{ "event": "test_event" }] }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(res["num_events"], 1);
+    }
+
+    let outside_prefix = reqwest::Client::new()
+        .post(&add_url)
+        .bearer_auth(&prefix_token)
+        .json(&serde_json::json!({ "key": "other", "events": [{ "event": "test_event" }] }))
+        .send()
+        .await?;
+    assert_eq!(outside_prefix.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    // Revoking one stream key blocks the prefix token for that stream only
+    http_client
+        .post(format!("http://localhost:{port}/api/stream/revoke"))
+        .json(&serde_json::json!({ "key": format!("{prefix}tab-1") }))
+        .send()
+        .await?
+        .error_for_status()?;
+    for (tab, status) in [
+        ("tab-1", reqwest::StatusCode::UNAUTHORIZED),
+        ("tab-2", reqwest::StatusCode::OK),
+    ] {
+        let res = reqwest::Client::new()
+            .post(&add_url)
+            .bearer_auth(&prefix_token)
+            .json(&serde_json::json!({ "key": format!("{prefix}{tab}"), "events": [{ "event": "test_event" }] }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), status);
+    }
+
+    // Stream keys can't contain the wildcard, so a token for a stream can't act as a prefix
+    let wildcard_key = http_client
+        .post(format!("http://localhost:{port}/api/stream"))
+        .json(&serde_json::json!({ "key": format!("{prefix}*") }))
+        .send()
+        .await?;
+    assert_eq!(wildcard_key.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    // Keys are bounded in length, also when a prefix token would cover them
+    let long_key = format!("{prefix}{}", "x".repeat(256));
+    let long_create = http_client
+        .post(format!("http://localhost:{port}/api/stream"))
+        .json(&serde_json::json!({ "key": long_key }))
+        .send()
+        .await?;
+    assert_eq!(long_create.status(), reqwest::StatusCode::BAD_REQUEST);
+    let long_ingest = reqwest::Client::new()
+        .post(&add_url)
+        .bearer_auth(&prefix_token)
+        .json(&serde_json::json!({ "key": long_key, "events": [{ "event": "test_event" }] }))
+        .send()
+        .await?;
+    assert_eq!(long_ingest.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    // A bare wildcard would cover every stream, so the prefix can't be empty
+    let empty_prefix = http_client
+        .post(format!("http://localhost:{port}/api/stream/token"))
+        .json(&serde_json::json!({ "key": "*", "scopes": ["read"] }))
+        .send()
+        .await?;
+    assert_eq!(empty_prefix.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn revoked_tokens_are_rejected() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let key = rand::random::<u16>().to_string();
+
+    http_client
+        .post(format!("http://localhost:{port}/api/stream"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?;
+    let mut write_tokens = Vec::new();
+    for _ in 0..2 {
+        let res: serde_json::Value = http_client
+            .post(format!("http://localhost:{port}/api/stream/token"))
+            .json(&serde_json::json!({ "key": key, "scopes": ["write"] }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        write_tokens.push(res["token"].as_str().expect("should get token").to_owned());
+    }
+    let (token, other_token) = (&write_tokens[0], &write_tokens[1]);
+
+    let res = http_client
+        .post(format!("http://localhost:{port}/api/stream/revoke"))
+        .json(&serde_json::json!({ "token": token }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+
+    let add_url = format!("http://localhost:{port}/api/event/add");
+    let body = serde_json::json!({ "key": key, "events": [{ "event": "test_event" }] });
+    let revoked = reqwest::Client::new()
+        .post(&add_url)
+        .bearer_auth(token)
+        .json(&body)
+        .send()
+        .await?;
+    assert_eq!(revoked.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let res: serde_json::Value = reqwest::Client::new()
+        .post(&add_url)
+        .bearer_auth(other_token)
+        .json(&body)
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(res["num_events"], 1);
+
+    // Revoking a key prefix also covers the tokens for the streams under it
+    let res = http_client
+        .post(format!("http://localhost:{port}/api/stream/revoke"))
+        .json(&serde_json::json!({ "key": format!("{key}*") }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+    let revoked = reqwest::Client::new()
+        .post(&add_url)
+        .bearer_auth(other_token)
+        .json(&body)
+        .send()
+        .await?;
+    assert_eq!(revoked.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let no_target = http_client
+        .post(format!("http://localhost:{port}/api/stream/revoke"))
+        .json(&serde_json::json!({}))
+        .send()
+        .await?;
+    assert_eq!(no_target.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn revoked_tokens_stop_ws_ingest() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let key = rand::random::<u16>().to_string();
+
+    http_client
+        .post(format!("http://localhost:{port}/api/stream"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?;
+    let res: serde_json::Value = http_client
+        .post(format!("http://localhost:{port}/api/stream/token"))
+        .json(&serde_json::json!({ "key": key, "scopes": ["write"] }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = res["token"].as_str().expect("should get token").to_owned();
+
+    let res = reqwest::Client::new()
+        .get(format!(
+            "http://localhost:{port}/api/event/add/ws-stream?key={key}"
+        ))
+        .bearer_auth(&token)
+        .upgrade()
+        .send()
+        .await?;
+    let mut ingest = res.into_websocket().await?;
+    let event = serde_json::json!({ "event": "test_event" }).to_string();
+    ingest
+        .send(reqwest_websocket::Message::Text(event.into()))
+        .await?;
+    let Some(Ok(reqwest_websocket::Message::Text(text))) = ingest.next().await else {
+        panic!("should get ingest response");
+    };
+    let response: serde_json::Value = serde_json::from_str(&text)?;
+    assert_eq!(response["status"], "success");
+
+    // Revoking the token sends an error and closes the producer's connection
+    http_client
+        .post(format!("http://localhost:{port}/api/stream/revoke"))
+        .json(&serde_json::json!({ "token": token }))
+        .send()
+        .await?
+        .error_for_status()?;
+    let message = tokio::time::timeout(std::time::Duration::from_secs(5), ingest.next())
+        .await
+        .expect("should get revoked message");
+    let Some(Ok(reqwest_websocket::Message::Text(text))) = message else {
+        panic!("should get error response");
+    };
+    let response: serde_json::Value = serde_json::from_str(&text)?;
+    assert_eq!(
+        response,
+        serde_json::json!({ "status": "error", "message": "token revoked" })
+    );
+    let Some(Ok(reqwest_websocket::Message::Close { .. })) = ingest.next().await else {
+        panic!("should close the connection");
+    };
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn stream_events_are_paginated() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let key = rand::random::<u16>().to_string();
+    let stream_url = format!("http://localhost:{port}/api/stream");
+
+    http_client
+        .post(&stream_url)
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?;
+    let events: Vec<_> = (0..5)
+        .map(|i| serde_json::json!({ "event": format!("event_{i}") }))
+        .collect();
+    http_client
+        .post(format!("http://localhost:{port}/api/event/add"))
+        .json(&serde_json::json!({ "key": key, "events": events }))
+        .send()
+        .await?;
+    http_client
+        .post(format!("{stream_url}/end"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?;
+
+    // Page through all events with the cursor, forward and in reverse
+    let get_page = async |params: &[(&str, &str)]| -> anyhow::Result<serde_json::Value> {
+        let page = http_client
+            .get(format!("{stream_url}/events"))
+            .query(&[("key", key.as_str())])
+            .query(params)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(page)
+    };
+    let event_names = |page: &serde_json::Value| -> Vec<String> {
+        let events = page["events"].as_array().expect("should have events");
+        events
+            .iter()
+            .map(|event| event["event"].as_str().unwrap().to_owned())
+            .collect()
+    };
+
+    let page = get_page(&[("limit", "3")]).await?;
+    assert_eq!(event_names(&page), ["start", "event_0", "event_1"]);
+    let cursor = page["next_cursor"].as_str().expect("should have cursor");
+    let page = get_page(&[("limit", "3"), ("start", cursor)]).await?;
+    assert_eq!(event_names(&page), ["event_2", "event_3", "event_4"]);
+    let cursor = page["next_cursor"].as_str().expect("should have cursor");
+    let page = get_page(&[("limit", "3"), ("start", cursor)]).await?;
+    assert_eq!(event_names(&page), ["end"]);
+    assert!(page["next_cursor"].is_null());
+
+    let page = get_page(&[("limit", "4"), ("reverse", "true")]).await?;
+    assert_eq!(event_names(&page), ["end", "event_4", "event_3", "event_2"]);
+    let cursor = page["next_cursor"].as_str().expect("should have cursor");
+    let page = get_page(&[("limit", "4"), ("reverse", "true"), ("end", cursor)]).await?;
+    assert_eq!(event_names(&page), ["event_1", "event_0", "start"]);
+    assert!(page["next_cursor"].is_null());
+
+    let page = get_page(&[]).await?;
+    assert_eq!(event_names(&page).len(), 7);
+
+    for params in [
+        [("start", "invalid")],
+        [("limit", "0")],
+        [("limit", "1001")],
+    ] {
+        let res = http_client
+            .get(format!("{stream_url}/events"))
+            .query(&[("key", key.as_str())])
+            .query(&params)
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+    let missing = http_client
+        .get(format!("{stream_url}/events"))
+        .query(&[("key", "missing")])
+        .send()
+        .await?;
+    assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn duplicate_events_are_skipped() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let key = rand::random::<u16>().to_string();
+    let stream_url = format!("http://localhost:{port}/api/stream");
+
+    http_client
+        .post(&stream_url)
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?;
+    let add_events = async |idempotency_keys: &[&str]| -> anyhow::Result<serde_json::Value> {
+        let events: Vec<_> = idempotency_keys
+            .iter()
+            .map(|idempotency_key| {
+                serde_json::json!({ "event": "token", "idempotency_key": idempotency_key })
+            })
+            .collect();
+        let response = http_client
+            .post(format!("http://localhost:{port}/api/event/add"))
+            .json(&serde_json::json!({ "key": key, "events": events }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response)
+    };
+
+    // Retried events with the same idempotency keys are skipped and reported
+    let first = add_events(&["producer-1:1", "producer-1:2"]).await?;
+    let retried = add_events(&["producer-1:2", "producer-1:3", "producer-1:3"]).await?;
+
+    let page: serde_json::Value = http_client
+        .get(format!("{stream_url}/events"))
+        .query(&[("key", key.as_str())])
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(page["events"].as_array().map(Vec::len), Some(4));
+    assert_eq!(
+        first,
+        serde_json::json!({
+            "num_events": 2,
+            "duplicates": [],
+            "last_id": page["events"][2]["id"]
+        })
+    );
+    assert_eq!(
+        retried,
+        serde_json::json!({
+            "num_events": 1,
+            "duplicates": ["producer-1:2", "producer-1:3"],
+            "last_id": page["events"][3]["id"]
+        })
+    );
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn writes_check_expected_last_id() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let key = rand::random::<u16>().to_string();
+    let add_url = format!("http://localhost:{port}/api/event/add");
+
+    http_client
+        .post(format!("http://localhost:{port}/api/stream"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?;
+    let response: serde_json::Value = http_client
+        .post(&add_url)
+        .json(&serde_json::json!({ "key": key, "events": [{ "event": "token" }] }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let last_id = response["last_id"].as_str().expect("should return last ID");
+
+    // A writer with a stale last ID gets a conflict with the current last ID
+    let res = http_client
+        .post(&add_url)
+        .json(&serde_json::json!({
+            "key": key,
+            "events": [{ "event": "token" }],
+            "expected_last_id": "0-1",
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+    let error: serde_json::Value = res.json().await?;
+    assert_eq!(error["error"]["last_id"], last_id);
+
+    // Writing after the current last ID succeeds
+    let response: serde_json::Value = http_client
+        .post(&add_url)
+        .json(&serde_json::json!({
+            "key": key,
+            "events": [{ "event": "token" }, { "event": "token" }],
+            "expected_last_id": last_id,
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    assert_eq!(response["num_events"], 2);
+    assert_ne!(response["last_id"], last_id);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn events_match_stream_schema() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let key = rand::random::<u16>().to_string();
+    let stream_url = format!("http://localhost:{port}/api/stream");
+    let add_url = format!("http://localhost:{port}/api/event/add");
+
+    let res = http_client
+        .post(&stream_url)
+        .json(&serde_json::json!({ "key": key, "event_schemas": { "delta": { "type": 5 } } }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    http_client
+        .post(&stream_url)
+        .json(&serde_json::json!({
+            "key": key,
+            "allowed_events": ["delta", "usage"],
+            "event_schemas": {
+                "delta": { "type": "string" },
+                "usage": { "type": "object", "required": ["tokens"] },
+            },
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    // Batches with any invalid event are rejected, with an error for each event
+    let res = http_client
+        .post(&add_url)
+        .json(&serde_json::json!({
+            "key": key,
+            "events": [
+                { "event": "delta", "data": "Hi" },
+                { "event": "tool_call", "data": "{}" },
+                { "event": "usage", "data": {} },
+            ],
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    let error: serde_json::Value = res.json().await?;
+    let message = error["error"]["message"].as_str().unwrap_or_default();
+    assert!(message.contains("event 1: event 'tool_call' is not allowed"));
+    assert!(message.contains("event 2: invalid data for event 'usage'"));
+
+    let res: serde_json::Value = http_client
+        .post(&add_url)
+        .json(&serde_json::json!({
+            "key": key,
+            "events": [{ "event": "delta", "data": "Hi" }, { "event": "usage", "data": { "tokens": 1 } }],
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    assert_eq!(res["num_events"], 2);
+
+    // JSON streams write the events before the first invalid one
+    let body = [
+        r#"{"event":"delta","data":"a"}"#,
+        r#"{"event":"delta","data":1}"#,
+        r#"{"event":"delta","data":"b"}"#,
+    ]
+    .join("\n");
+    let res = http_client
+        .post(format!("{add_url}/json-stream"))
+        .query(&[("key", key.as_str())])
+        .body(body)
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    // ...also when the invalid event is followed by malformed JSON
+    let body = [
+        r#"{"event":"delta","data":"c"}"#,
+        r#"{"event":"tool_call"}"#,
+        "not json",
+    ]
+    .join("\n");
+    let res = http_client
+        .post(format!("{add_url}/json-stream"))
+        .query(&[("key", key.as_str())])
+        .body(body)
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    let error: serde_json::Value = res.json().await?;
+    let message = error["error"]["message"].as_str().unwrap_or_default();
+    assert!(message.contains("event 'tool_call' is not allowed"));
+
+    let page: serde_json::Value = http_client
+        .get(format!("{stream_url}/events"))
+        .query(&[("key", key.as_str())])
+        .send()
+        .await?
+        .json()
+        .await?;
+    let names: Vec<_> = page["events"]
+        .as_array()
+        .expect("should be an array")
+        .iter()
+        .map(|event| event["event"].as_str().unwrap_or_default())
+        .collect();
+    assert_eq!(names, ["start", "delta", "usage", "delta", "delta"]);
+
+    // Events are checked against the schema of a stream created again at the same key
+    http_client
+        .post(format!("{stream_url}/end"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?
+        .error_for_status()?;
+    http_client
+        .post(&stream_url)
+        .json(&serde_json::json!({ "key": key, "allowed_events": ["tool_call"] }))
+        .send()
+        .await?
+        .error_for_status()?;
+    let res: serde_json::Value = http_client
+        .post(&add_url)
+        .json(&serde_json::json!({ "key": key, "events": [{ "event": "tool_call" }] }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    assert_eq!(res["num_events"], 1);
+    let res = http_client
+        .post(&add_url)
+        .json(&serde_json::json!({ "key": key, "events": [{ "event": "delta", "data": "Hi" }] }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn streams_are_touched_and_slide() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let base_url = format!("http://localhost:{port}/api");
+    let key = rand::random::<u16>().to_string();
+    let sliding_key = rand::random::<u16>().to_string();
+
+    http_client
+        .post(format!("{base_url}/stream"))
+        .json(&serde_json::json!({ "key": key, "ttl": 2 }))
+        .send()
+        .await?
+        .error_for_status()?;
+    let response: serde_json::Value = http_client
+        .post(format!("{base_url}/stream/touch"))
+        .json(&serde_json::json!({ "key": key, "ttl": 600 }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    assert_eq!(response["ttl"], 600);
+    let info: serde_json::Value = http_client
+        .get(format!("{base_url}/stream/info?key={key}"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert!(info["ttl"].as_i64().unwrap() > 500);
+
+    let res = http_client
+        .post(format!("{base_url}/stream/touch"))
+        .json(&serde_json::json!({ "key": key, "ttl": 0 }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    let res = http_client
+        .post(format!("{base_url}/stream/touch"))
+        .json(&serde_json::json!({ "key": sliding_key }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+    // Each write refreshes the sliding TTL, so the stream outlives its original TTL, and so
+    // can its tokens
+    http_client
+        .post(format!("{base_url}/stream"))
+        .json(&serde_json::json!({ "key": sliding_key, "ttl": 1, "sliding_ttl": true }))
+        .send()
+        .await?
+        .error_for_status()?;
+    let res: serde_json::Value = http_client
+        .post(format!("{base_url}/stream/token"))
+        .json(&serde_json::json!({ "key": sliding_key, "ttl": 600, "scopes": ["write"] }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let write_token = res["token"].as_str().expect("should get token").to_owned();
+    for _ in 0..4 {
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        reqwest::Client::new()
+            .post(format!("{base_url}/event/add"))
+            .bearer_auth(&write_token)
+            .json(&serde_json::json!({ "key": sliding_key, "events": [{ "event": "token" }] }))
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+    let info: serde_json::Value = http_client
+        .get(format!("{base_url}/stream/info?key={sliding_key}"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(info["status"], "active");
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn finished_streams_report_reason() -> anyhow::Result<()> {
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let http_client = setup_backend_http_client();
+    let base_url = format!("http://localhost:{port}/api");
+    let key = rand::random::<u16>().to_string();
+
+    http_client
+        .post(format!("{base_url}/stream"))
+        .json(&serde_json::json!({ "key": key }))
+        .send()
+        .await?
+        .error_for_status()?;
+    http_client
+        .post(format!("{base_url}/stream/end"))
+        .json(&serde_json::json!({
+            "key": key,
+            "reason": "completed",
+            "data": { "total_tokens": 42 },
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    // The reason and data are the terminal event's data, and are reported by the stream info
+    let page: serde_json::Value = http_client
+        .get(format!(
+            "{base_url}/stream/events?key={key}&reverse=true&limit=1"
+        ))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(page["events"][0]["event"], "end");
+    assert_eq!(
+        page["events"][0]["data"],
+        serde_json::json!({ "reason": "completed", "data": { "total_tokens": 42 } })
+    );
+    let info: serde_json::Value = http_client
+        .get(format!("{base_url}/stream/info?key={key}"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(info["status"], "ended");
+    assert_eq!(info["reason"], "completed");
+    assert_eq!(info["data"]["total_tokens"], 42);
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+};
+use hmac::{Hmac, KeyInit, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use tinistream_client::{ClientStreamExt, types::StreamRequest};
+use tokio::sync::mpsc;
+
+use crate::common::{setup_backend_client, setup_backend_http_client, setup_http_server};
+
+mod common;
+
+const WEBHOOK_SECRET: &str = "webhook-secret";
+
+/// State of the test webhook endpoint
+#[derive(Clone)]
+struct Endpoint {
+    received: mpsc::UnboundedSender<Value>,
+    /// IDs of the webhooks that already failed once
+    failed: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Check the signature of the webhook, and fail the first attempt of each `stream.created` webhook
+async fn receive_webhook(
+    State(endpoint): State<Endpoint>,
+    headers: HeaderMap,
+    body: String,
+) -> StatusCode {
+    let timestamp = headers["x-tinistream-timestamp"].to_str().unwrap();
+    let mut mac = Hmac::<Sha256>::new_from_slice(WEBHOOK_SECRET.as_bytes()).unwrap();
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+    assert_eq!(headers["x-tinistream-signature"], signature.as_str());
+
+    let payload: Value = serde_json::from_str(&body).unwrap();
+    let id = payload["id"].as_str().unwrap().to_owned();
+    let is_retry = !endpoint.failed.lock().unwrap().insert(id);
+    endpoint.received.send(payload.clone()).unwrap();
+    match payload["type"] == "stream.created" && !is_retry {
+        true => StatusCode::INTERNAL_SERVER_ERROR,
+        false => StatusCode::OK,
+    }
+}
+
+#[tokio::test]
+async fn lifecycle_webhooks_are_signed_and_retried() -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint_port = listener.local_addr()?.port();
+    let (received_tx, mut received) = mpsc::unbounded_channel();
+    let endpoint = Endpoint {
+        received: received_tx,
+        failed: Default::default(),
+    };
+    let router = axum::Router::new()
+        .route("/hook", post(receive_webhook))
+        .with_state(endpoint);
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    // SAFETY: this is the only test in this binary, so no other threads read the environment
+    unsafe {
+        std::env::set_var(
+            "STREAMER_WEBHOOK_URLS",
+            format!("http://127.0.0.1:{endpoint_port}/hook"),
+        );
+        std::env::set_var("STREAMER_WEBHOOK_SECRET", WEBHOOK_SECRET);
+    }
+    let (port, _server, shutdown) = setup_http_server().await?;
+    let client = setup_backend_http_client();
+    let base_url = format!("http://localhost:{port}/api");
+    let key = rand::random::<u16>().to_string();
+
+    let backend = setup_backend_client(port);
+    backend
+        .create_stream()
+        .body(StreamRequest::builder().key(&key))
+        .send()
+        .await
+        .expect("should create stream");
+    backend
+        .end_stream()
+        .body(StreamRequest::builder().key(&key))
+        .send()
+        .await
+        .expect("should end stream");
+
+    // The created webhook is retried after failing, and the ended webhook is sent once
+    let mut payloads = Vec::new();
+    while payloads.len() < 3 {
+        let payload = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await?
+            .expect("endpoint should be running");
+        assert_eq!(payload["key"], key.as_str());
+        payloads.push(payload);
+    }
+    let types: Vec<_> = payloads
+        .iter()
+        .map(|p| p["type"].as_str().unwrap())
+        .collect();
+    assert_eq!(types.iter().filter(|t| **t == "stream.created").count(), 2);
+    assert_eq!(types.iter().filter(|t| **t == "stream.ended").count(), 1);
+
+    // The delivery log is updated right after the endpoint responds
+    let mut deliveries: Vec<Value> = Vec::new();
+    for _ in 0..20 {
+        deliveries = client
+            .get(format!("{base_url}/webhook/deliveries"))
+            .send()
+            .await?
+            .json()
+            .await?;
+        if deliveries.iter().all(|d| d["status"] != "pending") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(deliveries.len(), 2);
+    assert_eq!(deliveries[0]["event"], "stream.ended");
+    assert_eq!(deliveries[0]["status"], "delivered");
+    assert_eq!(deliveries[0]["attempts"], 1);
+    assert_eq!(deliveries[1]["event"], "stream.created");
+    assert_eq!(deliveries[1]["status"], "delivered");
+    assert_eq!(deliveries[1]["attempts"], 2);
+    assert_eq!(deliveries[1]["response_status"], 200);
+
+    let failed: Vec<Value> = client
+        .get(format!("{base_url}/webhook/deliveries?status=failed"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert!(failed.is_empty());
+
+    shutdown.await.expect("failed to shutdown server");
+
+    Ok(())
+}